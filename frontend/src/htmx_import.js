@@ -1,6 +1,16 @@
 window.htmx = require('htmx.org');
 swal = require('sweetalert')
 
+// The server hands out the CSRF token as a cookie. Put it in the meta
+// tag, where `hx-headers` on <body> picks it up for every request.
+document.querySelector('meta[name="csrf-token"]').content = document.cookie
+  .split("; ")
+  .find((cookie) => cookie.startsWith("csrftoken="))
+  ?.split("=")[1] ?? "";
+
+// Send DELETE parameters in the body, where `JsonOrForm` looks for them
+window.htmx.default.config.methodsThatUseUrlParams = ["get"];
+
 document.body.addEventListener("htmx:configRequest", function(evt) {
   for (const key of Reflect.ownKeys(evt.detail.parameters)) {
     console.log(evt.detail.parameters[key])
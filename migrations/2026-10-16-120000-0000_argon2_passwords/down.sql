@@ -0,0 +1,8 @@
+-- Accounts that were already upgraded to Argon2id can't be
+-- converted back and will need their password reset.
+ALTER TABLE users
+ALTER COLUMN password TYPE BYTEA
+USING CASE
+    WHEN password LIKE '$blake3$%' THEN decode(substring(password FROM 9), 'hex')
+    ELSE '\x'::bytea
+END;
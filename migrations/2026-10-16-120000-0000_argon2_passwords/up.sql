@@ -0,0 +1,6 @@
+-- Existing hashes are unsalted blake3(username || password).
+-- Keep them around as a self-describing string so they can be
+-- verified once and replaced with Argon2id on the next login.
+ALTER TABLE users
+ALTER COLUMN password TYPE VARCHAR
+USING '$blake3$' || encode(password, 'hex');
@@ -0,0 +1,14 @@
+-- Sessions are looked up by token before the user is known, so
+-- this table isn't covered by row level security. Queries on it
+-- must always filter on user_id themselves.
+CREATE TABLE sessions(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash BYTEA NOT NULL UNIQUE,
+    user_agent VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
@@ -0,0 +1,16 @@
+CREATE TYPE token_scope AS ENUM ('games_read', 'games_write', 'account');
+
+-- Like sessions, tokens are looked up before the user is known, so
+-- this table isn't covered by row level security either.
+CREATE TABLE api_tokens(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash BYTEA NOT NULL UNIQUE,
+    scopes token_scope[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
@@ -0,0 +1,3 @@
+ALTER TABLE sessions DROP COLUMN second_factor_pending;
+DROP TABLE recovery_codes;
+DROP TABLE totp_credentials;
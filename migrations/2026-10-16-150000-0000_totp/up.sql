@@ -0,0 +1,20 @@
+-- The second factor is checked before the user is known, so like
+-- sessions these tables aren't covered by row level security.
+CREATE TABLE totp_credentials(
+    user_id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,
+    confirmed_at TIMESTAMPTZ,
+    last_used_step BIGINT
+);
+
+CREATE TABLE recovery_codes(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash BYTEA NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- A session waiting for the second factor doesn't log anyone in yet
+ALTER TABLE sessions ADD COLUMN second_factor_pending BOOLEAN NOT NULL DEFAULT false;
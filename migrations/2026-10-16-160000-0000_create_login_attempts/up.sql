@@ -0,0 +1,7 @@
+-- Keyed by `account:<username>` or `ip:<address>`. Attempts are
+-- counted before anyone is logged in, so no row level security.
+CREATE TABLE login_attempts(
+    key VARCHAR PRIMARY KEY,
+    failures INT NOT NULL,
+    last_failure_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,6 @@
+DROP TABLE email_tokens;
+DROP TYPE email_token_purpose;
+
+ALTER TABLE users
+    DROP COLUMN email_verified_at,
+    DROP COLUMN email;
@@ -0,0 +1,20 @@
+ALTER TABLE users
+    ADD COLUMN email VARCHAR UNIQUE,
+    ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+CREATE TYPE email_token_purpose AS ENUM ('verify_email', 'reset_password');
+
+-- Tokens are redeemed from links in mail, usually without being
+-- logged in, so this table isn't covered by row level security.
+CREATE TABLE email_tokens(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    purpose email_token_purpose NOT NULL,
+    token_hash BYTEA NOT NULL UNIQUE,
+    -- Address being verified, so changing it again voids the link
+    email VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX email_tokens_user_id_idx ON email_tokens (user_id);
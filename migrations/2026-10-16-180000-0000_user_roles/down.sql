@@ -0,0 +1,18 @@
+DROP POLICY "Admins can delete every profile" ON users;
+DROP POLICY "Admins can update every profile" ON users;
+DROP POLICY "Moderators can update every game" ON games;
+DROP POLICY "Moderators can view every game" ON games;
+DROP POLICY "Users can view listed games and their own" ON games;
+
+CREATE POLICY "Users can view games"
+ON games FOR SELECT
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) != 0);
+
+DROP TRIGGER check_hidden_change ON games;
+DROP FUNCTION check_hidden_change();
+DROP TRIGGER check_role_change ON users;
+DROP FUNCTION check_role_change();
+
+ALTER TABLE games DROP COLUMN hidden;
+ALTER TABLE users DROP COLUMN role;
+DROP TYPE user_role;
@@ -0,0 +1,70 @@
+CREATE TYPE user_role AS ENUM ('user', 'moderator', 'admin');
+
+ALTER TABLE users ADD COLUMN role user_role NOT NULL DEFAULT 'user';
+
+-- Moderators hide listings instead of deleting them
+ALTER TABLE games ADD COLUMN hidden BOOLEAN NOT NULL DEFAULT false;
+
+-- The role is set next to `app.current_user_id` by the app. Policies
+-- can't restrict single columns, so these triggers keep everyone else
+-- from handing out roles or hiding and unhiding listings. Connections
+-- that never set a user id (e.g. psql) are let through, which is how
+-- the first admin gets appointed.
+CREATE FUNCTION check_role_change() RETURNS trigger AS $$
+BEGIN
+    IF (
+        NEW.role IS DISTINCT FROM (CASE WHEN TG_OP = 'INSERT' THEN 'user' ELSE OLD.role END) AND
+        current_setting('app.current_user_id', true) IS NOT NULL AND
+        current_setting('app.current_user_role', true) IS DISTINCT FROM 'admin'
+    ) THEN
+        RAISE EXCEPTION 'Only admins can change roles' USING ERRCODE = 'insufficient_privilege';
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER check_role_change BEFORE INSERT OR UPDATE ON users
+FOR EACH ROW EXECUTE PROCEDURE check_role_change();
+
+CREATE FUNCTION check_hidden_change() RETURNS trigger AS $$
+BEGIN
+    IF (
+        NEW.hidden IS DISTINCT FROM (CASE WHEN TG_OP = 'INSERT' THEN false ELSE OLD.hidden END) AND
+        current_setting('app.current_user_id', true) IS NOT NULL AND
+        coalesce(current_setting('app.current_user_role', true), '') NOT IN ('moderator', 'admin')
+    ) THEN
+        RAISE EXCEPTION 'Only moderators can hide listings' USING ERRCODE = 'insufficient_privilege';
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER check_hidden_change BEFORE INSERT OR UPDATE ON games
+FOR EACH ROW EXECUTE PROCEDURE check_hidden_change();
+
+DROP POLICY "Users can view games" ON games;
+
+CREATE POLICY "Users can view listed games and their own"
+ON games FOR SELECT
+USING (
+    (SELECT current_setting('app.current_user_id', true)::integer) != 0
+    AND (NOT hidden OR (SELECT current_setting('app.current_user_id', true)::integer) = owned_by)
+);
+
+CREATE POLICY "Moderators can view every game"
+ON games FOR SELECT
+USING ( (SELECT current_setting('app.current_user_role', true)) IN ('moderator', 'admin') );
+
+CREATE POLICY "Moderators can update every game"
+ON games FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_role', true)) IN ('moderator', 'admin') )
+WITH CHECK ( (SELECT current_setting('app.current_user_role', true)) IN ('moderator', 'admin') );
+
+CREATE POLICY "Admins can update every profile"
+ON users FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' )
+WITH CHECK ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
+
+CREATE POLICY "Admins can delete every profile"
+ON users FOR DELETE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
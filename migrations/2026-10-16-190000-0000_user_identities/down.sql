@@ -0,0 +1,5 @@
+DROP TABLE oidc_logins;
+DROP TABLE user_identities;
+
+DELETE FROM users WHERE password IS NULL;
+ALTER TABLE users ALTER COLUMN password SET NOT NULL;
@@ -0,0 +1,30 @@
+-- Accounts created through an OpenID Connect provider have no password
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+-- Identities are looked up by provider and subject before the user is
+-- known, so like sessions this table isn't covered by row level
+-- security. Queries on it must filter on user_id themselves.
+CREATE TABLE user_identities(
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    provider VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    email VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (provider, subject)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
+
+-- Authorization requests waiting for the provider to redirect back,
+-- keyed by the hash of their `state` parameter
+CREATE TABLE oidc_logins(
+    state_hash BYTEA PRIMARY KEY,
+    provider VARCHAR NOT NULL,
+    pkce_verifier VARCHAR NOT NULL,
+    nonce VARCHAR NOT NULL,
+    -- Set when a logged in user is linking another identity
+    link_user_id INT REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,20 @@
+DROP TABLE trade_partners;
+
+ALTER TABLE users ADD COLUMN street_address VARCHAR;
+
+-- Copying the addresses back has to get past the policies on both tables
+ALTER TABLE street_addresses DISABLE ROW LEVEL SECURITY;
+ALTER TABLE users NO FORCE ROW LEVEL SECURITY;
+
+UPDATE users SET street_address = street_addresses.street_address
+FROM street_addresses
+WHERE street_addresses.user_id = users.id;
+
+ALTER TABLE users FORCE ROW LEVEL SECURITY;
+
+DROP TABLE street_addresses;
+
+ALTER TABLE users DROP COLUMN created_at;
+ALTER TABLE users DROP COLUMN address_visibility;
+ALTER TABLE users DROP COLUMN bio;
+DROP TYPE address_visibility;
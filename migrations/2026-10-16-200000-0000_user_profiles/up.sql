@@ -0,0 +1,89 @@
+CREATE TYPE address_visibility AS ENUM ('nobody', 'trade_partners', 'everyone');
+
+ALTER TABLE users ADD COLUMN bio VARCHAR;
+ALTER TABLE users ADD COLUMN address_visibility address_visibility NOT NULL DEFAULT 'nobody';
+-- Accounts from before this migration count as joined when it ran
+ALTER TABLE users ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Row level security can only hide whole rows, and profiles are
+-- public, so the address moves to a table of its own
+CREATE TABLE street_addresses(
+    user_id INT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    street_address VARCHAR NOT NULL
+);
+
+INSERT INTO street_addresses (user_id, street_address)
+SELECT id, street_address FROM users WHERE street_address IS NOT NULL;
+
+ALTER TABLE users DROP COLUMN street_address;
+
+-- `user_id` shares their address with `partner_id`
+CREATE TABLE trade_partners(
+    user_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    partner_id INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, partner_id),
+    CHECK (user_id != partner_id)
+);
+
+CREATE INDEX trade_partners_partner_id_idx ON trade_partners (partner_id);
+
+ALTER TABLE street_addresses ENABLE ROW LEVEL SECURITY;
+ALTER TABLE street_addresses FORCE ROW LEVEL SECURITY;
+ALTER TABLE trade_partners ENABLE ROW LEVEL SECURITY;
+ALTER TABLE trade_partners FORCE ROW LEVEL SECURITY;
+
+CREATE POLICY "Users can view their own address"
+ON street_addresses FOR SELECT
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Users can view addresses shared with everyone"
+ON street_addresses FOR SELECT
+USING (
+    (SELECT current_setting('app.current_user_id', true)::integer) != 0
+    AND EXISTS (
+        SELECT 1 FROM users
+        WHERE users.id = street_addresses.user_id
+        AND users.address_visibility = 'everyone'
+    )
+);
+
+CREATE POLICY "Trade partners can view addresses shared with them"
+ON street_addresses FOR SELECT
+USING (
+    EXISTS (
+        SELECT 1 FROM users
+        JOIN trade_partners ON trade_partners.user_id = users.id
+        WHERE users.id = street_addresses.user_id
+        AND users.address_visibility = 'trade_partners'
+        AND trade_partners.partner_id = (SELECT current_setting('app.current_user_id', true)::integer)
+    )
+);
+
+CREATE POLICY "Users can add their own address"
+ON street_addresses FOR INSERT
+WITH CHECK ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Users can update their own address"
+ON street_addresses FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id )
+WITH CHECK ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Users can delete their own address"
+ON street_addresses FOR DELETE
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Users can view who they share with and who shares with them"
+ON trade_partners FOR SELECT
+USING (
+    (SELECT current_setting('app.current_user_id', true)::integer) = user_id
+    OR (SELECT current_setting('app.current_user_id', true)::integer) = partner_id
+);
+
+CREATE POLICY "Users can pick their own trade partners"
+ON trade_partners FOR INSERT
+WITH CHECK ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Users can drop their own trade partners"
+ON trade_partners FOR DELETE
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
@@ -0,0 +1,11 @@
+DROP POLICY "Users can view listed games and their own" ON games;
+
+CREATE POLICY "Users can view listed games and their own"
+ON games FOR SELECT
+USING (
+    (SELECT current_setting('app.current_user_id', true)::integer) != 0
+    AND (NOT hidden OR (SELECT current_setting('app.current_user_id', true)::integer) = owned_by)
+);
+
+DROP INDEX users_delete_after_idx;
+ALTER TABLE users DROP COLUMN delete_after;
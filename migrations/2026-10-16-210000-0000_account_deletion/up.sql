@@ -0,0 +1,26 @@
+-- Set when the account was deleted by its owner, who can still cancel
+-- it until then
+ALTER TABLE users ADD COLUMN delete_after TIMESTAMPTZ;
+
+CREATE INDEX users_delete_after_idx ON users (delete_after) WHERE delete_after IS NOT NULL;
+
+-- Listings of accounts waiting to be deleted are hidden like the ones
+-- hidden by a moderator
+DROP POLICY "Users can view listed games and their own" ON games;
+
+CREATE POLICY "Users can view listed games and their own"
+ON games FOR SELECT
+USING (
+    (SELECT current_setting('app.current_user_id', true)::integer) != 0
+    AND (
+        (SELECT current_setting('app.current_user_id', true)::integer) = owned_by
+        OR (
+            NOT hidden
+            AND NOT EXISTS (
+                SELECT 1 FROM users
+                WHERE users.id = games.owned_by
+                AND users.delete_after IS NOT NULL
+            )
+        )
+    )
+);
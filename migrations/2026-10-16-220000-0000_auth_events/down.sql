@@ -0,0 +1,3 @@
+DROP TABLE auth_events;
+DROP FUNCTION reject_auth_event_change;
+DROP TYPE auth_event_kind;
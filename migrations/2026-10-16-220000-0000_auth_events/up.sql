@@ -0,0 +1,52 @@
+CREATE TYPE auth_event_kind AS ENUM (
+    'signup',
+    'login_succeeded',
+    'login_failed',
+    'password_changed',
+    'logout',
+    'token_created',
+    'account_deleted'
+);
+
+-- Failed logins are recorded before anyone is logged in, so anyone may
+-- insert. There are no policies to update or delete, and the trigger
+-- below rejects updates outright, so the log can only grow. Events
+-- only go away with their account, as foreign key cascades bypass
+-- row level security.
+CREATE TABLE auth_events(
+    id SERIAL PRIMARY KEY,
+    -- `NULL` for failed logins to accounts that don't exist
+    user_id INT REFERENCES users (id) ON DELETE CASCADE,
+    -- The username a failed login was attempted with
+    username VARCHAR,
+    kind auth_event_kind NOT NULL,
+    ip VARCHAR NOT NULL,
+    user_agent VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX auth_events_user_id_idx ON auth_events (user_id, id);
+
+CREATE FUNCTION reject_auth_event_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'Auth events are append-only' USING ERRCODE = 'insufficient_privilege';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER reject_auth_event_change BEFORE UPDATE ON auth_events
+FOR EACH ROW EXECUTE PROCEDURE reject_auth_event_change();
+
+ALTER TABLE auth_events ENABLE ROW LEVEL SECURITY;
+ALTER TABLE auth_events FORCE ROW LEVEL SECURITY;
+
+CREATE POLICY "Users can view their own auth events"
+ON auth_events FOR SELECT
+USING ( (SELECT current_setting('app.current_user_id', true)::integer) = user_id );
+
+CREATE POLICY "Admins can view every auth event"
+ON auth_events FOR SELECT
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
+
+CREATE POLICY "Anyone can record auth events"
+ON auth_events FOR INSERT
+WITH CHECK ( true );
@@ -0,0 +1,14 @@
+-- Codes are looked up before the account signing up with them exists,
+-- so like sessions this table isn't covered by row level security.
+CREATE TABLE invites(
+    id SERIAL PRIMARY KEY,
+    code VARCHAR NOT NULL UNIQUE,
+    created_by INT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- Stays used after the invited account is deleted
+    used_by INT UNIQUE REFERENCES users (id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX invites_created_by_idx ON invites (created_by);
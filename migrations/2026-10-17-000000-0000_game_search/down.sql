@@ -0,0 +1,4 @@
+DROP INDEX games_publisher_trgm_idx;
+DROP INDEX games_name_trgm_idx;
+ALTER TABLE games DROP COLUMN search;
+DROP EXTENSION IF EXISTS pg_trgm;
@@ -0,0 +1,12 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Stemmed words of the name and the publisher, the name weighing more
+ALTER TABLE games ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', name), 'A') ||
+    setweight(to_tsvector('english', coalesce(publisher, '')), 'B')
+) STORED;
+
+CREATE INDEX games_search_idx ON games USING GIN (search);
+-- For the `<%` word similarity operator of pg_trgm
+CREATE INDEX games_name_trgm_idx ON games USING GIN (name gin_trgm_ops);
+CREATE INDEX games_publisher_trgm_idx ON games USING GIN (publisher gin_trgm_ops);
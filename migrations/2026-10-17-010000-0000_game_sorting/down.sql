@@ -0,0 +1,7 @@
+DROP INDEX games_condition_idx;
+DROP INDEX games_platform_idx;
+DROP INDEX games_year_idx;
+DROP INDEX games_name_idx;
+DROP INDEX games_updated_at_idx;
+DROP TRIGGER set_updated_at ON games;
+ALTER TABLE games DROP COLUMN updated_at;
@@ -0,0 +1,10 @@
+-- Games from before this migration count as updated when it ran
+ALTER TABLE games ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+SELECT diesel_manage_updated_at('games');
+
+-- Every sort of the game list ends in the id, like the cursor
+CREATE INDEX games_updated_at_idx ON games (updated_at, id);
+CREATE INDEX games_name_idx ON games (name, id);
+CREATE INDEX games_year_idx ON games (year, id);
+CREATE INDEX games_platform_idx ON games (platform, id);
+CREATE INDEX games_condition_idx ON games (condition, id);
@@ -0,0 +1,65 @@
+CREATE TABLE game_photos(
+    id SERIAL PRIMARY KEY,
+    game_id INT NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    -- From 0 without gaps, the photo at 0 is the cover
+    position INT NOT NULL,
+    content_type VARCHAR NOT NULL,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    storage_key VARCHAR NOT NULL,
+    thumbnail_key VARCHAR NOT NULL,
+    url VARCHAR NOT NULL,
+    thumbnail_url VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Checked at the end of the transaction, as reordering moves photos
+    -- through each other's positions
+    CONSTRAINT game_photos_position_key UNIQUE (game_id, position)
+        DEFERRABLE INITIALLY DEFERRED
+);
+
+ALTER TABLE game_photos ENABLE ROW LEVEL SECURITY;
+ALTER TABLE game_photos FORCE ROW LEVEL SECURITY;
+
+-- The policies of games decide which of them are visible
+CREATE POLICY "Users can view photos of the games they can view"
+ON game_photos FOR SELECT
+USING ( EXISTS (SELECT 1 FROM games WHERE games.id = game_id) );
+
+CREATE POLICY "Users can add photos to their own games"
+ON game_photos FOR INSERT
+WITH CHECK (
+    EXISTS (
+        SELECT 1 FROM games
+        WHERE games.id = game_id
+        AND games.owned_by = (SELECT current_setting('app.current_user_id', true)::integer)
+    )
+);
+
+CREATE POLICY "Users can reorder photos of their own games"
+ON game_photos FOR UPDATE
+USING (
+    EXISTS (
+        SELECT 1 FROM games
+        WHERE games.id = game_id
+        AND games.owned_by = (SELECT current_setting('app.current_user_id', true)::integer)
+    )
+);
+
+CREATE POLICY "Users can delete photos of their own games"
+ON game_photos FOR DELETE
+USING (
+    EXISTS (
+        SELECT 1 FROM games
+        WHERE games.id = game_id
+        AND games.owned_by = (SELECT current_setting('app.current_user_id', true)::integer)
+    )
+);
+
+-- To close the gap a deleted photo leaves
+CREATE POLICY "Moderators can reorder every photo"
+ON game_photos FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_role', true)) IN ('moderator', 'admin') );
+
+CREATE POLICY "Moderators can delete every photo"
+ON game_photos FOR DELETE
+USING ( (SELECT current_setting('app.current_user_role', true)) IN ('moderator', 'admin') );
@@ -0,0 +1,17 @@
+ALTER TABLE games ADD COLUMN platform VARCHAR;
+
+ALTER TABLE games NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE games DISABLE TRIGGER set_updated_at;
+
+UPDATE games SET platform = platforms.name
+FROM platforms
+WHERE platforms.id = games.platform_id;
+
+ALTER TABLE games ENABLE TRIGGER set_updated_at;
+ALTER TABLE games FORCE ROW LEVEL SECURITY;
+
+DROP INDEX games_platform_id_idx;
+ALTER TABLE games DROP COLUMN platform_id;
+CREATE INDEX games_platform_idx ON games (platform, id);
+
+DROP TABLE platforms;
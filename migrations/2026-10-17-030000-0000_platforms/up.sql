@@ -0,0 +1,110 @@
+CREATE TABLE platforms(
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    manufacturer VARCHAR,
+    -- Console generation, computers have none
+    generation SMALLINT,
+    release_year SMALLINT,
+    -- Where it came out first
+    region VARCHAR,
+    -- Other spellings it's known by, lowercase
+    aliases VARCHAR[] NOT NULL DEFAULT '{}'
+);
+
+CREATE UNIQUE INDEX platforms_name_idx ON platforms (lower(name));
+CREATE INDEX platforms_aliases_idx ON platforms USING GIN (aliases);
+CREATE INDEX platforms_name_trgm_idx ON platforms USING GIN (name gin_trgm_ops);
+
+INSERT INTO platforms (name, manufacturer, generation, release_year, region, aliases) VALUES
+    ('Atari 2600', 'Atari', 2, 1977, 'NA', '{"2600", "vcs", "atari vcs"}'),
+    ('Intellivision', 'Mattel', 2, 1979, 'NA', '{"intv"}'),
+    ('ColecoVision', 'Coleco', 2, 1982, 'NA', '{"coleco"}'),
+    ('Nintendo Entertainment System', 'Nintendo', 3, 1983, 'JP', '{"nes", "famicom", "fc"}'),
+    ('Master System', 'Sega', 3, 1985, 'JP', '{"sms", "sega master system", "mark iii"}'),
+    ('Atari 7800', 'Atari', 3, 1986, 'NA', '{"7800"}'),
+    ('PC Engine', 'NEC', 4, 1987, 'JP', '{"turbografx-16", "turbografx", "tg16", "pce"}'),
+    ('Mega Drive', 'Sega', 4, 1988, 'JP', '{"genesis", "sega genesis", "sega mega drive", "md"}'),
+    ('Game Boy', 'Nintendo', 4, 1989, 'JP', '{"gb", "gameboy"}'),
+    ('Atari Lynx', 'Atari', 4, 1989, 'NA', '{"lynx"}'),
+    ('Super Nintendo Entertainment System', 'Nintendo', 4, 1990, 'JP', '{"snes", "super nintendo", "super famicom", "sfc"}'),
+    ('Neo Geo', 'SNK', 4, 1990, 'JP', '{"neogeo", "neo geo aes", "aes"}'),
+    ('Game Gear', 'Sega', 4, 1990, 'JP', '{"gg", "sega game gear"}'),
+    ('Atari Jaguar', 'Atari', 5, 1993, 'NA', '{"jaguar"}'),
+    ('3DO', 'Panasonic', 5, 1993, 'NA', '{"3do interactive multiplayer"}'),
+    ('Sega Saturn', 'Sega', 5, 1994, 'JP', '{"saturn"}'),
+    ('PlayStation', 'Sony', 5, 1994, 'JP', '{"ps1", "psx", "psone", "ps one", "playstation 1"}'),
+    ('Nintendo 64', 'Nintendo', 5, 1996, 'JP', '{"n64"}'),
+    ('Game Boy Color', 'Nintendo', 5, 1998, 'JP', '{"gbc"}'),
+    ('Dreamcast', 'Sega', 6, 1998, 'JP', '{"dc", "sega dreamcast"}'),
+    ('PlayStation 2', 'Sony', 6, 2000, 'JP', '{"ps2"}'),
+    ('GameCube', 'Nintendo', 6, 2001, 'JP', '{"gc", "ngc", "nintendo gamecube"}'),
+    ('Xbox', 'Microsoft', 6, 2001, 'NA', '{"original xbox"}'),
+    ('Game Boy Advance', 'Nintendo', 6, 2001, 'JP', '{"gba"}'),
+    ('Nintendo DS', 'Nintendo', 7, 2004, 'JP', '{"nds", "ds"}'),
+    ('PlayStation Portable', 'Sony', 7, 2004, 'JP', '{"psp"}'),
+    ('Xbox 360', 'Microsoft', 7, 2005, 'NA', '{"x360", "360"}'),
+    ('PlayStation 3', 'Sony', 7, 2006, 'JP', '{"ps3"}'),
+    ('Wii', 'Nintendo', 7, 2006, 'NA', '{"nintendo wii"}'),
+    ('Nintendo 3DS', 'Nintendo', 8, 2011, 'JP', '{"3ds"}'),
+    ('PlayStation Vita', 'Sony', 8, 2011, 'JP', '{"vita", "psvita", "ps vita"}'),
+    ('Wii U', 'Nintendo', 8, 2012, 'NA', '{"wiiu"}'),
+    ('PlayStation 4', 'Sony', 8, 2013, 'NA', '{"ps4"}'),
+    ('Xbox One', 'Microsoft', 8, 2013, 'NA', '{"xb1", "xbone"}'),
+    ('Nintendo Switch', 'Nintendo', 8, 2017, 'Worldwide', '{"switch", "ns"}'),
+    ('PlayStation 5', 'Sony', 9, 2020, 'NA', '{"ps5"}'),
+    ('Xbox Series X|S', 'Microsoft', 9, 2020, 'Worldwide', '{"xbox series x", "xbox series s", "xsx", "series x"}'),
+    ('Nintendo Switch 2', 'Nintendo', 9, 2025, 'Worldwide', '{"switch 2", "ns2"}'),
+    ('ZX Spectrum', 'Sinclair', NULL, 1982, 'EU', '{"spectrum", "zx"}'),
+    ('Commodore 64', 'Commodore', NULL, 1982, 'NA', '{"c64", "c-64"}'),
+    ('Amiga', 'Commodore', NULL, 1985, 'NA', '{"commodore amiga"}'),
+    ('PC', NULL, NULL, NULL, NULL, '{"windows", "dos", "ms-dos", "computer"}');
+
+ALTER TABLE games ADD COLUMN platform_id INT REFERENCES platforms (id);
+
+-- The owner is subject to the policies too, which would hide every
+-- game here, and linking a platform doesn't count as an update
+ALTER TABLE games NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE games DISABLE TRIGGER set_updated_at;
+
+-- Spellings no platform is known by get one of their own, for an admin
+-- to fill in or merge
+INSERT INTO platforms (name)
+SELECT DISTINCT ON (lower(trim(platform))) trim(platform)
+FROM games
+WHERE trim(platform) != ''
+AND NOT EXISTS (
+    SELECT 1 FROM platforms
+    WHERE lower(platforms.name) = lower(trim(games.platform))
+    OR lower(trim(games.platform)) = ANY (platforms.aliases)
+);
+
+UPDATE games SET platform_id = platforms.id
+FROM platforms
+WHERE lower(platforms.name) = lower(trim(games.platform))
+OR lower(trim(games.platform)) = ANY (platforms.aliases);
+
+ALTER TABLE games ENABLE TRIGGER set_updated_at;
+ALTER TABLE games FORCE ROW LEVEL SECURITY;
+
+ALTER TABLE games DROP COLUMN platform;
+CREATE INDEX games_platform_id_idx ON games (platform_id, id);
+
+ALTER TABLE platforms ENABLE ROW LEVEL SECURITY;
+ALTER TABLE platforms FORCE ROW LEVEL SECURITY;
+
+CREATE POLICY "Anyone can view platforms"
+ON platforms FOR SELECT
+USING ( true );
+
+CREATE POLICY "Admins can add platforms"
+ON platforms FOR INSERT
+WITH CHECK ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
+
+CREATE POLICY "Admins can update platforms"
+ON platforms FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' )
+WITH CHECK ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
+
+CREATE POLICY "Admins can delete platforms"
+ON platforms FOR DELETE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
@@ -0,0 +1,37 @@
+ALTER TABLE games ADD COLUMN name VARCHAR;
+ALTER TABLE games ADD COLUMN publisher VARCHAR;
+ALTER TABLE games ADD COLUMN year SMALLINT;
+ALTER TABLE games ADD COLUMN platform_id INT REFERENCES platforms (id);
+
+ALTER TABLE games NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE games DISABLE TRIGGER set_updated_at;
+
+UPDATE games SET
+    name = titles.name,
+    publisher = titles.publisher,
+    year = titles.year,
+    platform_id = titles.platform_id
+FROM titles
+WHERE titles.id = games.title_id;
+
+ALTER TABLE games ENABLE TRIGGER set_updated_at;
+ALTER TABLE games FORCE ROW LEVEL SECURITY;
+
+ALTER TABLE games ALTER COLUMN name SET NOT NULL;
+
+ALTER TABLE games ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', name), 'A') ||
+    setweight(to_tsvector('english', coalesce(publisher, '')), 'B')
+) STORED;
+
+CREATE INDEX games_search_idx ON games USING GIN (search);
+CREATE INDEX games_name_trgm_idx ON games USING GIN (name gin_trgm_ops);
+CREATE INDEX games_publisher_trgm_idx ON games USING GIN (publisher gin_trgm_ops);
+CREATE INDEX games_name_idx ON games (name, id);
+CREATE INDEX games_year_idx ON games (year, id);
+CREATE INDEX games_platform_id_idx ON games (platform_id, id);
+
+DROP INDEX games_title_id_idx;
+ALTER TABLE games DROP COLUMN title_id;
+
+DROP TABLE titles;
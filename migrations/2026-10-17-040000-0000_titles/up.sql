@@ -0,0 +1,96 @@
+-- What a game is, shared by every copy listed of it. `games` keeps
+-- what belongs to a copy: who owns it and what condition it's in.
+CREATE TABLE titles(
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    publisher VARCHAR,
+    year SMALLINT,
+    platform_id INT REFERENCES platforms (id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Titles that only differ in case are the same title
+CREATE UNIQUE INDEX titles_identity_idx ON titles (
+    lower(name),
+    lower(coalesce(publisher, '')),
+    coalesce(year, -1),
+    coalesce(platform_id, 0)
+);
+CREATE INDEX titles_platform_id_idx ON titles (platform_id);
+CREATE INDEX titles_name_idx ON titles (name, id);
+CREATE INDEX titles_year_idx ON titles (year, id);
+
+-- The owner is subject to the policies too, which would hide every
+-- game here, and moving the facts out doesn't count as an update
+ALTER TABLE games NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE games DISABLE TRIGGER set_updated_at;
+
+-- The oldest listing decides how a title is spelled
+INSERT INTO titles (name, publisher, year, platform_id)
+SELECT DISTINCT ON (
+    lower(trim(name)),
+    lower(coalesce(trim(publisher), '')),
+    coalesce(year, -1),
+    coalesce(platform_id, 0)
+) trim(name), nullif(trim(publisher), ''), year, platform_id
+FROM games
+ORDER BY
+    lower(trim(name)),
+    lower(coalesce(trim(publisher), '')),
+    coalesce(year, -1),
+    coalesce(platform_id, 0),
+    id;
+
+ALTER TABLE games ADD COLUMN title_id INT REFERENCES titles (id);
+
+UPDATE games SET title_id = titles.id
+FROM titles
+WHERE lower(titles.name) = lower(trim(games.name))
+AND lower(coalesce(titles.publisher, '')) = lower(coalesce(trim(games.publisher), ''))
+AND coalesce(titles.year, -1) = coalesce(games.year, -1)
+AND coalesce(titles.platform_id, 0) = coalesce(games.platform_id, 0);
+
+ALTER TABLE games ENABLE TRIGGER set_updated_at;
+ALTER TABLE games FORCE ROW LEVEL SECURITY;
+
+ALTER TABLE games ALTER COLUMN title_id SET NOT NULL;
+CREATE INDEX games_title_id_idx ON games (title_id, id);
+
+-- Their indexes go with them
+ALTER TABLE games DROP COLUMN search;
+ALTER TABLE games DROP COLUMN name;
+ALTER TABLE games DROP COLUMN publisher;
+ALTER TABLE games DROP COLUMN year;
+ALTER TABLE games DROP COLUMN platform_id;
+
+-- Searching moves along with the name and the publisher
+ALTER TABLE titles ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', name), 'A') ||
+    setweight(to_tsvector('english', coalesce(publisher, '')), 'B')
+) STORED;
+
+CREATE INDEX titles_search_idx ON titles USING GIN (search);
+CREATE INDEX titles_name_trgm_idx ON titles USING GIN (name gin_trgm_ops);
+CREATE INDEX titles_publisher_trgm_idx ON titles USING GIN (publisher gin_trgm_ops);
+
+ALTER TABLE titles ENABLE ROW LEVEL SECURITY;
+ALTER TABLE titles FORCE ROW LEVEL SECURITY;
+
+CREATE POLICY "Anyone can view titles"
+ON titles FOR SELECT
+USING ( true );
+
+-- Listing a copy of a game nobody listed before adds its title
+CREATE POLICY "Users can add titles"
+ON titles FOR INSERT
+WITH CHECK ( (SELECT current_setting('app.current_user_id', true)::integer) != 0 );
+
+-- To correct misspelled titles and remove those nobody lists anymore
+CREATE POLICY "Admins can change titles"
+ON titles FOR UPDATE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' )
+WITH CHECK ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
+
+CREATE POLICY "Admins can remove titles"
+ON titles FOR DELETE
+USING ( (SELECT current_setting('app.current_user_role', true)) = 'admin' );
@@ -0,0 +1,424 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use axum_extra::TypedHeader;
+use color_eyre::eyre::{Context, eyre};
+use diesel::{ExpressionMethods, HasQuery, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::{
+        auth::{
+            User,
+            cache::CredentialCache,
+            events::{self, AuthEventKind, AuthEventPage, AuthEventsQuery, EventsTemplate},
+            invites::{self, Invites, InvitesTemplate},
+            pool::DatabaseConnection,
+            role::Role,
+            scope::{self, RequireScope},
+        },
+        games::photos,
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{game_photos, games, users},
+    storage::Storage,
+};
+
+#[derive(TemplateOnce)]
+#[template(path = "admin/users.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct UsersTemplate {
+    users: Vec<User>,
+    user_id: i32,
+}
+
+impl Placeholder for UsersTemplate {
+    fn placeholder() -> Self {
+        Self {
+            users: vec![User::placeholder()],
+            user_id: 0,
+        }
+    }
+}
+
+openapi_template!(UsersTemplate, users);
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct UpdateRole {
+    role: Role,
+}
+
+impl Placeholder for UpdateRole {
+    fn placeholder() -> Self {
+        Self {
+            role: Role::Moderator,
+        }
+    }
+}
+
+async fn users_template(conn: &mut AsyncPgConnection, user: &User) -> error::Result<UsersTemplate> {
+    let users = User::query()
+        .order(users::username)
+        .load(conn)
+        .await
+        .wrap_err("Failed to get users")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(UsersTemplate {
+        users,
+        user_id: user.id,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "Admin",
+    description = "List every account and its role. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(UsersTemplate) = "text/html", example = UsersTemplate::render_placeholder),
+                ([User], example = json!([User::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_users(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<UsersTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        users_template(&mut conn, &user).await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{user_id}/role",
+    tag = "Admin",
+    description = "Change the role of an account. Needs the `admin` role.",
+    request_body(content(
+        (UpdateRole, example = UpdateRole::placeholder),
+        (UpdateRole = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(UsersTemplate) = "text/html", example = UsersTemplate::render_placeholder),
+                ([User], example = json!([User::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("user_id" = i32, Path, description = "User ID to change the role of")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, credential_cache))]
+pub async fn update_role(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(credential_cache): State<CredentialCache>,
+    Path(user_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(update): JsonOrForm<UpdateRole>,
+) -> Result<HtmlOrJsonOnce<UsersTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let updated = diesel::update(users::table)
+        .filter(users::id.eq(user_id))
+        .set(users::role.eq(update.role))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update role")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    if updated == 0 {
+        return Err(eyre!("That user doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+    credential_cache.invalidate_user(user_id);
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        users_template(&mut conn, &user).await?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{user_id}",
+    tag = "Admin",
+    description = "Delete an account along with its games and their photos. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(UsersTemplate) = "text/html", example = UsersTemplate::render_placeholder),
+                ([User], example = json!([User::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("user_id" = i32, Path, description = "User ID to delete")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, credential_cache, storage))]
+pub async fn delete_user(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(credential_cache): State<CredentialCache>,
+    State(storage): State<Arc<dyn Storage>>,
+    Path(user_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<UsersTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    if user_id == user.id {
+        return Err(eyre!("Delete your own account from your account settings"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    // The photos go with the account, but their files have to be removed
+    let photo_keys = game_photos::table
+        .inner_join(games::table)
+        .filter(games::owned_by.eq(user_id))
+        .select((game_photos::storage_key, game_photos::thumbnail_key))
+        .load::<(String, String)>(&mut conn)
+        .await
+        .wrap_err("Failed to get photos of user")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deleted = diesel::delete(users::table)
+        .filter(users::id.eq(user_id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to delete user")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    if deleted == 0 {
+        return Err(eyre!("That user doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+    credential_cache.invalidate_user(user_id);
+    photos::remove_files(&*storage, photo_keys).await;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        users_template(&mut conn, &user).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/auth-events",
+    tag = "Admin",
+    description = "Page through the security audit log of every account, newest first. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(EventsTemplate) = "text/html", example = EventsTemplate::render_placeholder),
+                (AuthEventPage, example = AuthEventPage::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("before" = Option<i32>, Query, description = "Only events older than this event ID, from `next_before`"),
+        ("limit" = Option<i64>, Query, description = "Events per page, 50 by default and at most 200"),
+        ("user_id" = Option<i32>, Query, description = "Only events of this user ID"),
+        ("kind" = Option<AuthEventKind>, Query, description = "Only events of this kind")
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_auth_events(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Query(query): Query<AuthEventsQuery>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<EventsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let page = events::load(&mut conn, &query, None).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        EventsTemplate::new(page, &query, "/admin/auth-events", true),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/invites",
+    tag = "Admin",
+    description = "List the invite codes of every account, along with who used them. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(InvitesTemplate) = "text/html", example = InvitesTemplate::render_placeholder),
+                (Invites, example = Invites::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_invites(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<InvitesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        InvitesTemplate::new(invites::load(&mut conn, None).await?, None, true),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/invites/{invite_id}",
+    tag = "Admin",
+    description = "Revoke any invite code that hasn't been used yet. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(InvitesTemplate) = "text/html", example = InvitesTemplate::render_placeholder),
+                (Invites, example = Invites::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("invite_id" = i32, Path, description = "Invite ID to revoke")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_invite(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Path(invite_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<InvitesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    invites::revoke(&mut conn, invite_id, None).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        InvitesTemplate::new(invites::load(&mut conn, None).await?, None, true),
+    ))
+}
@@ -1,28 +1,17 @@
 use axum::{
-    extract::{FromRequestParts, OptionalFromRequestParts, Query},
+    Json,
+    extract::{FromRequestParts, OptionalFromRequestParts, Query, State},
     http::StatusCode,
-    response::Html,
+    response::{Html, IntoResponse, Response},
 };
-use axum_extra::{
-    TypedHeader,
-    extract::{CookieJar, cookie::Cookie},
-    headers::{
-        Authorization,
-        authorization::{Basic, Credentials},
-    },
-};
-use blake3::{Hash, OUT_LEN};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{Context, eyre};
 use diesel::{
-    ExpressionMethods, HasQuery, QueryDsl,
-    backend::Backend,
-    deserialize::{self, FromSql, FromSqlRow},
-    expression::AsExpression,
+    ExpressionMethods, HasQuery, OptionalExtension, QueryDsl, SelectableHelper,
     prelude::{AsChangeset, Insertable},
-    serialize::{self, Output, ToSql},
-    sql_types,
 };
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use sailfish::TemplateSimple;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
@@ -30,23 +19,55 @@ use utoipa::ToSchema;
 
 use crate::{
     Placeholder,
-    api::auth::pool::DatabaseConnection,
+    api::auth::{
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+    },
+    cookies::Jar,
     error::{self, Error, WithStatusCode},
-    html_or_json::HtmlOrJsonHeader,
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonSimple},
     htmx::HxRefresh,
     json_or_form::JsonOrForm,
+    mail::Mailer,
     openapi_template,
     schema::users,
+    state::AppState,
 };
 
-use pool::Pool;
+pub mod cache;
+pub mod deletion;
+pub mod email;
+pub mod events;
+pub mod invites;
+pub mod jwt;
+pub mod oidc;
+pub mod password;
+pub mod reauth;
+pub mod role;
+pub mod scope;
+pub mod session;
+pub mod throttle;
+pub mod tokens;
+pub mod totp;
+
+use cache::CredentialCache;
+use deletion::DeletionConfig;
+use events::{AuthEventKind, ClientInfo};
+use invites::InviteConfig;
+use oidc::{OidcProviders, ProviderLink};
+use password::{PasswordHasher, Verification};
+use reauth::Reauthentication;
+use role::Role;
+use session::SessionConfig;
+use throttle::{LoginAttempt, ThrottleConfig};
 
-#[derive(HasQuery, ToSchema, Deserialize, Serialize, Debug, Default)]
+#[derive(HasQuery, ToSchema, Deserialize, Serialize, Clone, Debug, Default)]
 #[diesel(table_name = crate::schema::users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct User {
     pub id: i32,
     pub username: String,
+    pub role: Role,
 }
 
 #[derive(ToSchema, Deserialize, Serialize, Debug, Default)]
@@ -55,46 +76,26 @@ pub struct Login {
     password: String,
 }
 
-#[repr(transparent)]
-#[derive(Debug, PartialEq, AsExpression, FromSqlRow)]
-#[diesel(sql_type = sql_types::Binary)]
-pub struct DieselHash(Hash);
-
-impl<ST, DB> FromSql<ST, DB> for DieselHash
-where
-    DB: Backend,
-    *const [u8]: FromSql<ST, DB>,
-{
-    #[allow(unsafe_code)] // ptr dereferencing
-    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
-        let slice_ptr = <*const [u8] as FromSql<ST, DB>>::from_sql(bytes)?;
-        // We know that the pointer impl will never return null
-        let bytes = unsafe { &*slice_ptr };
-        let result: [u8; OUT_LEN] = bytes.try_into()?;
-        Ok(DieselHash(result.into()))
-    }
-}
-
-impl Into<Hash> for DieselHash {
-    fn into(self) -> Hash {
-        self.0
-    }
-}
-
-impl Into<DieselHash> for Hash {
-    fn into(self) -> DieselHash {
-        DieselHash(self)
-    }
+#[derive(ToSchema, Deserialize, Serialize, Debug, Default)]
+pub struct UpdateLogin {
+    username: String,
+    /// The new password
+    password: String,
+    #[serde(flatten)]
+    reauth: Reauthentication,
 }
 
-impl<DB> ToSql<sql_types::Binary, DB> for DieselHash
-where
-    DB: Backend,
-    [u8; OUT_LEN]: ToSql<sql_types::Binary, DB>,
-{
-    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
-        self.0.as_bytes().to_sql(out)
-    }
+#[derive(ToSchema, Deserialize, Serialize, Debug, Default)]
+pub struct Signup {
+    username: String,
+    password: String,
+    /// Optional, needed to reset a forgotten password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// Code from the member who invited you. Required if the exchange
+    /// is invite only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invite_code: Option<String>,
 }
 
 #[derive(Insertable, AsChangeset, Debug, PartialEq)]
@@ -102,31 +103,21 @@ where
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct InsertableDatabaseUser {
     username: String,
-    #[diesel(serialize_as = DieselHash)]
-    password: Hash,
+    password: String,
 }
 
-impl Into<InsertableDatabaseUser> for Login {
-    fn into(self) -> InsertableDatabaseUser {
-        let mut hash = blake3::Hasher::new();
-        hash.update(self.username.as_bytes());
-        hash.update(self.password.as_bytes());
-        InsertableDatabaseUser {
+impl Login {
+    async fn into_database_user(
+        self,
+        hasher: &PasswordHasher,
+    ) -> error::Result<InsertableDatabaseUser> {
+        Ok(InsertableDatabaseUser {
+            password: hasher
+                .hash(self.password)
+                .await
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?,
             username: self.username,
-            password: hash.finalize(),
-        }
-    }
-}
-
-impl Into<InsertableDatabaseUser> for Basic {
-    fn into(self) -> InsertableDatabaseUser {
-        let mut hash = blake3::Hasher::new();
-        hash.update(self.username().as_bytes());
-        hash.update(self.password().as_bytes());
-        InsertableDatabaseUser {
-            username: self.username().to_owned(),
-            password: hash.finalize(),
-        }
+        })
     }
 }
 
@@ -136,8 +127,95 @@ impl Into<InsertableDatabaseUser> for Basic {
 pub struct DatabaseUser {
     id: i32,
     username: String,
-    #[diesel(deserialize_as = DieselHash)]
-    password: Hash,
+    role: Role,
+    /// `None` for accounts that only log in through an OpenID Connect provider
+    password: Option<String>,
+}
+
+impl DatabaseUser {
+    /// Checks `password` against the stored hash. If the hash uses the
+    /// legacy blake3 scheme or outdated Argon2 parameters, it is
+    /// replaced with a fresh one on success.
+    async fn verify_password(
+        &self,
+        conn: &mut AsyncPgConnection,
+        hasher: &PasswordHasher,
+        password: &str,
+    ) -> error::Result<bool> {
+        let Some(stored) = self.password.clone() else {
+            hasher
+                .verify_nothing(password.to_owned())
+                .await
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Ok(false);
+        };
+
+        let verification = hasher
+            .verify(self.username.clone(), password.to_owned(), stored)
+            .await
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if verification == Verification::ValidNeedsRehash {
+            let rehashed = hasher
+                .hash(password.to_owned())
+                .await
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            pool::set_current_user(conn, self.id, self.role).await?;
+            diesel::update(users::table)
+                .filter(users::id.eq(self.id))
+                .set(users::password.eq(rehashed))
+                .execute(conn)
+                .await
+                .wrap_err("Failed to upgrade password hash")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        Ok(verification != Verification::Invalid)
+    }
+
+    /// Looks up `username` and checks `password`, recording a failure
+    /// on `attempt` and in the audit log if either is wrong. Marking
+    /// the attempt as succeeded is left to the caller, as a second
+    /// factor may still be missing.
+    async fn find_and_verify(
+        conn: &mut AsyncPgConnection,
+        hasher: &PasswordHasher,
+        attempt: &LoginAttempt<'_>,
+        client: &ClientInfo,
+        username: &str,
+        password: &str,
+    ) -> error::Result<Option<Self>> {
+        attempt.check(conn).await?;
+
+        let user = Self::query()
+            .filter(users::username.eq(username))
+            .get_result(conn)
+            .await
+            .optional()
+            .wrap_err("Failed to get user from database")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let verified = match &user {
+            Some(user) => user.verify_password(conn, hasher, password).await?,
+            None => {
+                hasher
+                    .verify_nothing(password.to_owned())
+                    .await
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+                false
+            }
+        };
+
+        if !verified {
+            attempt.failed(conn).await?;
+            events::record_failed_login(conn, client, user.as_ref().map(|u| u.id), username)
+                .await?;
+            return Ok(None);
+        }
+
+        Ok(user)
+    }
 }
 
 impl Placeholder for User {
@@ -145,6 +223,18 @@ impl Placeholder for User {
         Self {
             id: 1,
             username: String::from("johndoe"),
+            role: Role::User,
+        }
+    }
+}
+
+impl Placeholder for Signup {
+    fn placeholder() -> Self {
+        Self {
+            username: String::from("johndoe"),
+            password: String::from("verySecurePassword1234"),
+            email: Some(String::from("johndoe@example.com")),
+            invite_code: Some(String::from("k7mq-x3pd-w9ht")),
         }
     }
 }
@@ -158,14 +248,24 @@ impl Placeholder for Login {
     }
 }
 
-impl FromRequestParts<Pool> for User {
+impl Placeholder for UpdateLogin {
+    fn placeholder() -> Self {
+        Self {
+            username: String::from("johndoe"),
+            password: String::from("evenMoreSecurePassword5678"),
+            reauth: Reauthentication::placeholder(),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for User {
     type Rejection = error::Error;
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
-        state: &Pool,
+        state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        <User as OptionalFromRequestParts<Pool>>::from_request_parts(parts, state)
+        <User as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state)
             .await?
             .ok_or_else(|| eyre!("Your user wasn't found"))
             .with_status_code(StatusCode::UNAUTHORIZED)
@@ -175,18 +275,16 @@ impl FromRequestParts<Pool> for User {
 pub mod pool {
     use axum::{
         extract::{FromRequestParts, OptionalFromRequestParts},
-        http::{HeaderValue, StatusCode},
+        http::{StatusCode, header},
     };
     use axum_extra::{
         TypedHeader,
-        extract::CookieJar,
         headers::{
             Authorization,
-            authorization::{Basic, Bearer, Credentials},
+            authorization::{Basic, Bearer},
         },
     };
     use color_eyre::eyre::{Context, eyre};
-    use diesel::{ExpressionMethods, HasQuery, QueryDsl};
     use diesel_async::{
         AsyncPgConnection, RunQueryDsl,
         pooled_connection::bb8::{self, RunError},
@@ -194,9 +292,21 @@ pub mod pool {
     use tracing::instrument;
 
     use crate::{
-        api::auth::{DatabaseUser, InsertableDatabaseUser, User},
+        api::auth::{
+            DatabaseUser, User,
+            cache::{CachedCredential, Credential},
+            deletion,
+            events::ClientInfo,
+            role::Role,
+            scope::GrantedScopes,
+            session,
+            throttle::LoginAttempt,
+            tokens, totp,
+        },
+        cookies::Jar,
         error::{self, Actions, WithStatusCode},
-        schema::users,
+        schema::sql_types,
+        state::AppState,
     };
 
     #[derive(Clone)]
@@ -207,13 +317,6 @@ pub mod pool {
             Self(pool)
         }
 
-        fn get(
-            &self,
-        ) -> impl Future<Output = Result<bb8::PooledConnection<'_, AsyncPgConnection>, RunError>>
-        {
-            self.0.get()
-        }
-
         fn get_owned(
             &self,
         ) -> impl Future<Output = Result<bb8::PooledConnection<'static, AsyncPgConnection>, RunError>>
@@ -224,99 +327,281 @@ pub mod pool {
 
     pub struct DatabaseConnection(
         pub bb8::PooledConnection<'static, AsyncPgConnection>,
-        pub CookieJar,
+        pub Jar,
         pub Option<User>,
     );
 
-    impl OptionalFromRequestParts<Pool> for User {
-        type Rejection = error::Error;
+    /// Sets the user id and role that the row level security policies
+    /// check against
+    pub async fn set_current_user(
+        conn: &mut AsyncPgConnection,
+        user_id: i32,
+        role: Role,
+    ) -> Result<(), error::Error> {
+        set_config(conn, user_id, role, false).await
+    }
 
-        #[instrument(skip_all)]
-        async fn from_request_parts(
-            parts: &mut axum::http::request::Parts,
-            pool: &Pool,
-        ) -> Result<Option<Self>, Self::Rejection> {
-            let cookie_jar = CookieJar::from_request_parts(parts, pool)
-                .await
-                .wrap_err("Failed to retreive cookies from header")
-                .with_status_code_and_actions(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Actions::sign_out(),
-                )?;
-
-            if let Some(TypedHeader(Authorization(basic_auth))) = <TypedHeader<Authorization<Basic>> as OptionalFromRequestParts<
-            Pool,
-            >>::from_request_parts(parts, pool)
-            .await
-            .wrap_err("Failed to parse basic auth header")
-            .with_status_code_and_actions(StatusCode::BAD_REQUEST, Actions::sign_out())?
-            .or_else(|| cookie_jar.get("sessionid").and_then(|sessionid| Some(TypedHeader(Authorization(Basic::decode(&HeaderValue::from_str(sessionid.value()).ok()?)?)))))
-            {
-                let mut conn = pool
-                    .get()
+    /// Like `set_current_user`, but only until the current transaction
+    /// ends, so the connection goes back to the pool as nobody. For work
+    /// outside of requests, whose connections nothing resets.
+    pub async fn set_transaction_user(
+        conn: &mut AsyncPgConnection,
+        user_id: i32,
+        role: Role,
+    ) -> Result<(), error::Error> {
+        set_config(conn, user_id, role, true).await
+    }
+
+    async fn set_config(
+        conn: &mut AsyncPgConnection,
+        user_id: i32,
+        role: Role,
+        is_local: bool,
+    ) -> Result<(), error::Error> {
+        diesel::sql_query(
+            r#"SELECT set_config('app.current_user_id', $1::text, $3), set_config('app.current_user_role', $2::text, $3)"#,
+        )
+        .bind::<diesel::sql_types::Integer, _>(user_id)
+        .bind::<sql_types::UserRole, _>(role)
+        .bind::<diesel::sql_types::Bool, _>(is_local)
+        .execute(conn)
+        .await
+        .wrap_err("Failed to set user on connection")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(())
+    }
+
+    /// A connection that is only checked out of the pool once it's
+    /// needed, as most credentials are checked without the database
+    struct LazyConnection<'a> {
+        pool: &'a Pool,
+        conn: Option<bb8::PooledConnection<'static, AsyncPgConnection>>,
+    }
+
+    impl<'a> LazyConnection<'a> {
+        fn new(pool: &'a Pool) -> Self {
+            Self { pool, conn: None }
+        }
+
+        async fn get(&mut self) -> error::Result<&mut AsyncPgConnection> {
+            let conn = match self.conn.take() {
+                Some(conn) => conn,
+                None => self
+                    .pool
+                    .get_owned()
                     .await
                     .wrap_err("Failed to get connection to database")
-                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?,
+            };
+            let conn: &mut AsyncPgConnection = self.conn.insert(conn);
+            Ok(conn)
+        }
 
-                let user = DatabaseUser::query().filter(users::username.eq(basic_auth.username())).get_result(&mut conn).await.wrap_err("Failed to get user from database").with_status_code_and_actions(StatusCode::INTERNAL_SERVER_ERROR, Actions::sign_out())?;     
+        async fn into_owned(
+            self,
+        ) -> error::Result<bb8::PooledConnection<'static, AsyncPgConnection>> {
+            match self.conn {
+                Some(conn) => Ok(conn),
+                None => self
+                    .pool
+                    .get_owned()
+                    .await
+                    .wrap_err("Failed to get connection to database")
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR),
+            }
+        }
+    }
+
+    /// The user a request was authenticated as. Kept in the request
+    /// extensions, so every extractor after the first one reuses it.
+    #[derive(Clone)]
+    struct ResolvedUser(Option<User>);
 
-                let login_attempt: InsertableDatabaseUser = basic_auth.into();
+    async fn resolve(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+        conn: &mut LazyConnection<'_>,
+    ) -> Result<Option<User>, error::Error> {
+        if let Some(ResolvedUser(user)) = parts.extensions.get::<ResolvedUser>() {
+            return Ok(user.clone());
+        }
+
+        let user = authenticate(parts, state, conn).await?;
+        parts.extensions.insert(ResolvedUser(user.clone()));
+        Ok(user)
+    }
+
+    async fn authenticate(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+        conn: &mut LazyConnection<'_>,
+    ) -> Result<Option<User>, error::Error> {
+        let cookie_jar = Jar::from_headers(&parts.headers, &state.cookies);
+
+        parts.extensions.insert(GrantedScopes::All);
+
+        let scheme = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .map(|(scheme, _)| scheme.to_ascii_lowercase());
+
+        match scheme.as_deref() {
+            Some("basic") => {
+                let TypedHeader(Authorization(basic_auth)) = <TypedHeader<Authorization<Basic>> as FromRequestParts<AppState>>::from_request_parts(parts, state)
+                    .await
+                    .wrap_err("Failed to parse basic auth header")
+                    .with_status_code_and_actions(StatusCode::BAD_REQUEST, Actions::sign_out())?;
 
-                if login_attempt.password != user.password {
-                    return Err(eyre!("Passwords didn't match")).with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out())
+                let credential = Credential::Basic {
+                    username: basic_auth.username(),
+                    password: basic_auth.password(),
+                };
+                if let Some(cached) = state.credential_cache.get(&credential) {
+                    return Ok(Some(cached.user));
                 }
 
-                return Ok(Some(User {
+                let client = ClientInfo::from_request_parts(parts, state).await?;
+                let attempt = LoginAttempt::new(&state.throttle, client.ip, basic_auth.username());
+                let conn = conn.get().await?;
+
+                let Some(user) = DatabaseUser::find_and_verify(
+                    conn,
+                    &state.hasher,
+                    &attempt,
+                    &client,
+                    basic_auth.username(),
+                    basic_auth.password(),
+                )
+                .await?
+                else {
+                    return Err(eyre!("Passwords didn't match")).with_status_code_and_actions(
+                        StatusCode::UNAUTHORIZED,
+                        Actions::sign_out(),
+                    );
+                };
+
+                // Basic auth has no way to carry the second factor
+                if totp::is_enabled(conn, user.id).await? {
+                    return Err(eyre!(
+                        "Basic auth isn't available with two-factor authentication, use an access token"
+                    ))
+                    .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
+                }
+                if deletion::scheduled(conn, user.id).await?.is_some() {
+                    return Err(eyre!(
+                        "Your account is scheduled for deletion, log in to cancel it"
+                    ))
+                    .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
+                }
+                // Every request logs in again, so only failures make
+                // it into the audit log
+                attempt.succeeded(conn).await?;
+
+                let user = User {
                     id: user.id,
-                    username: user.username
-                }))
+                    username: user.username,
+                    role: user.role,
+                };
+                state.credential_cache.insert(
+                    &credential,
+                    CachedCredential {
+                        user: user.clone(),
+                        scopes: GrantedScopes::All,
+                        expires_at: None,
+                    },
+                );
+                return Ok(Some(user));
             }
+            Some("bearer") => {
+                let TypedHeader(Authorization(bearer_auth)) = <TypedHeader<
+                    Authorization<Bearer>,
+                > as FromRequestParts<AppState>>::from_request_parts(
+                    parts, state
+                )
+                .await
+                .wrap_err("Failed to parse bearer auth header")
+                .with_status_code_and_actions(StatusCode::BAD_REQUEST, Actions::sign_out())?;
 
-            if let Some(TypedHeader(Authorization(bearer_auth))) = <TypedHeader<
-                Authorization<Bearer>,
-            > as OptionalFromRequestParts<Pool>>::from_request_parts(
-                parts, pool
-            )
-            .await
-            .wrap_err("Failed to parse bearer auth header")
-            .with_status_code_and_actions(StatusCode::BAD_REQUEST, Actions::sign_out())?
-            {
+                if bearer_auth.token().starts_with(tokens::PREFIX) {
+                    let credential = Credential::AccessToken(bearer_auth.token());
+                    if let Some(cached) = state.credential_cache.get(&credential) {
+                        parts.extensions.insert(cached.scopes);
+                        return Ok(Some(cached.user));
+                    }
+
+                    let Some((user, scopes, expires_at)) =
+                        tokens::authenticate(conn.get().await?, bearer_auth.token()).await?
+                    else {
+                        return Err(eyre!("Invalid or expired access token"))
+                            .with_status_code_and_actions(
+                                StatusCode::UNAUTHORIZED,
+                                Actions::sign_out(),
+                            );
+                    };
+
+                    let scopes = GrantedScopes::Only(scopes);
+                    state.credential_cache.insert(
+                        &credential,
+                        CachedCredential {
+                            user: user.clone(),
+                            scopes: scopes.clone(),
+                            expires_at,
+                        },
+                    );
+                    parts.extensions.insert(scopes);
+                    return Ok(Some(user));
+                }
+
+                return state
+                    .jwt
+                    .verify(bearer_auth.token())
+                    .map(Some)
+                    .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
             }
+            _ => {}
+        }
 
-            return Ok(None);
+        if let Some(sessionid) = cookie_jar.get(session::COOKIE_NAME) {
+            return session::authenticate(conn.get().await?, sessionid.value()).await;
         }
+
+        Ok(None)
     }
 
-    impl FromRequestParts<Pool> for DatabaseConnection {
+    impl OptionalFromRequestParts<AppState> for User {
         type Rejection = error::Error;
 
         #[instrument(skip_all)]
         async fn from_request_parts(
             parts: &mut axum::http::request::Parts,
-            pool: &Pool,
+            state: &AppState,
+        ) -> Result<Option<Self>, Self::Rejection> {
+            resolve(parts, state, &mut LazyConnection::new(&state.pool)).await
+        }
+    }
+
+    impl FromRequestParts<AppState> for DatabaseConnection {
+        type Rejection = error::Error;
+
+        #[instrument(skip_all)]
+        async fn from_request_parts(
+            parts: &mut axum::http::request::Parts,
+            state: &AppState,
         ) -> Result<Self, Self::Rejection> {
-            let user =
-                <User as OptionalFromRequestParts<Pool>>::from_request_parts(parts, pool).await?;
+            // Whatever connection authenticating needed is handed on to
+            // the handler, instead of checking out a second one
+            let mut conn = LazyConnection::new(&state.pool);
+            let user = resolve(parts, state, &mut conn).await?;
 
-            let cookie_jar = CookieJar::from_request_parts(parts, pool)
-                .await
-                .wrap_err("Failed to retreive cookies from header")
-                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            let cookie_jar = Jar::from_headers(&parts.headers, &state.cookies);
 
-            let mut conn = pool
-                .get_owned()
-                .await
-                .wrap_err("Failed to get connection to database")
-                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            let mut conn = conn.into_owned().await?;
 
-            diesel::sql_query(r#"SELECT set_config('app.current_user_id', $1::text, false)"#)
-                .bind::<diesel::sql_types::Integer, _>(
-                    user.as_ref().map(|u| u.id).unwrap_or_default(),
-                )
-                .execute(&mut conn)
-                .await
-                .wrap_err("Failed to set user id on connection")
-                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (user_id, role) = user.as_ref().map(|u| (u.id, u.role)).unwrap_or_default();
+            set_current_user(&mut conn, user_id, role).await?;
 
             Ok(Self(conn, cookie_jar, user))
         }
@@ -327,10 +612,10 @@ pub mod pool {
     post,
     path = "/auth/signup",
     tag = "Users",
-    description = "Create a new account",
+    description = "Create a new account. If an email address is given, a link to verify it is mailed to it. If the exchange is invite only, an unused invite code is required.",
     request_body(content(
-        (Login, example = Login::placeholder),
-        (Login = "application/x-www-form-urlencoded")
+        (Signup, example = Signup::placeholder),
+        (Signup = "application/x-www-form-urlencoded")
     )),
     responses(
         (status = OK, description = "Ok",
@@ -350,40 +635,109 @@ pub mod pool {
         ),
     ),
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, hasher, mailer, new_user))]
 pub async fn signup(
     DatabaseConnection(mut conn, jar, _): DatabaseConnection,
+    State(hasher): State<PasswordHasher>,
+    State(session_config): State<SessionConfig>,
+    State(mailer): State<Mailer>,
+    State(invite_config): State<InviteConfig>,
+    client: ClientInfo,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
-    JsonOrForm(new_user): JsonOrForm<Login>,
-) -> Result<(CookieJar, TypedHeader<HxRefresh>), error::Error> {
-    let encoded = Authorization::basic(&new_user.username, &new_user.password);
-    let db_user: InsertableDatabaseUser = new_user.into();
+    JsonOrForm(new_user): JsonOrForm<Signup>,
+) -> Result<(Jar, TypedHeader<HxRefresh>), error::Error> {
+    let invite_code = new_user
+        .invite_code
+        .as_deref()
+        .filter(|code| !code.trim().is_empty());
+    if invite_config.invite_only && invite_code.is_none() {
+        return Err(eyre!("Signing up needs an invite code from a member"))
+            .with_status_code(StatusCode::FORBIDDEN);
+    }
 
-    diesel::insert_into(users::table)
-        .values(db_user)
-        .execute(&mut conn)
-        .await
+    let email = new_user
+        .email
+        .as_deref()
+        .filter(|email| !email.trim().is_empty())
+        .map(email::parse_address)
+        .transpose()?;
+
+    let db_user = Login {
+        username: new_user.username,
+        password: new_user.password,
+    }
+    .into_database_user(&hasher)
+    .await?;
+
+    // Claimed before the account exists, so two signups can't both use it
+    let invite_id = match invite_code {
+        Some(code) => Some(invites::claim(&mut conn, code).await?),
+        None => None,
+    };
+
+    let inserted = diesel::insert_into(users::table)
+        .values((
+            db_user,
+            users::email.eq(email.as_ref().map(AsRef::<str>::as_ref)),
+        ))
+        .returning(User::as_returning())
+        .get_result(&mut conn)
+        .await;
+    if inserted.is_err()
+        && let Some(invite_id) = invite_id
+    {
+        // Whoever tries again with another username can still use it
+        invites::release(&mut conn, invite_id).await?;
+    }
+    let user = inserted
         .wrap_err("Failed to insert user into database")
         .with_status_code(StatusCode::BAD_REQUEST)?;
+    let user_id = user.id;
+    if let Some(invite_id) = invite_id {
+        invites::redeem(&mut conn, invite_id, user_id).await?;
+    }
+    events::record(&mut conn, &client, AuthEventKind::Signup, user_id).await?;
 
-    let header_value = encoded.0.encode();
-    let mut cookie = Cookie::new(
-        "sessionid",
-        header_value
-            .to_str()
-            .wrap_err("Failed to encode sessionid")
-            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
-            .to_owned(),
-    );
-    cookie.set_path("/");
-    Ok((jar.add(cookie), TypedHeader(HxRefresh(true))))
+    if let Some(email) = email {
+        email::send_verification(&mut conn, &mailer, &user, &email).await?;
+    }
+
+    let token = session::create(
+        &mut conn,
+        &session_config,
+        user_id,
+        client.user_agent.as_deref(),
+    )
+    .await?;
+
+    Ok((
+        session::set_cookie(jar, &session_config, token),
+        TypedHeader(HxRefresh(true)),
+    ))
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct SecondFactorRequired {
+    second_factor_required: bool,
+    /// Pass this to `/auth/login/totp` along with the code
+    challenge: String,
+}
+
+impl Placeholder for SecondFactorRequired {
+    fn placeholder() -> Self {
+        Self {
+            second_factor_required: true,
+            challenge: "q0Xc2m3Jc9Jw1r0kq7wz8d5m0Jb6mC8d4Xn1Zr2yT3s".to_owned(),
+        }
+    }
 }
 
 #[utoipa::path(
     post,
     path = "/auth/login",
     tag = "Users",
-    description = "Login to your account",
+    description = "Login to your account. Accounts with two-factor authentication \
+        get a challenge instead, to be finished with `/auth/login/totp`.",
     request_body(content(
         (Login, example = Login::placeholder),
         (Login = "application/x-www-form-urlencoded")
@@ -393,6 +747,10 @@ pub async fn signup(
             headers(
                 ("Set-Cookie" = String)
             ),
+            content(
+                (inline(LoginTemplate) = "text/html", example = LoginTemplate::render_placeholder),
+                (SecondFactorRequired, example = SecondFactorRequired::placeholder)
+            )
         ),
         (status = "4XX", description = "You did something wrong",
             content(
@@ -406,37 +764,75 @@ pub async fn signup(
         ),
     ),
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, hasher, new_user))]
 pub async fn login(
     DatabaseConnection(mut conn, jar, _): DatabaseConnection,
+    State(hasher): State<PasswordHasher>,
+    State(session_config): State<SessionConfig>,
+    State(throttle_config): State<ThrottleConfig>,
+    client: ClientInfo,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
     JsonOrForm(new_user): JsonOrForm<Login>,
-) -> Result<(CookieJar, TypedHeader<HxRefresh>), error::Error> {
-    let encoded = Authorization::basic(&new_user.username, &new_user.password);
-    let db_user: InsertableDatabaseUser = new_user.into();
+) -> Result<Response, error::Error> {
+    let attempt = LoginAttempt::new(&throttle_config, client.ip, &new_user.username);
 
-    let user = DatabaseUser::query()
-        .filter(users::username.eq(db_user.username))
-        .get_result(&mut conn)
-        .await
-        .wrap_err("Failed to get user from database")
-        .with_status_code(StatusCode::BAD_REQUEST)?;
+    let Some(user) = DatabaseUser::find_and_verify(
+        &mut conn,
+        &hasher,
+        &attempt,
+        &client,
+        &new_user.username,
+        &new_user.password,
+    )
+    .await?
+    else {
+        return Err(eyre!("Invalid username or password"))
+            .with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let user_agent = client.user_agent.as_deref();
 
-    if user.password == db_user.password {
-        let header_value = encoded.0.encode();
-        let mut cookie = Cookie::new(
-            "sessionid",
-            header_value
-                .to_str()
-                .wrap_err("Failed to encode sessionid")
-                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
-                .to_owned(),
-        );
-        cookie.set_path("/");
-        Ok((jar.add(cookie), TypedHeader(HxRefresh(true))))
-    } else {
-        Err(eyre!("Invalid username or password")).with_status_code(StatusCode::UNAUTHORIZED)
+    // The login only succeeded once the second factor checks out
+    if totp::is_enabled(&mut conn, user.id).await? {
+        let challenge = session::create_pending(&mut conn, user.id, user_agent).await?;
+        let jar = session::set_pending_cookie(jar, challenge.clone());
+
+        return Ok(match accept {
+            HtmlOrJsonHeader::Html => (
+                jar,
+                HtmlOrJsonSimple(
+                    accept,
+                    LoginTemplate {
+                        user: None,
+                        editing: false,
+                        second_factor: true,
+                        providers: Vec::new(),
+                        delete_after: None,
+                        invite_only: false,
+                    },
+                ),
+            )
+                .into_response(),
+            HtmlOrJsonHeader::Json => (
+                jar,
+                Json(SecondFactorRequired {
+                    second_factor_required: true,
+                    challenge,
+                }),
+            )
+                .into_response(),
+        });
     }
+
+    attempt.succeeded(&mut conn).await?;
+    events::record(&mut conn, &client, AuthEventKind::LoginSucceeded, user.id).await?;
+    let token = session::create(&mut conn, &session_config, user.id, user_agent).await?;
+
+    Ok((
+        session::set_cookie(jar, &session_config, token),
+        TypedHeader(HxRefresh(true)),
+    )
+        .into_response())
 }
 
 #[derive(TemplateSimple)]
@@ -445,6 +841,14 @@ pub async fn login(
 pub struct LoginTemplate {
     user: Option<User>,
     editing: bool,
+    /// Ask for the second factor of a login in progress
+    second_factor: bool,
+    /// OpenID Connect providers to offer next to the password form
+    providers: Vec<ProviderLink>,
+    /// When the account is going to be deleted, if it is
+    delete_after: Option<DateTime<Utc>>,
+    /// Ask for an invite code to sign up
+    invite_only: bool,
 }
 
 impl Placeholder for LoginTemplate {
@@ -452,6 +856,10 @@ impl Placeholder for LoginTemplate {
         Self {
             user: None,
             editing: false,
+            second_factor: false,
+            providers: Vec::new(),
+            delete_after: None,
+            invite_only: false,
         }
     }
 }
@@ -489,16 +897,36 @@ pub struct EditQuery {
         ("edit" = Option<bool>, Query, description = "If logged in, returns a form for your credentials that is editable")
     )
 )]
-#[instrument]
+#[instrument(skip(conn, providers))]
 pub async fn get_login(
-    user: Option<User>,
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    State(providers): State<OidcProviders>,
+    State(invite_config): State<InviteConfig>,
     Query(edit): Query<EditQuery>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
 ) -> Result<Html<String>, error::Error> {
+    // A login that went through an OpenID Connect provider comes back
+    // here with a session that still needs the second factor
+    let second_factor = match (&user, jar.get(session::COOKIE_NAME)) {
+        (None, Some(sessionid)) => session::pending_user(&mut conn, sessionid.value())
+            .await?
+            .is_some(),
+        _ => false,
+    };
+
+    let delete_after = match &user {
+        Some(user) => deletion::scheduled(&mut conn, user.id).await?,
+        None => None,
+    };
+
     Ok(Html(
         LoginTemplate {
             user,
             editing: edit.edit.unwrap_or_default(),
+            second_factor,
+            providers: providers.links(),
+            delete_after,
+            invite_only: invite_config.invite_only,
         }
         .render_once()
         .wrap_err("Failed to render login template")
@@ -510,10 +938,10 @@ pub async fn get_login(
     patch,
     path = "/auth/login",
     tag = "Users",
-    description = "Edit login information",
+    description = "Edit login information. Needs your current password or a code from your authenticator app.",
     request_body(content(
-        (Login, example = Login::placeholder),
-        (Login = "application/x-www-form-urlencoded")
+        (UpdateLogin, example = UpdateLogin::placeholder),
+        (UpdateLogin = "application/x-www-form-urlencoded")
     )),
     responses(
         (status = OK, description = "Ok",
@@ -533,15 +961,38 @@ pub async fn get_login(
         ),
     ),
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, hasher, credential_cache, _scope, changeset_user))]
 pub async fn patch_login(
     DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(hasher): State<PasswordHasher>,
+    State(throttle_config): State<ThrottleConfig>,
+    State(credential_cache): State<CredentialCache>,
+    client: ClientInfo,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
-    JsonOrForm(changeset_user): JsonOrForm<Login>,
-) -> Result<(CookieJar, TypedHeader<HxRefresh>), error::Error> {
-    let encoded = Authorization::basic(&changeset_user.username, &changeset_user.password);
-    let user_id = user.map(|u| u.id).unwrap_or_default();
-    let db_user: InsertableDatabaseUser = changeset_user.into();
+    JsonOrForm(changeset_user): JsonOrForm<UpdateLogin>,
+) -> Result<(Jar, TypedHeader<HxRefresh>), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    reauth::require(
+        &mut conn,
+        &hasher,
+        &throttle_config,
+        &client,
+        &jar,
+        &user,
+        &changeset_user.reauth,
+    )
+    .await?;
+
+    let user_id = user.id;
+    let db_user = Login {
+        username: changeset_user.username,
+        password: changeset_user.password,
+    }
+    .into_database_user(&hasher)
+    .await?;
 
     diesel::update(users::table)
         .filter(users::id.eq(user_id))
@@ -550,19 +1001,19 @@ pub async fn patch_login(
         .await
         .wrap_err("Failed to update user in database")
         .with_status_code(StatusCode::BAD_REQUEST)?;
+    credential_cache.invalidate_user(user_id);
+    events::record(&mut conn, &client, AuthEventKind::PasswordChanged, user_id).await?;
 
-    let header_value = encoded.0.encode();
-    let mut cookie = Cookie::new(
-        "sessionid",
-        header_value
-            .to_str()
-            .wrap_err("Failed to encode sessionid")
-            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
-            .to_owned(),
-    );
-    cookie.set_path("/");
+    // Anyone else holding a session needed the old credentials
+    session::revoke_others(
+        &mut conn,
+        user_id,
+        jar.get(session::COOKIE_NAME)
+            .map(|sessionid| sessionid.value()),
+    )
+    .await?;
 
-    Ok((jar.add(cookie), TypedHeader(HxRefresh(true))))
+    Ok((jar, TypedHeader(HxRefresh(true))))
 }
 
 #[utoipa::path(
@@ -588,18 +1039,30 @@ pub async fn patch_login(
         ),
     ),
 )]
-#[instrument]
-pub async fn logout(jar: CookieJar) -> (CookieJar, TypedHeader<HxRefresh>) {
-    let mut cookie = Cookie::from("sessionid");
-    cookie.set_path("/");
-    (jar.remove(cookie), TypedHeader(HxRefresh(true)))
+#[instrument(skip(conn))]
+pub async fn logout(
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    client: ClientInfo,
+) -> Result<(Jar, TypedHeader<HxRefresh>), error::Error> {
+    if let Some(sessionid) = jar.get(session::COOKIE_NAME) {
+        session::revoke(&mut conn, sessionid.value()).await?;
+    }
+    if let Some(user) = user {
+        events::record(&mut conn, &client, AuthEventKind::Logout, user.id).await?;
+    }
+
+    Ok((session::remove_cookie(jar), TypedHeader(HxRefresh(true))))
 }
 
 #[utoipa::path(
     delete,
     path = "/auth/login",
     tag = "Users",
-    description = "Delete account",
+    description = "Delete account. It is logged out everywhere and its listings are hidden, but it is only deleted once the grace period is over. Logging back in before that lets you cancel it. Needs your current password or a code from your authenticator app.",
+    request_body(content(
+        (Reauthentication, example = Reauthentication::placeholder),
+        (Reauthentication = "application/x-www-form-urlencoded")
+    )),
     responses(
         (status = OK, description = "Ok",
             headers(
@@ -618,20 +1081,35 @@ pub async fn logout(jar: CookieJar) -> (CookieJar, TypedHeader<HxRefresh>) {
         ),
     ),
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope, hasher, credential_cache, credentials))]
 pub async fn delete_login(
     DatabaseConnection(mut conn, jar, user): DatabaseConnection,
-) -> Result<(CookieJar, TypedHeader<HxRefresh>), error::Error> {
-    let user_id = user.map(|u| u.id).unwrap_or_default();
+    _scope: RequireScope<scope::Account>,
+    State(hasher): State<PasswordHasher>,
+    State(throttle_config): State<ThrottleConfig>,
+    State(deletion_config): State<DeletionConfig>,
+    State(credential_cache): State<CredentialCache>,
+    client: ClientInfo,
+    JsonOrForm(credentials): JsonOrForm<Reauthentication>,
+) -> Result<(Jar, TypedHeader<HxRefresh>), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    reauth::require(
+        &mut conn,
+        &hasher,
+        &throttle_config,
+        &client,
+        &jar,
+        &user,
+        &credentials,
+    )
+    .await?;
 
-    diesel::delete(users::table)
-        .filter(users::id.eq(user_id))
-        .execute(&mut conn)
-        .await
-        .wrap_err("Failed to delete user from database")
-        .with_status_code(StatusCode::BAD_REQUEST)?;
+    deletion::schedule(&mut conn, &deletion_config, user.id).await?;
+    credential_cache.invalidate_user(user.id);
+    events::record(&mut conn, &client, AuthEventKind::AccountDeleted, user.id).await?;
+    session::revoke_others(&mut conn, user.id, None).await?;
 
-    let mut cookie = Cookie::from("sessionid");
-    cookie.set_path("/");
-    Ok((jar.remove(cookie), TypedHeader(HxRefresh(true))))
+    Ok((session::remove_cookie(jar), TypedHeader(HxRefresh(true))))
 }
@@ -0,0 +1,143 @@
+//! Short-lived cache of verified credentials
+//!
+//! Checking a basic auth password runs Argon2, and an access token is
+//! looked up and has its last use written on every request. With
+//! `--credential-cache-seconds` set, the user either resolved to is
+//! remembered for that long, keyed by a keyed hash of the credentials.
+//! Changing the password, role, second factor or access tokens of an
+//! account, or deleting it, drops its entries right away.
+//!
+//! Sessions aren't cached, as revoking one has to take effect on the
+//! next request, and JWTs are verified without the database anyway.
+//! Every instance has a cache of its own, so other instances only
+//! notice a change once their entries expire.
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use clap::Args;
+use moka::sync::Cache;
+use serde::Deserialize;
+
+use crate::api::auth::{User, scope::GrantedScopes};
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct CredentialCacheConfig {
+    /// How many seconds verified basic auth credentials and access
+    /// tokens are remembered. 0 turns the cache off.
+    #[clap(long, env = "CREDENTIAL_CACHE_SECONDS")]
+    #[serde(default)]
+    pub credential_cache_seconds: u64,
+    /// How many credentials are remembered at most
+    #[clap(long, env = "CREDENTIAL_CACHE_CAPACITY")]
+    #[serde(default = "default_capacity")]
+    pub credential_cache_capacity: u64,
+}
+
+#[inline]
+const fn default_capacity() -> u64 {
+    10_000
+}
+
+impl Default for CredentialCacheConfig {
+    fn default() -> Self {
+        Self {
+            credential_cache_seconds: 0,
+            credential_cache_capacity: default_capacity(),
+        }
+    }
+}
+
+pub enum Credential<'a> {
+    Basic {
+        username: &'a str,
+        password: &'a str,
+    },
+    AccessToken(&'a str),
+}
+
+/// What a credential resolved to
+#[derive(Clone, Debug)]
+pub struct CachedCredential {
+    pub user: User,
+    pub scopes: GrantedScopes,
+    /// When the credential stops working, `None` if it doesn't
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+struct Inner {
+    /// Random per process, so the keys are useless outside of it
+    key: [u8; blake3::KEY_LEN],
+    cache: Cache<[u8; blake3::OUT_LEN], CachedCredential>,
+}
+
+#[derive(Clone)]
+pub struct CredentialCache(Option<Arc<Inner>>);
+
+impl CredentialCache {
+    pub fn new(config: &CredentialCacheConfig) -> Self {
+        Self((config.credential_cache_seconds > 0).then(|| {
+            Arc::new(Inner {
+                key: rand::random(),
+                cache: Cache::builder()
+                    .max_capacity(config.credential_cache_capacity)
+                    .time_to_live(Duration::from_secs(config.credential_cache_seconds))
+                    .support_invalidation_closures()
+                    .build(),
+            })
+        }))
+    }
+
+    fn key(key: &[u8; blake3::KEY_LEN], credential: &Credential) -> [u8; blake3::OUT_LEN] {
+        let mut hasher = blake3::Hasher::new_keyed(key);
+        match credential {
+            Credential::Basic { username, password } => {
+                hasher.update(b"basic\0");
+                hasher.update(username.as_bytes());
+                hasher.update(b"\0");
+                hasher.update(password.as_bytes());
+            }
+            Credential::AccessToken(token) => {
+                hasher.update(b"token\0");
+                hasher.update(token.as_bytes());
+            }
+        }
+        *hasher.finalize().as_bytes()
+    }
+
+    pub fn get(&self, credential: &Credential) -> Option<CachedCredential> {
+        let inner = self.0.as_ref()?;
+        let key = Self::key(&inner.key, credential);
+        let cached = inner.cache.get(&key)?;
+
+        // An access token can expire before its entry does
+        if cached
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            inner.cache.invalidate(&key);
+            return None;
+        }
+        Some(cached)
+    }
+
+    pub fn insert(&self, credential: &Credential, cached: CachedCredential) {
+        if let Some(inner) = &self.0 {
+            inner
+                .cache
+                .insert(Self::key(&inner.key, credential), cached);
+        }
+    }
+
+    /// Forgets every credential of `user_id`. Call this whenever what
+    /// they resolve to changes or stops being valid.
+    pub fn invalidate_user(&self, user_id: i32) {
+        if let Some(inner) = &self.0
+            && let Err(e) = inner
+                .cache
+                .invalidate_entries_if(move |_, cached| cached.user.id == user_id)
+        {
+            // Only fails if the cache was built without support for it
+            tracing::error!("Failed to invalidate cached credentials: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Deleting accounts after a grace period
+//!
+//! `DELETE /auth/login` only schedules the deletion and logs out
+//! everywhere, which revokes the access tokens of the account too. Until
+//! it's cancelled, neither access tokens nor basic auth are accepted for
+//! it. The listings of the account are hidden right away by the row
+//! level security policies. Logging back in before the grace period is
+//! over offers to cancel it, after that a background task deletes the
+//! account along with everything that references it.
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use axum::http::StatusCode;
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Duration, Utc};
+use clap::Args;
+use color_eyre::eyre::{Context, eyre};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, dsl::now};
+use diesel_async::{
+    AsyncConnection, AsyncPgConnection, RunQueryDsl, pooled_connection::bb8,
+    scoped_futures::ScopedFutureExt,
+};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::{
+    api::{
+        auth::{
+            cache::CredentialCache,
+            pool::{self, DatabaseConnection},
+            role::Role,
+            scope::{self, RequireScope},
+        },
+        games::photos,
+    },
+    error::{self, Error, WithStatusCode},
+    htmx::HxRefresh,
+    schema::{api_tokens, game_photos, games, users},
+    storage::Storage,
+};
+
+/// How often to look for accounts whose grace period is over
+const PURGE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct DeletionConfig {
+    /// How many days a deleted account can still be restored
+    #[clap(long, env = "DELETION_GRACE_DAYS")]
+    #[serde(default = "default_grace_days")]
+    pub deletion_grace_days: u32,
+}
+
+#[inline]
+const fn default_grace_days() -> u32 {
+    30
+}
+
+impl Default for DeletionConfig {
+    fn default() -> Self {
+        Self {
+            deletion_grace_days: default_grace_days(),
+        }
+    }
+}
+
+/// Schedules `user_id` for deletion once the grace period is over and
+/// revokes its access tokens
+pub async fn schedule(
+    conn: &mut AsyncPgConnection,
+    config: &DeletionConfig,
+    user_id: i32,
+) -> error::Result<DateTime<Utc>> {
+    let delete_after = Utc::now() + Duration::days(config.deletion_grace_days.into());
+
+    diesel::update(users::table)
+        .filter(users::id.eq(user_id))
+        .set(users::delete_after.eq(delete_after))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to schedule account deletion")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::delete(api_tokens::table)
+        .filter(api_tokens::user_id.eq(user_id))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to revoke access tokens")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(delete_after)
+}
+
+/// When `user_id` is going to be deleted, if it is
+pub async fn scheduled(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> error::Result<Option<DateTime<Utc>>> {
+    users::table
+        .filter(users::id.eq(user_id))
+        .select(users::delete_after)
+        .get_result::<Option<DateTime<Utc>>>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get account deletion")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+        .map(Option::flatten)
+}
+
+/// Deletes every account whose grace period is over, returning how many
+async fn purge(
+    pool: &bb8::Pool<AsyncPgConnection>,
+    credential_cache: &CredentialCache,
+    storage: &dyn Storage,
+) -> error::Result<usize> {
+    let mut conn = pool
+        .get()
+        .await
+        .wrap_err("Failed to get connection to database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (deleted, photo_keys) = conn
+        .transaction::<_, error::Error, _>(|conn| {
+            async move {
+                // Only admins may delete other people's profiles. The
+                // connection isn't reset like those of requests are, so
+                // being admin mustn't outlast the transaction.
+                pool::set_transaction_user(conn, 0, Role::Admin).await?;
+
+                // The photos go with the accounts, but their files have
+                // to be removed
+                let photo_keys = game_photos::table
+                    .inner_join(games::table.inner_join(users::table))
+                    .filter(users::delete_after.le(now))
+                    .select((
+                        users::id,
+                        game_photos::storage_key,
+                        game_photos::thumbnail_key,
+                    ))
+                    .load::<(i32, String, String)>(conn)
+                    .await
+                    .wrap_err("Failed to get photos of deleted accounts")
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+                let deleted = diesel::delete(users::table)
+                    .filter(users::delete_after.le(now))
+                    .returning(users::id)
+                    .get_results::<i32>(conn)
+                    .await
+                    .wrap_err("Failed to delete accounts")
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+                Ok((deleted, photo_keys))
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    for user_id in &deleted {
+        credential_cache.invalidate_user(*user_id);
+    }
+    // Accounts that were restored just now keep theirs
+    photos::remove_files(
+        storage,
+        photo_keys
+            .into_iter()
+            .filter(|(user_id, _, _)| deleted.contains(user_id))
+            .map(|(_, storage_key, thumbnail_key)| (storage_key, thumbnail_key))
+            .collect(),
+    )
+    .await;
+
+    Ok(deleted.len())
+}
+
+/// Runs `purge` every `PURGE_INTERVAL`, meant to be spawned on startup
+pub async fn purge_periodically(
+    pool: bb8::Pool<AsyncPgConnection>,
+    credential_cache: CredentialCache,
+    storage: Arc<dyn Storage>,
+) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match purge(&pool, &credential_cache, &*storage).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Deleted {} accounts after their grace period", deleted),
+            Err(e) => tracing::error!("Failed to delete accounts: {:?}", e),
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/login/deletion",
+    tag = "Users",
+    description = "Cancel the scheduled deletion of your account, which lists your games again",
+    responses(
+        (status = OK, description = "Ok"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn cancel_deletion(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+) -> Result<TypedHeader<HxRefresh>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let cancelled = diesel::update(users::table)
+        .filter(users::id.eq(user.id))
+        .filter(users::delete_after.is_not_null())
+        .set(users::delete_after.eq(None::<DateTime<Utc>>))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to cancel account deletion")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if cancelled == 0 {
+        return Err(eyre!("Your account isn't scheduled for deletion"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(TypedHeader(HxRefresh(true)))
+}
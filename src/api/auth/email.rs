@@ -0,0 +1,503 @@
+//! Email verification and password resets
+//!
+//! Both work by mailing a link with a single-use token. Like session
+//! tokens only their hash is stored, and they are deleted once used.
+use axum::{
+    extract::{Query, State},
+    http::{HeaderValue, StatusCode},
+    response::Redirect,
+};
+use axum_extra::TypedHeader;
+use chrono::{Duration, Utc};
+use color_eyre::eyre::{Context, OptionExt, eyre};
+use diesel::{
+    ExpressionMethods, NullableExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
+    dsl::{exists, now},
+    prelude::Insertable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_derive_enum::DbEnum;
+use lettre::message::Mailbox;
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        User,
+        cache::CredentialCache,
+        events::{self, AuthEventKind, ClientInfo},
+        password::PasswordHasher,
+        pool::{self, DatabaseConnection},
+        reauth::{self, Reauthentication},
+        role::Role,
+        scope::{self, RequireScope},
+        session::{self, generate_token, hash_token},
+        throttle::{LoginAttempt, ThrottleConfig},
+    },
+    error::{self, Error, WithStatusCode},
+    htmx::{HxRedirect, HxRefresh},
+    json_or_form::JsonOrForm,
+    mail::Mailer,
+    schema::{email_tokens, sql_types, users},
+};
+
+const VERIFY_TTL_HOURS: i64 = 24;
+const RESET_TTL_MINUTES: i64 = 60;
+
+#[derive(DbEnum, Debug, Clone, Copy, PartialEq)]
+#[db_enum(existing_type_path = "sql_types::EmailTokenPurpose")]
+pub enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::email_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableEmailToken<'a> {
+    user_id: i32,
+    purpose: EmailTokenPurpose,
+    token_hash: &'a [u8],
+    email: Option<&'a str>,
+    expires_at: chrono::DateTime<Utc>,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "emails/verify_email.stpl")]
+#[template(rm_whitespace = true)]
+struct VerifyEmailTemplate<'a> {
+    username: &'a str,
+    link: String,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "emails/reset_password.stpl")]
+#[template(rm_whitespace = true)]
+struct ResetPasswordTemplate<'a> {
+    username: &'a str,
+    link: String,
+}
+
+/// Parses an address people typed in, rejecting it with `400 Bad Request`
+pub fn parse_address(email: &str) -> error::Result<lettre::Address> {
+    email
+        .trim()
+        .parse()
+        .wrap_err("That doesn't look like an email address")
+        .with_status_code(StatusCode::BAD_REQUEST)
+}
+
+async fn create_token(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    purpose: EmailTokenPurpose,
+    email: Option<&str>,
+    ttl: Duration,
+) -> error::Result<String> {
+    let token = generate_token();
+
+    // Only the latest link of each kind works
+    diesel::delete(email_tokens::table)
+        .filter(email_tokens::user_id.eq(user_id))
+        .filter(email_tokens::purpose.eq(purpose))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to remove old email tokens")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::insert_into(email_tokens::table)
+        .values(InsertableEmailToken {
+            user_id,
+            purpose,
+            token_hash: &hash_token(&token),
+            email,
+            expires_at: Utc::now() + ttl,
+        })
+        .execute(conn)
+        .await
+        .wrap_err("Failed to create email token")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(token)
+}
+
+/// Whether `user_id` has an unexpired token for `purpose`
+async fn has_token(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    purpose: EmailTokenPurpose,
+) -> error::Result<bool> {
+    diesel::select(exists(
+        email_tokens::table
+            .filter(email_tokens::user_id.eq(user_id))
+            .filter(email_tokens::purpose.eq(purpose))
+            .filter(email_tokens::expires_at.gt(now)),
+    ))
+    .get_result(conn)
+    .await
+    .wrap_err("Failed to get email tokens")
+    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Uses up a token, returning the user and address it was issued for
+async fn consume_token(
+    conn: &mut AsyncPgConnection,
+    purpose: EmailTokenPurpose,
+    token: &str,
+) -> error::Result<Option<(i32, Option<String>)>> {
+    diesel::delete(email_tokens::table)
+        .filter(email_tokens::token_hash.eq(hash_token(token).as_slice()))
+        .filter(email_tokens::purpose.eq(purpose))
+        .filter(email_tokens::expires_at.gt(now))
+        .returning((email_tokens::user_id, email_tokens::email))
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up email token")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Mails `user` a link confirming they own `email`
+pub async fn send_verification(
+    conn: &mut AsyncPgConnection,
+    mailer: &Mailer,
+    user: &User,
+    email: &lettre::Address,
+) -> error::Result<()> {
+    let token = create_token(
+        conn,
+        user.id,
+        EmailTokenPurpose::VerifyEmail,
+        Some(email.as_ref()),
+        Duration::hours(VERIFY_TTL_HOURS),
+    )
+    .await?;
+
+    mailer.send(
+        Mailbox::new(Some(user.username.clone()), email.clone()),
+        "Confirm your email address",
+        VerifyEmailTemplate {
+            username: &user.username,
+            link: mailer.link(&format!("/auth/verify-email?token={}", token)),
+        },
+    )
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct TokenQuery {
+    token: String,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct UpdateEmail {
+    email: String,
+    #[serde(flatten)]
+    reauth: Reauthentication,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct PasswordResetRequest {
+    /// Username or email address of the account
+    account: String,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct PasswordReset {
+    /// Token from the reset link
+    token: String,
+    /// The new password
+    password: String,
+}
+
+impl Placeholder for UpdateEmail {
+    fn placeholder() -> Self {
+        Self {
+            email: "johndoe@example.com".to_owned(),
+            reauth: Reauthentication::placeholder(),
+        }
+    }
+}
+
+impl Placeholder for PasswordResetRequest {
+    fn placeholder() -> Self {
+        Self {
+            account: "johndoe".to_owned(),
+        }
+    }
+}
+
+impl Placeholder for PasswordReset {
+    fn placeholder() -> Self {
+        Self {
+            token: "q0Xc2m3Jc9Jw1r0kq7wz8d5m0Jb6mC8d4Xn1Zr2yT3s".to_owned(),
+            password: "evenMoreSecurePassword5678".to_owned(),
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/auth/email",
+    tag = "Users",
+    description = "Set the email address of your account. It stays unverified until the link mailed to it is opened. Needs your current password or a code from your authenticator app.",
+    request_body(content(
+        (UpdateEmail, example = UpdateEmail::placeholder),
+        (UpdateEmail = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, jar, _scope, mailer, hasher, update))]
+pub async fn update_email(
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(mailer): State<Mailer>,
+    State(hasher): State<PasswordHasher>,
+    State(throttle_config): State<ThrottleConfig>,
+    client: ClientInfo,
+    JsonOrForm(update): JsonOrForm<UpdateEmail>,
+) -> Result<TypedHeader<HxRefresh>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    // Otherwise a stolen session could reset the password through it
+    reauth::require(
+        &mut conn,
+        &hasher,
+        &throttle_config,
+        &client,
+        &jar,
+        &user,
+        &update.reauth,
+    )
+    .await?;
+
+    let email = parse_address(&update.email)?;
+
+    diesel::update(users::table)
+        .filter(users::id.eq(user.id))
+        .set((
+            users::email.eq(email.as_ref()),
+            users::email_verified_at.eq(None::<chrono::DateTime<Utc>>),
+        ))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update email address, it may already be in use")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    send_verification(&mut conn, &mailer, &user, &email).await?;
+
+    Ok(TypedHeader(HxRefresh(true)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/verify-email",
+    tag = "Users",
+    description = "Verify an email address. This is the link that gets mailed out.",
+    responses(
+        (status = SEE_OTHER, description = "Verified, redirects to the home page"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("token" = String, Query, description = "Token from the mailed link"))
+)]
+#[instrument(skip_all)]
+pub async fn verify_email(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    Query(query): Query<TokenQuery>,
+) -> Result<Redirect, error::Error> {
+    let (user_id, email) = consume_token(&mut conn, EmailTokenPurpose::VerifyEmail, &query.token)
+        .await?
+        .ok_or_eyre("This link is invalid or has expired")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    // The link may well be opened in a browser that isn't logged in
+    pool::set_current_user(&mut conn, user_id, Role::User).await?;
+    let verified = diesel::update(users::table)
+        .filter(users::id.eq(user_id))
+        .filter(users::email.eq(email))
+        .set(users::email_verified_at.eq(now))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to verify email address")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if verified == 0 {
+        return Err(eyre!(
+            "The email address of this account has changed since this link was sent"
+        ))
+        .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Redirect::to("/"))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/password-reset",
+    tag = "Users",
+    description = "Mail a password reset link to the verified address of an account. \
+        Answers the same whether or not the account exists. No new link is sent while the \
+        last one still works.",
+    request_body(content(
+        (PasswordResetRequest, example = PasswordResetRequest::placeholder),
+        (PasswordResetRequest = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = ACCEPTED, description = "Accepted"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+)]
+#[instrument(skip(conn, mailer, throttle_config))]
+pub async fn request_password_reset(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    State(mailer): State<Mailer>,
+    State(throttle_config): State<ThrottleConfig>,
+    client: ClientInfo,
+    JsonOrForm(request): JsonOrForm<PasswordResetRequest>,
+) -> Result<StatusCode, error::Error> {
+    let account = request.account.trim();
+
+    // Every request counts, so nobody can flood an inbox with links
+    let attempt = LoginAttempt::password_reset(&throttle_config, client.ip, account);
+    attempt.check(&mut conn).await?;
+    attempt.failed(&mut conn).await?;
+
+    // Only one column is looked at, so a username that happens to be
+    // the address of another account can't pick the wrong one
+    let query = users::table
+        .filter(users::email_verified_at.is_not_null())
+        .select((User::as_select(), users::email.assume_not_null()))
+        .into_boxed();
+    let query = if account.contains('@') {
+        query.filter(users::email.eq(account))
+    } else {
+        query.filter(users::username.eq(account))
+    };
+    let found = query
+        .get_result::<(User, String)>(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // A link that still works isn't replaced, it's in the inbox already
+    if let Some((user, email)) = found
+        && !has_token(&mut conn, user.id, EmailTokenPurpose::ResetPassword).await?
+    {
+        let token = create_token(
+            &mut conn,
+            user.id,
+            EmailTokenPurpose::ResetPassword,
+            None,
+            Duration::minutes(RESET_TTL_MINUTES),
+        )
+        .await?;
+
+        mailer.send(
+            Mailbox::new(Some(user.username.clone()), parse_address(&email)?),
+            "Reset your password",
+            ResetPasswordTemplate {
+                username: &user.username,
+                link: mailer.link(&format!("/reset-password.html?token={}", token)),
+            },
+        )?;
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/password-reset/confirm",
+    tag = "Users",
+    description = "Set a new password with a token from a reset link. This logs out every session of the account.",
+    request_body(content(
+        (PasswordReset, example = PasswordReset::placeholder),
+        (PasswordReset = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+)]
+#[instrument(skip_all)]
+pub async fn reset_password(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    State(hasher): State<PasswordHasher>,
+    State(credential_cache): State<CredentialCache>,
+    client: ClientInfo,
+    JsonOrForm(reset): JsonOrForm<PasswordReset>,
+) -> Result<TypedHeader<HxRedirect>, error::Error> {
+    let (user_id, _) = consume_token(&mut conn, EmailTokenPurpose::ResetPassword, &reset.token)
+        .await?
+        .ok_or_eyre("This reset link is invalid or has expired")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    let password = hasher
+        .hash(reset.password)
+        .await
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    pool::set_current_user(&mut conn, user_id, Role::User).await?;
+    diesel::update(users::table)
+        .filter(users::id.eq(user_id))
+        .set(users::password.eq(password))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update password")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+    credential_cache.invalidate_user(user_id);
+    events::record(&mut conn, &client, AuthEventKind::PasswordChanged, user_id).await?;
+
+    // Whoever else knew the old password shouldn't stay logged in
+    session::revoke_others(&mut conn, user_id, None).await?;
+
+    Ok(TypedHeader(HxRedirect(HeaderValue::from_static("/"))))
+}
@@ -0,0 +1,414 @@
+//! Security audit log
+//!
+//! Signups, logins, password changes, logouts, new access tokens and
+//! account deletions are appended to `auth_events` along with the
+//! address and user agent they came from. Anyone may insert but nobody
+//! may change or delete an event, so the log only grows. Users can
+//! read their own history and admins can page through everyone's.
+//! Events are only deleted along with their account.
+use std::{fmt::Write, net::IpAddr};
+
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{StatusCode, header},
+};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, eyre};
+use diesel::{
+    ExpressionMethods, NullableExpressionMethods, QueryDsl, Queryable, prelude::Insertable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_derive_enum::DbEnum;
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+        throttle::ClientIp,
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    openapi_template,
+    schema::{auth_events, sql_types, users},
+    state::AppState,
+};
+
+/// How many events a page has unless asked for fewer or more
+const PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(DbEnum, ToSchema, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[db_enum(existing_type_path = "sql_types::AuthEventKind")]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEventKind {
+    Signup,
+    LoginSucceeded,
+    LoginFailed,
+    PasswordChanged,
+    Logout,
+    /// A personal access token was created
+    TokenCreated,
+    /// The account was scheduled for deletion
+    AccountDeleted,
+}
+
+impl AuthEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthEventKind::Signup => "signup",
+            AuthEventKind::LoginSucceeded => "login_succeeded",
+            AuthEventKind::LoginFailed => "login_failed",
+            AuthEventKind::PasswordChanged => "password_changed",
+            AuthEventKind::Logout => "logout",
+            AuthEventKind::TokenCreated => "token_created",
+            AuthEventKind::AccountDeleted => "account_deleted",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AuthEventKind::Signup => "Signed up",
+            AuthEventKind::LoginSucceeded => "Logged in",
+            AuthEventKind::LoginFailed => "Failed login",
+            AuthEventKind::PasswordChanged => "Changed password",
+            AuthEventKind::Logout => "Logged out",
+            AuthEventKind::TokenCreated => "Created access token",
+            AuthEventKind::AccountDeleted => "Deleted account",
+        }
+    }
+}
+
+/// Where a request came from, as recorded with its events
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = error::Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+
+        Ok(Self {
+            ip,
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned),
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::auth_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableAuthEvent<'a> {
+    user_id: Option<i32>,
+    username: Option<&'a str>,
+    kind: AuthEventKind,
+    ip: String,
+    user_agent: Option<&'a str>,
+}
+
+async fn insert(conn: &mut AsyncPgConnection, event: InsertableAuthEvent<'_>) -> error::Result<()> {
+    diesel::insert_into(auth_events::table)
+        .values(event)
+        .execute(conn)
+        .await
+        .wrap_err("Failed to record auth event")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Records that `kind` happened to `user_id`
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    client: &ClientInfo,
+    kind: AuthEventKind,
+    user_id: i32,
+) -> error::Result<()> {
+    insert(
+        conn,
+        InsertableAuthEvent {
+            user_id: Some(user_id),
+            username: None,
+            kind,
+            ip: client.ip.to_string(),
+            user_agent: client.user_agent.as_deref(),
+        },
+    )
+    .await
+}
+
+/// Records a failed login as `username`, which belongs to `user_id`
+/// if such an account exists
+pub async fn record_failed_login(
+    conn: &mut AsyncPgConnection,
+    client: &ClientInfo,
+    user_id: Option<i32>,
+    username: &str,
+) -> error::Result<()> {
+    insert(
+        conn,
+        InsertableAuthEvent {
+            user_id,
+            username: user_id.is_none().then_some(username),
+            kind: AuthEventKind::LoginFailed,
+            ip: client.ip.to_string(),
+            user_agent: client.user_agent.as_deref(),
+        },
+    )
+    .await
+}
+
+#[derive(Queryable, Debug)]
+struct DatabaseAuthEvent {
+    id: i32,
+    user_id: Option<i32>,
+    account_username: Option<String>,
+    attempted_username: Option<String>,
+    kind: AuthEventKind,
+    ip: String,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct AuthEvent {
+    id: i32,
+    user_id: Option<i32>,
+    /// Username of the account, or the one a failed login to an
+    /// account that doesn't exist was attempted with
+    username: Option<String>,
+    kind: AuthEventKind,
+    ip: String,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<DatabaseAuthEvent> for AuthEvent {
+    fn from(event: DatabaseAuthEvent) -> Self {
+        Self {
+            id: event.id,
+            user_id: event.user_id,
+            username: event.account_username.or(event.attempted_username),
+            kind: event.kind,
+            ip: event.ip,
+            user_agent: event.user_agent,
+            created_at: event.created_at,
+        }
+    }
+}
+
+impl Placeholder for AuthEvent {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            user_id: Some(1),
+            username: Some("johndoe".to_owned()),
+            kind: AuthEventKind::LoginSucceeded,
+            ip: "203.0.113.7".to_owned(),
+            user_agent: Some(
+                "Mozilla/5.0 (X11; Linux x86_64; rv:147.0) Gecko/20100101 Firefox/147.0".to_owned(),
+            ),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Newest events first
+#[derive(ToSchema, Serialize, Debug)]
+pub struct AuthEventPage {
+    events: Vec<AuthEvent>,
+    /// Pass this as `before` to get the next page, `null` on the last one
+    next_before: Option<i32>,
+}
+
+impl Placeholder for AuthEventPage {
+    fn placeholder() -> Self {
+        Self {
+            events: vec![AuthEvent::placeholder()],
+            next_before: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthEventsQuery {
+    before: Option<i32>,
+    limit: Option<i64>,
+    user_id: Option<i32>,
+    kind: Option<AuthEventKind>,
+}
+
+impl AuthEventsQuery {
+    /// Link to the page after the one ending at `before`, with the same filters
+    fn next_page(&self, path: &str, before: i32) -> String {
+        let mut url = format!("{}?before={}", path, before);
+        if let Some(limit) = self.limit {
+            let _ = write!(url, "&limit={}", limit);
+        }
+        if let Some(user_id) = self.user_id {
+            let _ = write!(url, "&user_id={}", user_id);
+        }
+        if let Some(kind) = self.kind {
+            let _ = write!(url, "&kind={}", kind.as_str());
+        }
+        url
+    }
+}
+
+/// Loads a page of the events matching `query` that the connection's
+/// user may see, only those of `user_id` if given
+pub async fn load(
+    conn: &mut AsyncPgConnection,
+    query: &AuthEventsQuery,
+    user_id: Option<i32>,
+) -> error::Result<AuthEventPage> {
+    let limit = query.limit.unwrap_or(PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut events = auth_events::table
+        .left_join(users::table)
+        .select((
+            auth_events::id,
+            auth_events::user_id,
+            users::username.nullable(),
+            auth_events::username,
+            auth_events::kind,
+            auth_events::ip,
+            auth_events::user_agent,
+            auth_events::created_at,
+        ))
+        .order(auth_events::id.desc())
+        // One extra to know whether there is another page
+        .limit(limit + 1)
+        .into_boxed();
+
+    if let Some(before) = query.before {
+        events = events.filter(auth_events::id.lt(before));
+    }
+    if let Some(user_id) = user_id.or(query.user_id) {
+        events = events.filter(auth_events::user_id.eq(user_id));
+    }
+    if let Some(kind) = query.kind {
+        events = events.filter(auth_events::kind.eq(kind));
+    }
+
+    let mut events = events
+        .load::<DatabaseAuthEvent>(conn)
+        .await
+        .wrap_err("Failed to get auth events")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let next_before = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events.last().map(|event| event.id)
+    } else {
+        None
+    };
+
+    Ok(AuthEventPage {
+        events: events.into_iter().map(AuthEvent::from).collect(),
+        next_before,
+    })
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "events.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct EventsTemplate {
+    page: AuthEventPage,
+    /// Show whose events they are, for admins looking at everyone's
+    all_users: bool,
+    next_page: Option<String>,
+}
+
+impl EventsTemplate {
+    /// Renders `page` of `query`, linking to the next page under `path`
+    pub fn new(page: AuthEventPage, query: &AuthEventsQuery, path: &str, all_users: bool) -> Self {
+        Self {
+            next_page: page.next_before.map(|before| query.next_page(path, before)),
+            page,
+            all_users,
+        }
+    }
+}
+
+impl Placeholder for EventsTemplate {
+    fn placeholder() -> Self {
+        Self {
+            page: AuthEventPage::placeholder(),
+            all_users: false,
+            next_page: None,
+        }
+    }
+}
+
+openapi_template!(EventsTemplate, page);
+
+#[utoipa::path(
+    get,
+    path = "/auth/events",
+    tag = "Users",
+    description = "Page through the security history of your account, newest first",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(EventsTemplate) = "text/html", example = EventsTemplate::render_placeholder),
+                (AuthEventPage, example = AuthEventPage::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("before" = Option<i32>, Query, description = "Only events older than this event ID, from `next_before`"),
+        ("limit" = Option<i64>, Query, description = "Events per page, 50 by default and at most 200"),
+        ("kind" = Option<AuthEventKind>, Query, description = "Only events of this kind")
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_events(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Query(query): Query<AuthEventsQuery>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<EventsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    // Admins may see everyone's, but this is only about their own
+    let page = load(&mut conn, &query, Some(user.id)).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        EventsTemplate::new(page, &query, "/auth/events", false),
+    ))
+}
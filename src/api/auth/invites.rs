@@ -0,0 +1,516 @@
+//! Invite codes
+//!
+//! With `--invite-only` set, signing up needs a code from an existing
+//! member. Every member may have `--invite-quota` codes that aren't
+//! revoked, admins as many as they like. Codes are single use and
+//! remember who created them and who signed up with them. A code can
+//! be revoked until it's used, which also gives it back to the quota.
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
+use clap::Args;
+use color_eyre::eyre::{Context, OptionExt, eyre};
+use diesel::{
+    ExpressionMethods, JoinOnDsl, NullableExpressionMethods, OptionalExtension, QueryDsl,
+    Queryable, dsl::now,
+};
+use diesel_async::{
+    AsyncConnection, AsyncPgConnection, RunQueryDsl, scoped_futures::ScopedFutureExt,
+};
+use rand::Rng;
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        User,
+        pool::DatabaseConnection,
+        role::Role,
+        scope::{self, RequireScope},
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    openapi_template,
+    schema::{invites, users},
+};
+
+/// Leaves out characters that are easily mistaken for each other
+const CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const CODE_GROUPS: usize = 3;
+const CODE_GROUP_LEN: usize = 4;
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct InviteConfig {
+    /// Only allow signing up with an invite code from a member
+    #[clap(long, env = "INVITE_ONLY")]
+    #[serde(default)]
+    pub invite_only: bool,
+    /// How many unrevoked invite codes a member may have. Admins
+    /// aren't limited.
+    #[clap(long, env = "INVITE_QUOTA")]
+    #[serde(default = "default_invite_quota")]
+    pub invite_quota: u32,
+}
+
+#[inline]
+const fn default_invite_quota() -> u32 {
+    5
+}
+
+impl Default for InviteConfig {
+    fn default() -> Self {
+        Self {
+            invite_only: false,
+            invite_quota: default_invite_quota(),
+        }
+    }
+}
+
+#[derive(Queryable, ToSchema, Serialize, Debug)]
+pub struct Invite {
+    id: i32,
+    code: String,
+    /// User ID who created the code
+    created_by: i32,
+    created_by_username: String,
+    /// User ID who signed up with the code, if anyone did and their
+    /// account still exists
+    used_by: Option<i32>,
+    used_by_username: Option<String>,
+    created_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Invites {
+    invites: Vec<Invite>,
+    /// How many more codes you may create, left out if there's no limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining: Option<u32>,
+}
+
+impl Placeholder for Invite {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            code: "k7mq-x3pd-w9ht".to_owned(),
+            created_by: 1,
+            created_by_username: "johndoe".to_owned(),
+            used_by: Some(2),
+            used_by_username: Some("janedoe".to_owned()),
+            created_at: Utc::now(),
+            used_at: Some(Utc::now()),
+            revoked_at: None,
+        }
+    }
+}
+
+impl Placeholder for Invites {
+    fn placeholder() -> Self {
+        Self {
+            invites: vec![Invite::placeholder()],
+            remaining: Some(4),
+        }
+    }
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::rng();
+    (0..CODE_GROUPS)
+        .map(|_| {
+            (0..CODE_GROUP_LEN)
+                .map(|_| CODE_ALPHABET[rng.random_range(0..CODE_ALPHABET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Brings a typed code into the form it's stored in, so it matches
+/// without the dashes or in another case
+fn normalize_code(code: &str) -> String {
+    let chars = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    chars
+        .chunks(CODE_GROUP_LEN)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Marks an unused code as used, so nobody else can sign up with it.
+/// Returns the ID of the invite to `redeem` or `release` it with.
+pub async fn claim(conn: &mut AsyncPgConnection, code: &str) -> error::Result<i32> {
+    diesel::update(invites::table)
+        .filter(invites::code.eq(normalize_code(code)))
+        .filter(invites::used_at.is_null())
+        .filter(invites::revoked_at.is_null())
+        .set(invites::used_at.eq(now))
+        .returning(invites::id)
+        .get_result::<i32>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to claim invite code")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_eyre("This invite code is invalid or was already used")
+        .with_status_code(StatusCode::FORBIDDEN)
+}
+
+/// Makes a claimed code usable again, when the signup failed after all
+pub async fn release(conn: &mut AsyncPgConnection, invite_id: i32) -> error::Result<()> {
+    diesel::update(invites::table)
+        .filter(invites::id.eq(invite_id))
+        .set(invites::used_at.eq(None::<DateTime<Utc>>))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to release invite code")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Records that `user_id` signed up with a claimed code
+pub async fn redeem(
+    conn: &mut AsyncPgConnection,
+    invite_id: i32,
+    user_id: i32,
+) -> error::Result<()> {
+    diesel::update(invites::table)
+        .filter(invites::id.eq(invite_id))
+        .set(invites::used_by.eq(user_id))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to redeem invite code")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Lists the codes created by `created_by`, or everyone's if `None`,
+/// newest first
+pub async fn load(
+    conn: &mut AsyncPgConnection,
+    created_by: Option<i32>,
+) -> error::Result<Vec<Invite>> {
+    let (inviters, invitees) = diesel::alias!(users as inviters, users as invitees);
+
+    let mut query = invites::table
+        .inner_join(inviters.on(invites::created_by.eq(inviters.field(users::id))))
+        .left_join(invitees.on(invites::used_by.eq(invitees.field(users::id).nullable())))
+        .select((
+            invites::id,
+            invites::code,
+            invites::created_by,
+            inviters.field(users::username),
+            invites::used_by,
+            invitees.field(users::username).nullable(),
+            invites::created_at,
+            invites::used_at,
+            invites::revoked_at,
+        ))
+        .order(invites::id.desc())
+        .into_boxed();
+    if let Some(created_by) = created_by {
+        query = query.filter(invites::created_by.eq(created_by));
+    }
+
+    query
+        .load(conn)
+        .await
+        .wrap_err("Failed to get invite codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// How many more codes `user` may create, `None` if there's no limit
+async fn remaining(
+    conn: &mut AsyncPgConnection,
+    config: &InviteConfig,
+    user: &User,
+) -> error::Result<Option<u32>> {
+    if user.role >= Role::Admin {
+        return Ok(None);
+    }
+
+    let created = invites::table
+        .filter(invites::created_by.eq(user.id))
+        .filter(invites::revoked_at.is_null())
+        .count()
+        .get_result::<i64>(conn)
+        .await
+        .wrap_err("Failed to count invite codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Some(config.invite_quota.saturating_sub(
+        u32::try_from(created).unwrap_or(u32::MAX),
+    )))
+}
+
+/// Creates a code for `user` if their quota allows, with their row
+/// locked so two requests at once can't both take the last one
+async fn create(
+    conn: &mut AsyncPgConnection,
+    config: &InviteConfig,
+    user: &User,
+) -> error::Result<()> {
+    conn.transaction::<_, error::Error, _>(|conn| {
+        async move {
+            users::table
+                .filter(users::id.eq(user.id))
+                .select(users::id)
+                .for_update()
+                .execute(conn)
+                .await
+                .wrap_err("Failed to lock user")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            if remaining(conn, config, user).await? == Some(0) {
+                return Err(eyre!(
+                    "You already have {} invite codes, revoke an unused one first",
+                    config.invite_quota
+                ))
+                .with_status_code(StatusCode::FORBIDDEN);
+            }
+
+            diesel::insert_into(invites::table)
+                .values((
+                    invites::code.eq(generate_code()),
+                    invites::created_by.eq(user.id),
+                ))
+                .execute(conn)
+                .await
+                .wrap_err("Failed to create invite code")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Revokes an unused code, only among the ones of `created_by` if given
+pub async fn revoke(
+    conn: &mut AsyncPgConnection,
+    invite_id: i32,
+    created_by: Option<i32>,
+) -> error::Result<()> {
+    let mut query = diesel::update(invites::table)
+        .filter(invites::id.eq(invite_id))
+        .filter(invites::used_at.is_null())
+        .filter(invites::revoked_at.is_null())
+        .into_boxed();
+    if let Some(created_by) = created_by {
+        query = query.filter(invites::created_by.eq(created_by));
+    }
+
+    let revoked = query
+        .set(invites::revoked_at.eq(now))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to revoke invite code")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if revoked == 0 {
+        return Err(eyre!(
+            "That invite code doesn't exist or was already used or revoked"
+        ))
+        .with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "invites.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct InvitesTemplate {
+    invites: Invites,
+    /// Show who created the codes, for admins looking at everyone's
+    all_users: bool,
+}
+
+impl InvitesTemplate {
+    pub fn new(invites: Vec<Invite>, remaining: Option<u32>, all_users: bool) -> Self {
+        Self {
+            invites: Invites { invites, remaining },
+            all_users,
+        }
+    }
+}
+
+impl Placeholder for InvitesTemplate {
+    fn placeholder() -> Self {
+        Self {
+            invites: Invites::placeholder(),
+            all_users: false,
+        }
+    }
+}
+
+openapi_template!(InvitesTemplate, invites);
+
+/// The invite codes of `user` along with what's left of their quota
+async fn own_invites(
+    conn: &mut AsyncPgConnection,
+    config: &InviteConfig,
+    user: &User,
+) -> error::Result<InvitesTemplate> {
+    Ok(InvitesTemplate::new(
+        load(conn, Some(user.id)).await?,
+        remaining(conn, config, user).await?,
+        false,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/invites",
+    tag = "Users",
+    description = "List the invite codes you created and how many more you may create",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(InvitesTemplate) = "text/html", example = InvitesTemplate::render_placeholder),
+                (Invites, example = Invites::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_invites(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(config): State<InviteConfig>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<InvitesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        own_invites(&mut conn, &config, &user).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/invites",
+    tag = "Users",
+    description = "Create an invite code. Members may have a limited number of unrevoked codes, admins as many as they like.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(InvitesTemplate) = "text/html", example = InvitesTemplate::render_placeholder),
+                (Invites, example = Invites::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn add_invite(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(config): State<InviteConfig>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<InvitesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    create(&mut conn, &config, &user).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        own_invites(&mut conn, &config, &user).await?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/invites/{invite_id}",
+    tag = "Users",
+    description = "Revoke one of your invite codes that hasn't been used yet",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(InvitesTemplate) = "text/html", example = InvitesTemplate::render_placeholder),
+                (Invites, example = Invites::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("invite_id" = i32, Path, description = "Invite ID to revoke")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_invite(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(config): State<InviteConfig>,
+    Path(invite_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<InvitesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    revoke(&mut conn, invite_id, Some(user.id)).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        own_invites(&mut conn, &config, &user).await?,
+    ))
+}
@@ -0,0 +1,380 @@
+//! Bearer access tokens
+//!
+//! `POST /auth/token` exchanges a password or a refresh token for a
+//! short-lived HS256 JWT. Refresh tokens are ordinary sessions, so
+//! they show up in (and can be revoked from) the sessions list.
+//!
+//! Verifying an access token doesn't touch the database, so one that
+//! was issued stays valid until it expires, even if the account is
+//! scheduled for deletion or deleted in the meantime. That is why they
+//! only last minutes: no new ones are issued for such accounts, and
+//! deleting one revokes its sessions and with them the refresh tokens.
+//!
+//! Every key carries a `kid`. The first configured key signs new
+//! tokens and the rest are only used to verify, so a key can be
+//! rotated out by prepending its replacement and dropping it once
+//! the tokens it signed have expired.
+use std::{collections::HashMap, fmt::Debug, str::FromStr, sync::Arc};
+
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::{Duration, Utc};
+use clap::Args;
+use color_eyre::eyre::{self, Context, OptionExt, eyre};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        DatabaseUser, User, deletion,
+        events::{self, AuthEventKind, ClientInfo},
+        password::PasswordHasher,
+        pool::DatabaseConnection,
+        role::Role,
+        session::{self, SessionConfig},
+        throttle::{LoginAttempt, ThrottleConfig},
+        totp,
+    },
+    error::{self, Error, WithStatusCode},
+    json_or_form::JsonOrForm,
+};
+
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct SigningKey {
+    kid: String,
+    secret: String,
+}
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kid, secret) = s
+            .split_once('=')
+            .ok_or_eyre("Signing keys must be given as `kid=secret`")?;
+        if kid.is_empty() || secret.is_empty() {
+            return Err(eyre!("Signing key id and secret can't be empty"));
+        }
+
+        Ok(Self {
+            kid: kid.to_owned(),
+            secret: secret.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for SigningKey {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct JwtConfig {
+    /// Keys for signing access tokens, as `kid=secret`. The first one
+    /// signs new tokens, the others are only accepted when verifying.
+    #[clap(long = "jwt-key", env = "JWT_KEYS", value_delimiter = ',')]
+    #[serde(default)]
+    pub jwt_keys: Vec<SigningKey>,
+    /// Audience access tokens are issued for
+    #[clap(long, env = "JWT_AUDIENCE")]
+    #[serde(default = "default_audience")]
+    pub jwt_audience: String,
+    /// How many minutes an access token stays valid
+    #[clap(long, env = "ACCESS_TOKEN_TTL_MINUTES")]
+    #[serde(default = "default_access_token_ttl_minutes")]
+    pub access_token_ttl_minutes: u32,
+}
+
+#[inline]
+fn default_audience() -> String {
+    env!("CARGO_PKG_NAME").to_owned()
+}
+
+#[inline]
+const fn default_access_token_ttl_minutes() -> u32 {
+    15
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            jwt_keys: Vec::new(),
+            jwt_audience: default_audience(),
+            access_token_ttl_minutes: default_access_token_ttl_minutes(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    name: String,
+    /// Changes to the role only take effect once the token is refreshed
+    #[serde(default)]
+    role: Role,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+struct Keys {
+    signing_kid: String,
+    encoding: EncodingKey,
+    decoding: HashMap<String, DecodingKey>,
+    validation: Validation,
+    audience: String,
+    ttl: Duration,
+}
+
+#[derive(Clone)]
+pub struct JwtKeys(Arc<Keys>);
+
+impl JwtKeys {
+    pub fn new(config: &JwtConfig) -> Self {
+        let mut keys = config.jwt_keys.clone();
+        if keys.is_empty() {
+            tracing::warn!(
+                "No JWT signing keys configured, using a random one. Access tokens won't survive a restart"
+            );
+            keys.push(SigningKey {
+                kid: "ephemeral".to_owned(),
+                secret: base64::Engine::encode(
+                    &base64::prelude::BASE64_STANDARD,
+                    rand::random::<[u8; 32]>(),
+                ),
+            });
+        }
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[&config.jwt_audience]);
+        validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+
+        Self(Arc::new(Keys {
+            signing_kid: keys[0].kid.clone(),
+            encoding: EncodingKey::from_secret(keys[0].secret.as_bytes()),
+            decoding: keys
+                .iter()
+                .map(|key| {
+                    (
+                        key.kid.clone(),
+                        DecodingKey::from_secret(key.secret.as_bytes()),
+                    )
+                })
+                .collect(),
+            validation,
+            audience: config.jwt_audience.clone(),
+            ttl: Duration::minutes(config.access_token_ttl_minutes.into()),
+        }))
+    }
+
+    fn issue(&self, user: &User) -> eyre::Result<String> {
+        let now = Utc::now();
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(self.0.signing_kid.clone());
+
+        jsonwebtoken::encode(
+            &header,
+            &Claims {
+                sub: user.id.to_string(),
+                name: user.username.clone(),
+                role: user.role,
+                aud: self.0.audience.clone(),
+                iat: now.timestamp(),
+                exp: (now + self.0.ttl).timestamp(),
+            },
+            &self.0.encoding,
+        )
+        .wrap_err("Failed to sign access token")
+    }
+
+    /// Checks the signature, expiry and audience of an access token
+    pub fn verify(&self, token: &str) -> eyre::Result<User> {
+        let header = jsonwebtoken::decode_header(token).wrap_err("Malformed access token")?;
+        let kid = header.kid.ok_or_eyre("Access token has no key id")?;
+        let key = self
+            .0
+            .decoding
+            .get(&kid)
+            .ok_or_else(|| eyre!("Access token was signed with unknown key `{}`", kid))?;
+
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &self.0.validation)
+            .wrap_err("Invalid access token")?
+            .claims;
+
+        Ok(User {
+            id: claims
+                .sub
+                .parse()
+                .wrap_err("Access token subject isn't a user id")?,
+            username: claims.name,
+            role: claims.role,
+        })
+    }
+}
+
+#[derive(ToSchema, Deserialize, Serialize)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum TokenRequest {
+    Password {
+        username: String,
+        password: String,
+        /// TOTP or recovery code, for accounts with two-factor authentication
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        otp: Option<String>,
+    },
+    RefreshToken {
+        refresh_token: String,
+    },
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    /// Seconds until the access token expires
+    expires_in: i64,
+    refresh_token: String,
+}
+
+impl Placeholder for TokenRequest {
+    fn placeholder() -> Self {
+        Self::Password {
+            username: String::from("johndoe"),
+            password: String::from("verySecurePassword1234"),
+            otp: None,
+        }
+    }
+}
+
+impl Placeholder for TokenResponse {
+    fn placeholder() -> Self {
+        Self {
+            access_token: "eyJhbGciOiJIUzI1NiIsImtpZCI6IjIwMjYtMTAifQ.e30.c2lnbmF0dXJl".to_owned(),
+            token_type: "Bearer",
+            expires_in: Duration::minutes(default_access_token_ttl_minutes().into()).num_seconds(),
+            refresh_token: "q0Xc2m3Jc9Jw1r0kq7wz8d5m0Jb6mC8d4Xn1Zr2yT3s".to_owned(),
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/token",
+    tag = "Users",
+    description = "Get a bearer access token, either with your password or a refresh token. \
+        Using a refresh token revokes it and returns a new one.",
+    request_body(content(
+        (TokenRequest, example = TokenRequest::placeholder),
+        (TokenRequest = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (TokenResponse, example = TokenResponse::placeholder),
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+)]
+#[instrument(skip_all)]
+pub async fn token(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    State(hasher): State<PasswordHasher>,
+    State(keys): State<JwtKeys>,
+    State(session_config): State<SessionConfig>,
+    State(throttle_config): State<ThrottleConfig>,
+    client: ClientInfo,
+    JsonOrForm(request): JsonOrForm<TokenRequest>,
+) -> Result<Json<TokenResponse>, error::Error> {
+    let user = match request {
+        TokenRequest::Password {
+            username,
+            password,
+            otp,
+        } => {
+            let attempt = LoginAttempt::new(&throttle_config, client.ip, &username);
+
+            let Some(user) = DatabaseUser::find_and_verify(
+                &mut conn, &hasher, &attempt, &client, &username, &password,
+            )
+            .await?
+            else {
+                return Err(eyre!("Invalid username or password"))
+                    .with_status_code(StatusCode::UNAUTHORIZED);
+            };
+
+            if totp::is_enabled(&mut conn, user.id).await? {
+                let Some(otp) = otp else {
+                    return Err(eyre!("This account needs a second factor code in `otp`"))
+                        .with_status_code(StatusCode::UNAUTHORIZED);
+                };
+                if !totp::verify(&mut conn, user.id, &otp).await? {
+                    attempt.failed(&mut conn).await?;
+                    events::record_failed_login(&mut conn, &client, Some(user.id), &username)
+                        .await?;
+                    return Err(eyre!("Invalid code")).with_status_code(StatusCode::UNAUTHORIZED);
+                }
+            }
+            attempt.succeeded(&mut conn).await?;
+            events::record(&mut conn, &client, AuthEventKind::LoginSucceeded, user.id).await?;
+
+            User {
+                id: user.id,
+                username: user.username,
+                role: user.role,
+            }
+        }
+        TokenRequest::RefreshToken { refresh_token } => session::redeem(&mut conn, &refresh_token)
+            .await?
+            .ok_or_eyre("Invalid or expired refresh token")
+            .with_status_code(StatusCode::UNAUTHORIZED)?,
+    };
+
+    // Like basic auth, only logging in with a session can cancel it
+    if deletion::scheduled(&mut conn, user.id).await?.is_some() {
+        return Err(eyre!(
+            "Your account is scheduled for deletion, log in to cancel it"
+        ))
+        .with_status_code(StatusCode::UNAUTHORIZED);
+    }
+
+    let refresh_token = session::create(
+        &mut conn,
+        &session_config,
+        user.id,
+        client.user_agent.as_deref(),
+    )
+    .await?;
+
+    Ok(Json(TokenResponse {
+        access_token: keys
+            .issue(&user)
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?,
+        token_type: "Bearer",
+        expires_in: keys.0.ttl.num_seconds(),
+        refresh_token,
+    }))
+}
@@ -0,0 +1,735 @@
+//! Logging in through OpenID Connect providers
+//!
+//! Providers are listed in `config.toml` as `[[oidc_providers]]` and
+//! discovered on startup. Logging in uses the authorization code flow
+//! with PKCE: `/auth/oidc/{provider}` redirects to the provider, which
+//! redirects back to `/auth/oidc/{provider}/callback`.
+//!
+//! An identity logs in the account it is linked to. Unknown identities
+//! get a new account, they are never matched to an existing one by
+//! email. Logged in users link more identities with `?link=true`, within
+//! a few minutes of logging in, as a stolen session mustn't be able to
+//! add a way in.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Redirect,
+};
+use axum_extra::{TypedHeader, extract::cookie::SameSite};
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{self, Context, OptionExt, eyre};
+use diesel::{
+    ExpressionMethods, HasQuery, OptionalExtension, QueryDsl,
+    dsl::{exists, now},
+    prelude::Insertable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use openidconnect::{
+    AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet,
+    EndpointNotSet, EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope,
+    core::{CoreClient, CoreProviderMetadata, CoreResponseType},
+    reqwest,
+};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        events::{self, AuthEventKind, ClientInfo},
+        invites::InviteConfig,
+        pool::DatabaseConnection,
+        reauth,
+        scope::{self, RequireScope},
+        session::{self, SessionConfig, hash_token},
+        totp,
+    },
+    cookies::Jar,
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    openapi_template,
+    schema::{oidc_logins, user_identities, users},
+};
+
+/// Holds the `state` of a login in progress, so a callback can only
+/// finish a login started in the same browser
+const STATE_COOKIE_NAME: &str = "oidc_state";
+
+/// How long someone has to log in at the provider
+const LOGIN_TTL_MINUTES: i64 = 10;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OidcProviderConfig {
+    /// Used in urls, e.g. `/auth/oidc/{id}`
+    pub id: String,
+    /// Shown on the login button
+    pub name: String,
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Leave out for public clients
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+type Client = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+struct Provider {
+    id: String,
+    name: String,
+    client: Client,
+}
+
+struct Inner {
+    providers: Vec<Provider>,
+    http: reqwest::Client,
+}
+
+#[derive(Clone)]
+pub struct OidcProviders(Arc<Inner>);
+
+impl OidcProviders {
+    /// Fetches the metadata of every configured provider. Callbacks
+    /// are sent to `public_url`.
+    pub async fn discover(configs: &[OidcProviderConfig], public_url: &str) -> eyre::Result<Self> {
+        let http = reqwest::ClientBuilder::new()
+            // Following redirects would open up SSRF
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .wrap_err("Failed to build HTTP client")?;
+
+        let mut providers = Vec::with_capacity(configs.len());
+        for config in configs {
+            let metadata = CoreProviderMetadata::discover_async(
+                IssuerUrl::new(config.issuer_url.clone())
+                    .wrap_err_with(|| format!("Invalid issuer url for `{}`", config.id))?,
+                &http,
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to discover OpenID provider `{}`", config.id))?;
+
+            let client = CoreClient::from_provider_metadata(
+                metadata,
+                ClientId::new(config.client_id.clone()),
+                config.client_secret.clone().map(ClientSecret::new),
+            )
+            .set_redirect_uri(
+                RedirectUrl::new(format!(
+                    "{}/auth/oidc/{}/callback",
+                    public_url.trim_end_matches('/'),
+                    config.id
+                ))
+                .wrap_err("Invalid public url")?,
+            );
+
+            providers.push(Provider {
+                id: config.id.clone(),
+                name: config.name.clone(),
+                client,
+            });
+        }
+
+        Ok(Self(Arc::new(Inner { providers, http })))
+    }
+
+    fn get(&self, id: &str) -> error::Result<&Provider> {
+        self.0
+            .providers
+            .iter()
+            .find(|provider| provider.id == id)
+            .ok_or_else(|| eyre!("There's no login provider called `{}`", id))
+            .with_status_code(StatusCode::NOT_FOUND)
+    }
+
+    /// The configured providers, for listing them on login buttons
+    pub fn links(&self) -> Vec<ProviderLink> {
+        self.0
+            .providers
+            .iter()
+            .map(|provider| ProviderLink {
+                id: provider.id.clone(),
+                name: provider.name.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct ProviderLink {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::oidc_logins)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableOidcLogin<'a> {
+    state_hash: &'a [u8],
+    provider: &'a str,
+    pkce_verifier: &'a str,
+    nonce: &'a str,
+    link_user_id: Option<i32>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(HasQuery, ToSchema, Serialize, Debug)]
+#[diesel(table_name = crate::schema::user_identities)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Identity {
+    id: i32,
+    provider: String,
+    email: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+}
+
+impl Placeholder for Identity {
+    fn placeholder() -> Self {
+        let now = Utc::now();
+        Self {
+            id: 1,
+            provider: "mock".to_owned(),
+            email: Some("johndoe@example.com".to_owned()),
+            created_at: now,
+            last_used_at: now,
+        }
+    }
+}
+
+fn set_state_cookie(jar: Jar, state: String) -> Jar {
+    let mut cookie = jar.build(STATE_COOKIE_NAME, state);
+    cookie.set_path("/auth/oidc");
+    // The provider redirects back with a top-level GET, which a
+    // configured `Strict` would leave the cookie out of
+    cookie.set_same_site(SameSite::Lax);
+    jar.add(cookie)
+}
+
+fn remove_state_cookie(jar: Jar) -> Jar {
+    let mut cookie = jar.removal(STATE_COOKIE_NAME);
+    cookie.set_path("/auth/oidc");
+    jar.remove(cookie)
+}
+
+/// Creates an account for a new identity. The username is taken from
+/// the provider, with a number appended if it's already taken.
+async fn create_user(
+    conn: &mut AsyncPgConnection,
+    provider: &Provider,
+    preferred_username: Option<&str>,
+    email: Option<&str>,
+) -> error::Result<i32> {
+    let base = preferred_username
+        .or_else(|| email.and_then(|email| email.split('@').next()))
+        .filter(|username| !username.is_empty())
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| format!("{}-user", provider.id));
+
+    // A verified address is only kept if no other account claims it
+    let email = match email {
+        Some(email) => {
+            let taken = diesel::select(exists(users::table.filter(users::email.eq(email))))
+                .get_result::<bool>(conn)
+                .await
+                .wrap_err("Failed to check email address")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            (!taken).then_some(email)
+        }
+        None => None,
+    };
+
+    for attempt in 0..5 {
+        let username = match attempt {
+            0 => base.clone(),
+            _ => format!("{}-{}", base, rand::random_range(1000..10000)),
+        };
+
+        let user_id = diesel::insert_into(users::table)
+            .values((
+                users::username.eq(&username),
+                users::email.eq(email),
+                users::email_verified_at.eq(email.map(|_| Utc::now())),
+            ))
+            .on_conflict(users::username)
+            .do_nothing()
+            .returning(users::id)
+            .get_result::<i32>(conn)
+            .await
+            .optional()
+            .wrap_err("Failed to insert user into database")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if let Some(user_id) = user_id {
+            return Ok(user_id);
+        }
+    }
+
+    Err(eyre!("Couldn't find a free username for `{}`", base))
+        .with_status_code(StatusCode::CONFLICT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StartQuery {
+    link: Option<bool>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/{provider}",
+    tag = "Users",
+    description = "Log in through an OpenID Connect provider. Redirects to the provider, which redirects back to the callback.",
+    responses(
+        (status = SEE_OTHER, description = "Redirects to the provider",
+            headers(
+                ("Set-Cookie" = String)
+            ),
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("provider" = String, Path, description = "ID of the provider to log in with"),
+        ("link" = Option<bool>, Query, description = "Link the identity to the account you're logged in to instead, which needs a login from the last few minutes")
+    )
+)]
+#[instrument(skip(conn, _scope, providers))]
+pub async fn start(
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(providers): State<OidcProviders>,
+    Path(provider_id): Path<String>,
+    Query(query): Query<StartQuery>,
+) -> Result<(Jar, Redirect), error::Error> {
+    let provider = providers.get(&provider_id)?;
+
+    let link_user_id = match (query.link.unwrap_or_default(), user) {
+        (false, _) => None,
+        (true, Some(user)) => {
+            reauth::require_fresh_login(&mut conn, &jar).await?;
+            Some(user.id)
+        }
+        (true, None) => {
+            return Err(eyre!("Log in before linking another account"))
+                .with_status_code(StatusCode::UNAUTHORIZED);
+        }
+    };
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state, nonce) = provider
+        .client
+        .authorize_url(
+            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scope(Scope::new("email".to_owned()))
+        .add_scope(Scope::new("profile".to_owned()))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    diesel::delete(oidc_logins::table)
+        .filter(oidc_logins::expires_at.le(now))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to forget expired logins")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::insert_into(oidc_logins::table)
+        .values(InsertableOidcLogin {
+            state_hash: &hash_token(state.secret()),
+            provider: &provider.id,
+            pkce_verifier: pkce_verifier.secret(),
+            nonce: nonce.secret(),
+            link_user_id,
+            expires_at: Utc::now() + Duration::minutes(LOGIN_TTL_MINUTES),
+        })
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to store login")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        set_state_cookie(jar, state.secret().clone()),
+        Redirect::to(url.as_str()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackQuery {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/{provider}/callback",
+    tag = "Users",
+    description = "Where the provider redirects back to. Logs in, creating an account for a new identity, or finishes linking an identity.",
+    responses(
+        (status = SEE_OTHER, description = "Logged in or linked, redirects to the home page",
+            headers(
+                ("Set-Cookie" = String)
+            ),
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("provider" = String, Path, description = "ID of the provider that redirected back"),
+        ("state" = String, Query, description = "State from the authorization request"),
+        ("code" = Option<String>, Query, description = "Authorization code"),
+        ("error" = Option<String>, Query, description = "Why the provider refused the login")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn callback(
+    DatabaseConnection(mut conn, jar, _): DatabaseConnection,
+    State(providers): State<OidcProviders>,
+    State(session_config): State<SessionConfig>,
+    State(invite_config): State<InviteConfig>,
+    client: ClientInfo,
+    Path(provider_id): Path<String>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<(Jar, Redirect), error::Error> {
+    let provider = providers.get(&provider_id)?;
+
+    if jar.get(STATE_COOKIE_NAME).map(|cookie| cookie.value()) != Some(query.state.as_str()) {
+        return Err(eyre!(
+            "This login was started in another browser, try again"
+        ))
+        .with_status_code(StatusCode::BAD_REQUEST);
+    }
+    let jar = remove_state_cookie(jar);
+
+    let (pkce_verifier, nonce, link_user_id) = diesel::delete(oidc_logins::table)
+        .filter(oidc_logins::state_hash.eq(hash_token(&query.state).as_slice()))
+        .filter(oidc_logins::provider.eq(&provider.id))
+        .filter(oidc_logins::expires_at.gt(now))
+        .returning((
+            oidc_logins::pkce_verifier,
+            oidc_logins::nonce,
+            oidc_logins::link_user_id,
+        ))
+        .get_result::<(String, String, Option<i32>)>(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up login")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_eyre("This login has expired, try again")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    if let Some(error) = query.error {
+        return Err(eyre!(
+            "{} refused the login: {}",
+            provider.name,
+            query.error_description.unwrap_or(error)
+        ))
+        .with_status_code(StatusCode::UNAUTHORIZED);
+    }
+    let code = query
+        .code
+        .ok_or_eyre("The provider didn't send an authorization code")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    let token_response = provider
+        .client
+        .exchange_code(AuthorizationCode::new(code))
+        .wrap_err("The provider has no token endpoint")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+        .request_async(&providers.0.http)
+        .await
+        .wrap_err("Failed to redeem the authorization code")
+        .with_status_code(StatusCode::BAD_GATEWAY)?;
+
+    let claims = token_response
+        .extra_fields()
+        .id_token()
+        .ok_or_eyre("The provider didn't send an ID token")
+        .with_status_code(StatusCode::BAD_GATEWAY)?
+        .claims(&provider.client.id_token_verifier(), &Nonce::new(nonce))
+        .wrap_err("Invalid ID token")
+        .with_status_code(StatusCode::UNAUTHORIZED)?;
+
+    let subject = claims.subject().as_str();
+    let email = claims
+        .email()
+        .filter(|_| claims.email_verified() == Some(true))
+        .map(|email| email.as_str());
+
+    if let Some(user_id) = link_user_id {
+        let linked_to = diesel::insert_into(user_identities::table)
+            .values((
+                user_identities::user_id.eq(user_id),
+                user_identities::provider.eq(&provider.id),
+                user_identities::subject.eq(subject),
+                user_identities::email.eq(email),
+            ))
+            .on_conflict((user_identities::provider, user_identities::subject))
+            .do_update()
+            .set(user_identities::last_used_at.eq(now))
+            .returning(user_identities::user_id)
+            .get_result::<i32>(&mut conn)
+            .await
+            .wrap_err("Failed to link identity")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if linked_to != user_id {
+            return Err(eyre!(
+                "This {} account is already linked to another account",
+                provider.name
+            ))
+            .with_status_code(StatusCode::CONFLICT);
+        }
+
+        return Ok((jar, Redirect::to("/")));
+    }
+
+    let known_user_id = diesel::update(user_identities::table)
+        .filter(user_identities::provider.eq(&provider.id))
+        .filter(user_identities::subject.eq(subject))
+        .set(user_identities::last_used_at.eq(now))
+        .returning(user_identities::user_id)
+        .get_result::<i32>(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up identity")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let user_id = match known_user_id {
+        Some(user_id) => user_id,
+        None => {
+            // There's nowhere to enter an invite code on the way through
+            // the provider, so it can only be linked to an account after
+            if invite_config.invite_only {
+                return Err(eyre!(
+                    "Sign up with an invite code first, then link your {} account from your account settings",
+                    provider.name
+                ))
+                .with_status_code(StatusCode::FORBIDDEN);
+            }
+
+            let user_id = create_user(
+                &mut conn,
+                provider,
+                claims
+                    .preferred_username()
+                    .map(|username| username.as_str()),
+                email,
+            )
+            .await?;
+
+            diesel::insert_into(user_identities::table)
+                .values((
+                    user_identities::user_id.eq(user_id),
+                    user_identities::provider.eq(&provider.id),
+                    user_identities::subject.eq(subject),
+                    user_identities::email.eq(email),
+                ))
+                .execute(&mut conn)
+                .await
+                .wrap_err("Failed to link identity")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            events::record(&mut conn, &client, AuthEventKind::Signup, user_id).await?;
+
+            user_id
+        }
+    };
+
+    let user_agent = client.user_agent.as_deref();
+
+    // The provider doesn't know about our second factor, so it's still
+    // asked for. The login form picks up the pending session.
+    let jar = if totp::is_enabled(&mut conn, user_id).await? {
+        let token = session::create_pending(&mut conn, user_id, user_agent).await?;
+        session::set_pending_cookie(jar, token)
+    } else {
+        events::record(&mut conn, &client, AuthEventKind::LoginSucceeded, user_id).await?;
+        let token = session::create(&mut conn, &session_config, user_id, user_agent).await?;
+        session::set_cookie(jar, &session_config, token)
+    };
+
+    Ok((jar, Redirect::to("/")))
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "identities.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct IdentitiesTemplate {
+    identities: Vec<Identity>,
+    providers: Vec<ProviderLink>,
+}
+
+impl Placeholder for IdentitiesTemplate {
+    fn placeholder() -> Self {
+        Self {
+            identities: vec![Identity::placeholder()],
+            providers: vec![ProviderLink {
+                id: "mock".to_owned(),
+                name: "Mock".to_owned(),
+            }],
+        }
+    }
+}
+
+openapi_template!(IdentitiesTemplate, identities);
+
+#[utoipa::path(
+    get,
+    path = "/auth/identities",
+    tag = "Users",
+    description = "List the OpenID Connect identities linked to your account",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(IdentitiesTemplate) = "text/html", example = IdentitiesTemplate::render_placeholder),
+                ([Identity], example = json!([Identity::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, providers))]
+pub async fn get_identities(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(providers): State<OidcProviders>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<IdentitiesTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let identities = Identity::query()
+        .filter(user_identities::user_id.eq(user.id))
+        .order(user_identities::created_at)
+        .load(&mut conn)
+        .await
+        .wrap_err("Failed to get identities")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        IdentitiesTemplate {
+            identities,
+            providers: providers.links(),
+        },
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/identities/{identity_id}",
+    tag = "Users",
+    description = "Unlink an OpenID Connect identity from your account. The last one can only be unlinked once the account has a password.",
+    responses(
+        (status = OK, description = "Ok"),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("identity_id" = i32, Path, description = "Identity ID to unlink")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_identity(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Path(identity_id): Path<i32>,
+) -> Result<(), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let has_password = users::table
+        .filter(users::id.eq(user.id))
+        .select(users::password.is_not_null())
+        .get_result::<bool>(&mut conn)
+        .await
+        .wrap_err("Failed to get account")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let identity_count = user_identities::table
+        .filter(user_identities::user_id.eq(user.id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+        .wrap_err("Failed to get identities")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !has_password && identity_count <= 1 {
+        return Err(eyre!(
+            "Set a password before unlinking your last identity, or you couldn't log in anymore"
+        ))
+        .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    let deleted = diesel::delete(user_identities::table)
+        .filter(user_identities::id.eq(identity_id))
+        .filter(user_identities::user_id.eq(user.id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to unlink identity")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if deleted == 0 {
+        return Err(eyre!("That identity isn't linked to your account"))
+            .with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
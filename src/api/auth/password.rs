@@ -0,0 +1,309 @@
+//! Password hashing
+//!
+//! Passwords are stored as PHC strings. New hashes are always
+//! Argon2id, but rows created before the switch still hold a
+//! `$blake3$<hex>` string (unsalted `blake3(username || password)`).
+//! Those are accepted once and then upgraded in place.
+//!
+//! Logins for accounts that don't exist or have no password are checked
+//! against a dummy hash, so they take as long as any other and the time
+//! a failed login takes doesn't tell which usernames exist.
+use std::sync::Arc;
+
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _, PasswordVerifier as _, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use clap::Args;
+use color_eyre::eyre::{self, Context, eyre};
+use serde::Deserialize;
+
+/// Prefix of hashes migrated from the old blake3 scheme
+const LEGACY_BLAKE3_PREFIX: &str = "$blake3$";
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct PasswordConfig {
+    /// Argon2id memory cost in KiB
+    #[clap(long, env = "ARGON2_MEMORY_KIB")]
+    #[serde(default = "default_memory_kib")]
+    pub argon2_memory_kib: u32,
+    /// Argon2id number of iterations
+    #[clap(long, env = "ARGON2_ITERATIONS")]
+    #[serde(default = "default_iterations")]
+    pub argon2_iterations: u32,
+    /// Argon2id degree of parallelism
+    #[clap(long, env = "ARGON2_PARALLELISM")]
+    #[serde(default = "default_parallelism")]
+    pub argon2_parallelism: u32,
+}
+
+#[inline]
+const fn default_memory_kib() -> u32 {
+    Params::DEFAULT_M_COST
+}
+
+#[inline]
+const fn default_iterations() -> u32 {
+    Params::DEFAULT_T_COST
+}
+
+#[inline]
+const fn default_parallelism() -> u32 {
+    Params::DEFAULT_P_COST
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            argon2_memory_kib: default_memory_kib(),
+            argon2_iterations: default_iterations(),
+            argon2_parallelism: default_parallelism(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password matched, but the stored hash uses a legacy
+    /// scheme or outdated parameters and should be replaced
+    ValidNeedsRehash,
+}
+
+#[derive(Clone)]
+pub struct PasswordHasher {
+    argon2: Arc<Argon2<'static>>,
+    /// Hash of nobody's password, with the current parameters
+    dummy: Arc<str>,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &PasswordConfig) -> eyre::Result<Self> {
+        let params = Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| eyre!("{}", e))
+        .wrap_err("Invalid Argon2 parameters")?;
+
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let dummy = argon2
+            .hash_password(b"", &SaltString::generate(&mut OsRng))
+            .map_err(|e| eyre!("{}", e))
+            .wrap_err("Failed to hash dummy password")?
+            .to_string();
+
+        Ok(Self {
+            argon2: Arc::new(argon2),
+            dummy: dummy.into(),
+        })
+    }
+
+    /// Hashes `password` with a fresh salt, returning a PHC string
+    pub async fn hash(&self, password: String) -> eyre::Result<String> {
+        let argon2 = self.argon2.clone();
+        tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| eyre!("{}", e))
+        })
+        .await
+        .wrap_err("Password hashing task failed")?
+        .wrap_err("Failed to hash password")
+    }
+
+    /// Checks `password` against a stored hash in constant time
+    ///
+    /// `username` is only needed for legacy blake3 hashes, which
+    /// were keyed on it.
+    pub async fn verify(
+        &self,
+        username: String,
+        password: String,
+        stored: String,
+    ) -> eyre::Result<Verification> {
+        let argon2 = self.argon2.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(hex) = stored.strip_prefix(LEGACY_BLAKE3_PREFIX) {
+                let expected = blake3::Hash::from_hex(hex).wrap_err("Malformed legacy hash")?;
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(username.as_bytes());
+                hasher.update(password.as_bytes());
+                // `blake3::Hash`'s `PartialEq` is constant time
+                return Ok(if hasher.finalize() == expected {
+                    Verification::ValidNeedsRehash
+                } else {
+                    Verification::Invalid
+                });
+            }
+
+            let hash = PasswordHash::new(&stored)
+                .map_err(|e| eyre!("{}", e))
+                .wrap_err("Malformed password hash")?;
+
+            match argon2.verify_password(password.as_bytes(), &hash) {
+                Ok(()) => {}
+                Err(argon2::password_hash::Error::Password) => return Ok(Verification::Invalid),
+                Err(e) => return Err(eyre!("{}", e)).wrap_err("Failed to verify password"),
+            }
+
+            let current = argon2.params();
+            let outdated = Algorithm::try_from(hash.algorithm) != Ok(Algorithm::Argon2id)
+                || Params::try_from(&hash).is_ok_and(|params| {
+                    params.m_cost() != current.m_cost()
+                        || params.t_cost() != current.t_cost()
+                        || params.p_cost() != current.p_cost()
+                });
+
+            Ok(if outdated {
+                Verification::ValidNeedsRehash
+            } else {
+                Verification::Valid
+            })
+        })
+        .await
+        .wrap_err("Password verification task failed")?
+    }
+
+    /// Takes as long as `verify`, for a login without a hash to check
+    /// the password against. Never matches.
+    pub async fn verify_nothing(&self, password: String) -> eyre::Result<Verification> {
+        self.verify(String::new(), password, self.dummy.to_string())
+            .await
+            .map(|_| Verification::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, so the tests don't take seconds each
+    fn hasher(memory_kib: u32) -> PasswordHasher {
+        PasswordHasher::new(&PasswordConfig {
+            argon2_memory_kib: memory_kib,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    fn legacy_hash(username: &str, password: &str) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(username.as_bytes());
+        hasher.update(password.as_bytes());
+        format!("{}{}", LEGACY_BLAKE3_PREFIX, hasher.finalize().to_hex())
+    }
+
+    async fn verify(
+        hasher: &PasswordHasher,
+        username: &str,
+        password: &str,
+        stored: &str,
+    ) -> Verification {
+        hasher
+            .verify(username.to_owned(), password.to_owned(), stored.to_owned())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn legacy_hash_matches_and_needs_rehash() {
+        let stored = legacy_hash("johndoe", "hunter2");
+
+        assert_eq!(
+            verify(&hasher(1024), "johndoe", "hunter2", &stored).await,
+            Verification::ValidNeedsRehash
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_hash_rejects_wrong_password_or_username() {
+        let hasher = hasher(1024);
+        let stored = legacy_hash("johndoe", "hunter2");
+
+        assert_eq!(
+            verify(&hasher, "johndoe", "hunter3", &stored).await,
+            Verification::Invalid
+        );
+        assert_eq!(
+            verify(&hasher, "janedoe", "hunter2", &stored).await,
+            Verification::Invalid
+        );
+        // The username is hashed in front of the password, not apart
+        assert_eq!(
+            verify(&hasher, "johndoeh", "unter2", &stored).await,
+            Verification::ValidNeedsRehash
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_legacy_hash_is_an_error() {
+        assert!(
+            hasher(1024)
+                .verify(
+                    "johndoe".to_owned(),
+                    "hunter2".to_owned(),
+                    format!("{}not-hex", LEGACY_BLAKE3_PREFIX),
+                )
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn current_hash_is_valid() {
+        let hasher = hasher(1024);
+        let stored = hasher.hash("hunter2".to_owned()).await.unwrap();
+
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(
+            verify(&hasher, "johndoe", "hunter2", &stored).await,
+            Verification::Valid
+        );
+        assert_eq!(
+            verify(&hasher, "johndoe", "hunter3", &stored).await,
+            Verification::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_parameters_need_rehash() {
+        let stored = hasher(1024).hash("hunter2".to_owned()).await.unwrap();
+        let changed = hasher(2048);
+
+        assert_eq!(
+            verify(&changed, "johndoe", "hunter2", &stored).await,
+            Verification::ValidNeedsRehash
+        );
+        assert_eq!(
+            verify(&changed, "johndoe", "hunter3", &stored).await,
+            Verification::Invalid
+        );
+
+        let rehashed = changed.hash("hunter2".to_owned()).await.unwrap();
+        assert_eq!(
+            verify(&changed, "johndoe", "hunter2", &rehashed).await,
+            Verification::Valid
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_never_matches() {
+        let hasher = hasher(1024);
+
+        assert_eq!(
+            hasher.verify_nothing(String::new()).await.unwrap(),
+            Verification::Invalid
+        );
+        assert_eq!(
+            hasher.verify_nothing("hunter2".to_owned()).await.unwrap(),
+            Verification::Invalid
+        );
+    }
+}
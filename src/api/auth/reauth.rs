@@ -0,0 +1,134 @@
+//! Re-authentication for sensitive account changes
+//!
+//! Changing the login or the email address, or deleting the account
+//! needs more than a session, which might have been stolen along with
+//! its cookie. The request has to carry the current password or a fresh
+//! code from the second factor. Accounts that only log in through an
+//! OpenID Connect provider have neither, so for them a session that was
+//! started in the last few minutes has to do. So it has to for linking
+//! another identity, which leaves through a redirect to the provider.
+//!
+//! Failures count against the login throttle and show up in the audit
+//! log like any other failed login.
+use axum::http::StatusCode;
+use chrono::{Duration, Utc};
+use color_eyre::eyre::{Context, eyre};
+use diesel::{ExpressionMethods, HasQuery, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        DatabaseUser, User,
+        events::{self, ClientInfo},
+        password::PasswordHasher,
+        session,
+        throttle::{LoginAttempt, ThrottleConfig},
+        totp,
+    },
+    cookies::Jar,
+    error::{self, Actions, WithStatusCode},
+    schema::users,
+};
+
+/// How recent a login has to be to stand in for the password of an
+/// account that has none
+const FRESH_LOGIN_MINUTES: i64 = 5;
+
+#[derive(ToSchema, Deserialize, Serialize, Debug, Default)]
+pub struct Reauthentication {
+    /// Your current password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_password: Option<String>,
+    /// A code from your authenticator app or a recovery code, instead
+    /// of the password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl Placeholder for Reauthentication {
+    fn placeholder() -> Self {
+        Self {
+            current_password: Some("verySecurePassword1234".to_owned()),
+            code: None,
+        }
+    }
+}
+
+/// Rejects the request unless its session was started in the last
+/// `FRESH_LOGIN_MINUTES`
+pub async fn require_fresh_login(conn: &mut AsyncPgConnection, jar: &Jar) -> error::Result<()> {
+    let started_at = match jar.get(session::COOKIE_NAME) {
+        Some(sessionid) => session::started_at(conn, sessionid.value()).await?,
+        None => None,
+    };
+
+    if !started_at
+        .is_some_and(|started_at| Utc::now() - started_at < Duration::minutes(FRESH_LOGIN_MINUTES))
+    {
+        return Err(eyre!(
+            "Log in again to confirm it's you, then try within {} minutes",
+            FRESH_LOGIN_MINUTES
+        ))
+        .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
+    }
+
+    Ok(())
+}
+
+/// Rejects the request unless `reauth` proves it comes from `user`
+/// and not just from someone holding their session
+pub async fn require(
+    conn: &mut AsyncPgConnection,
+    hasher: &PasswordHasher,
+    throttle_config: &ThrottleConfig,
+    client: &ClientInfo,
+    jar: &Jar,
+    user: &User,
+    reauth: &Reauthentication,
+) -> error::Result<()> {
+    let attempt = LoginAttempt::new(throttle_config, client.ip, &user.username);
+    attempt.check(conn).await?;
+
+    let db_user = DatabaseUser::query()
+        .filter(users::id.eq(user.id))
+        .get_result(conn)
+        .await
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let current_password = reauth
+        .current_password
+        .as_deref()
+        .filter(|password| !password.is_empty());
+    let code = reauth
+        .code
+        .as_deref()
+        .filter(|code| !code.trim().is_empty());
+
+    let verified = match (current_password, code) {
+        (Some(password), _) => db_user.verify_password(conn, hasher, password).await?,
+        (None, Some(code)) => totp::verify(conn, user.id, code).await?,
+        (None, None) if db_user.password.is_none() && !totp::is_enabled(conn, user.id).await? => {
+            return require_fresh_login(conn, jar).await;
+        }
+        (None, None) => {
+            return Err(eyre!(
+                "Enter your current password or a code from your authenticator app"
+            ))
+            .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
+        }
+    };
+
+    if !verified {
+        attempt.failed(conn).await?;
+        events::record_failed_login(conn, client, Some(user.id), &user.username).await?;
+        return Err(eyre!("Invalid password or code"))
+            .with_status_code_and_actions(StatusCode::UNAUTHORIZED, Actions::sign_out());
+    }
+    attempt.succeeded(conn).await?;
+
+    Ok(())
+}
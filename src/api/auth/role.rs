@@ -0,0 +1,81 @@
+//! Roles for moderating listings and administering users
+//!
+//! The role of the current user is handed to Postgres next to their
+//! id, and the row level security policies decide what it may touch.
+//! Handlers check it up front with `User::require_role`, so a missing
+//! role is answered with `403 Forbidden` instead of an update that
+//! silently matched no rows.
+use std::fmt::Display;
+
+use axum::http::StatusCode;
+use color_eyre::eyre::eyre;
+use diesel_derive_enum::DbEnum;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::auth::User,
+    error::{self, WithStatusCode},
+    schema::sql_types,
+};
+
+/// Ordered from least to most privileged, every role may do what the
+/// ones before it may
+#[derive(
+    DbEnum,
+    ToSchema,
+    Deserialize,
+    Serialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[db_enum(existing_type_path = "sql_types::UserRole")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// Can edit and hide every listing
+    Moderator,
+    /// Can also change roles and delete accounts
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn can_moderate(self) -> bool {
+        self >= Role::Moderator
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl User {
+    /// Rejects with `403 Forbidden` unless the user has at least `role`
+    pub fn require_role(&self, role: Role) -> error::Result<()> {
+        if self.role < role {
+            return Err(eyre!("This needs the `{}` role", role))
+                .with_status_code(StatusCode::FORBIDDEN);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Scopes limiting what personal access tokens may do
+//!
+//! Any other way of logging in grants every scope. Handlers opt in
+//! to a check by taking a `RequireScope<_>` after their
+//! `DatabaseConnection`.
+use std::{fmt::Display, marker::PhantomData};
+
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::StatusCode,
+};
+use color_eyre::eyre::eyre;
+use diesel_derive_enum::DbEnum;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::auth::User,
+    error::{self, WithStatusCode},
+    schema::sql_types,
+    state::AppState,
+};
+
+#[derive(DbEnum, ToSchema, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[db_enum(existing_type_path = "sql_types::TokenScope")]
+pub enum Scope {
+    #[serde(rename = "games:read")]
+    GamesRead,
+    #[serde(rename = "games:write")]
+    GamesWrite,
+    #[serde(rename = "account")]
+    Account,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::GamesRead, Scope::GamesWrite, Scope::Account];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::GamesRead => "games:read",
+            Scope::GamesWrite => "games:write",
+            Scope::Account => "account",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the credentials of the current request are allowed to do.
+/// Put into the request extensions by the `User` extractor.
+#[derive(Clone, Debug)]
+pub enum GrantedScopes {
+    All,
+    Only(Vec<Scope>),
+}
+
+impl GrantedScopes {
+    pub fn allows(&self, scope: Scope) -> bool {
+        match self {
+            GrantedScopes::All => true,
+            GrantedScopes::Only(scopes) => scopes.contains(&scope),
+        }
+    }
+}
+
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub struct GamesRead;
+pub struct GamesWrite;
+pub struct Account;
+
+impl RequiredScope for GamesRead {
+    const SCOPE: Scope = Scope::GamesRead;
+}
+
+impl RequiredScope for GamesWrite {
+    const SCOPE: Scope = Scope::GamesWrite;
+}
+
+impl RequiredScope for Account {
+    const SCOPE: Scope = Scope::Account;
+}
+
+/// Rejects the request with `403 Forbidden` if it was made with a
+/// token that lacks `S::SCOPE`
+pub struct RequireScope<S>(PhantomData<fn() -> S>);
+
+impl<S: RequiredScope> FromRequestParts<AppState> for RequireScope<S> {
+    type Rejection = error::Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if parts.extensions.get::<GrantedScopes>().is_none() {
+            <User as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state).await?;
+        }
+
+        match parts.extensions.get::<GrantedScopes>() {
+            Some(granted) if !granted.allows(S::SCOPE) => {
+                Err(eyre!("This token is missing the `{}` scope", S::SCOPE))
+                    .with_status_code(StatusCode::FORBIDDEN)
+            }
+            _ => Ok(Self(PhantomData)),
+        }
+    }
+}
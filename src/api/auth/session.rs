@@ -0,0 +1,491 @@
+//! Server-side sessions
+//!
+//! The `sessionid` cookie holds a random opaque token. Only its
+//! blake3 hash is stored, so a database leak doesn't leak live
+//! sessions.
+//!
+//! Accounts with a second factor first get a short-lived pending
+//! session, which only becomes a real one once the code checks out.
+use axum::{extract::Path, http::StatusCode};
+use axum_extra::TypedHeader;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use clap::Args;
+use color_eyre::eyre::{Context, eyre};
+use diesel::{
+    ExpressionMethods, HasQuery, OptionalExtension, QueryDsl, dsl::now, prelude::Insertable,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        User,
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+    },
+    cookies::Jar,
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    htmx::HxRefresh,
+    openapi_template,
+    schema::{sessions, users},
+};
+
+pub const COOKIE_NAME: &str = "sessionid";
+
+/// How long someone has to enter their second factor after their password
+const PENDING_TTL_MINUTES: i64 = 5;
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct SessionConfig {
+    /// How many days a login session stays valid
+    #[clap(long, env = "SESSION_TTL_DAYS")]
+    #[serde(default = "default_ttl_days")]
+    pub session_ttl_days: u32,
+}
+
+#[inline]
+const fn default_ttl_days() -> u32 {
+    30
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            session_ttl_days: default_ttl_days(),
+        }
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableSession<'a> {
+    user_id: i32,
+    token_hash: &'a [u8],
+    user_agent: Option<&'a str>,
+    expires_at: DateTime<Utc>,
+    second_factor_pending: bool,
+}
+
+#[derive(HasQuery, Debug)]
+#[diesel(table_name = crate::schema::sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct DatabaseSession {
+    id: i32,
+    token_hash: Vec<u8>,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Session {
+    id: i32,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    /// Whether this is the session the request was made with
+    current: bool,
+}
+
+impl DatabaseSession {
+    fn into_session(self, current_hash: Option<&[u8]>) -> Session {
+        Session {
+            current: current_hash == Some(self.token_hash.as_slice()),
+            id: self.id,
+            user_agent: self.user_agent,
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+impl Placeholder for Session {
+    fn placeholder() -> Self {
+        let now = Utc::now();
+        Self {
+            id: 1,
+            user_agent: Some(
+                "Mozilla/5.0 (X11; Linux x86_64; rv:147.0) Gecko/20100101 Firefox/147.0".to_owned(),
+            ),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + Duration::days(default_ttl_days().into()),
+            current: true,
+        }
+    }
+}
+
+/// Generates a random token suitable for handing to a client
+pub(super) fn generate_token() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Hashes a token for storage. Tokens are random, so a fast
+/// unsalted hash is enough.
+pub(super) fn hash_token(token: &str) -> [u8; blake3::OUT_LEN] {
+    *blake3::hash(token.as_bytes()).as_bytes()
+}
+
+/// Hands a session token to the client, in a cookie that lasts as
+/// long as the session
+pub fn set_cookie(jar: Jar, config: &SessionConfig, token: String) -> Jar {
+    let mut cookie = jar.build(COOKIE_NAME, token);
+    cookie.set_max_age(time::Duration::days(config.session_ttl_days.into()));
+    jar.add(cookie)
+}
+
+/// Hands a pending session token to the client, in a cookie that ends
+/// with the browser session
+pub fn set_pending_cookie(jar: Jar, token: String) -> Jar {
+    let cookie = jar.build(COOKIE_NAME, token);
+    jar.add(cookie)
+}
+
+/// Removes the session token from the client
+pub fn remove_cookie(jar: Jar) -> Jar {
+    let cookie = jar.removal(COOKIE_NAME);
+    jar.remove(cookie)
+}
+
+/// Starts a new session for `user_id`, returning the token to hand to the client
+pub async fn create(
+    conn: &mut AsyncPgConnection,
+    config: &SessionConfig,
+    user_id: i32,
+    user_agent: Option<&str>,
+) -> error::Result<String> {
+    insert(
+        conn,
+        user_id,
+        user_agent,
+        Utc::now() + Duration::days(config.session_ttl_days.into()),
+        false,
+    )
+    .await
+}
+
+/// Starts a session that is still waiting for the second factor of `user_id`
+pub async fn create_pending(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    user_agent: Option<&str>,
+) -> error::Result<String> {
+    insert(
+        conn,
+        user_id,
+        user_agent,
+        Utc::now() + Duration::minutes(PENDING_TTL_MINUTES),
+        true,
+    )
+    .await
+}
+
+async fn insert(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    user_agent: Option<&str>,
+    expires_at: DateTime<Utc>,
+    second_factor_pending: bool,
+) -> error::Result<String> {
+    let token = generate_token();
+
+    diesel::delete(sessions::table)
+        .filter(sessions::user_id.eq(user_id))
+        .filter(sessions::expires_at.le(now))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to clean up expired sessions")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::insert_into(sessions::table)
+        .values(InsertableSession {
+            user_id,
+            token_hash: &hash_token(&token),
+            user_agent,
+            expires_at,
+            second_factor_pending,
+        })
+        .execute(conn)
+        .await
+        .wrap_err("Failed to create session")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(token)
+}
+
+/// Resolves a session token to its user, marking the session as seen
+pub async fn authenticate(
+    conn: &mut AsyncPgConnection,
+    token: &str,
+) -> error::Result<Option<User>> {
+    let Some(user_id) = diesel::update(sessions::table)
+        .filter(sessions::token_hash.eq(hash_token(token).as_slice()))
+        .filter(sessions::expires_at.gt(now))
+        .filter(sessions::second_factor_pending.eq(false))
+        .set(sessions::last_seen_at.eq(now))
+        .returning(sessions::user_id)
+        .get_result::<i32>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up session")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(None);
+    };
+
+    User::query()
+        .filter(users::id.eq(user_id))
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Ends the session `token` belongs to and returns its user, in one
+/// statement so the same token can't be used twice at the same time
+pub async fn redeem(conn: &mut AsyncPgConnection, token: &str) -> error::Result<Option<User>> {
+    let Some(user_id) = diesel::delete(sessions::table)
+        .filter(sessions::token_hash.eq(hash_token(token).as_slice()))
+        .filter(sessions::expires_at.gt(now))
+        .filter(sessions::second_factor_pending.eq(false))
+        .returning(sessions::user_id)
+        .get_result::<i32>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to redeem session")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(None);
+    };
+
+    User::query()
+        .filter(users::id.eq(user_id))
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Finds the user a pending session is waiting on a second factor for
+pub async fn pending_user(conn: &mut AsyncPgConnection, token: &str) -> error::Result<Option<i32>> {
+    sessions::table
+        .filter(sessions::token_hash.eq(hash_token(token).as_slice()))
+        .filter(sessions::expires_at.gt(now))
+        .filter(sessions::second_factor_pending.eq(true))
+        .select(sessions::user_id)
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up pending login")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// When the live session a token belongs to was started
+pub async fn started_at(
+    conn: &mut AsyncPgConnection,
+    token: &str,
+) -> error::Result<Option<DateTime<Utc>>> {
+    sessions::table
+        .filter(sessions::token_hash.eq(hash_token(token).as_slice()))
+        .filter(sessions::expires_at.gt(now))
+        .filter(sessions::second_factor_pending.eq(false))
+        .select(sessions::created_at)
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up session")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Ends the session a token belongs to
+pub async fn revoke(conn: &mut AsyncPgConnection, token: &str) -> error::Result<()> {
+    diesel::delete(sessions::table)
+        .filter(sessions::token_hash.eq(hash_token(token).as_slice()))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to revoke session")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Ends every session of `user_id` except the one `keep` belongs to
+pub async fn revoke_others(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    keep: Option<&str>,
+) -> error::Result<()> {
+    let keep = keep.map(hash_token).unwrap_or_default();
+
+    diesel::delete(sessions::table)
+        .filter(sessions::user_id.eq(user_id))
+        .filter(sessions::token_hash.ne(keep.as_slice()))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to revoke other sessions")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+/// Lists the active sessions of `user_id`, marking the one whose
+/// token hashes to `current_hash`
+pub async fn load(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    current_hash: Option<&[u8]>,
+) -> error::Result<Vec<Session>> {
+    Ok(DatabaseSession::query()
+        .filter(sessions::user_id.eq(user_id))
+        .filter(sessions::expires_at.gt(now))
+        .filter(sessions::second_factor_pending.eq(false))
+        .order(sessions::last_seen_at.desc())
+        .load(conn)
+        .await
+        .wrap_err("Failed to get sessions")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|session| session.into_session(current_hash))
+        .collect())
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "sessions.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct SessionsTemplate {
+    sessions: Vec<Session>,
+}
+
+impl Placeholder for SessionsTemplate {
+    fn placeholder() -> Self {
+        Self {
+            sessions: vec![Session::placeholder()],
+        }
+    }
+}
+
+openapi_template!(SessionsTemplate, sessions);
+
+#[utoipa::path(
+    get,
+    path = "/auth/sessions",
+    tag = "Users",
+    description = "List the active sessions of your account",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(SessionsTemplate) = "text/html", example = SessionsTemplate::render_placeholder),
+                ([Session], example = json!([Session::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_sessions(
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<SessionsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let current_hash = jar
+        .get(COOKIE_NAME)
+        .map(|sessionid| hash_token(sessionid.value()));
+
+    let sessions = load(
+        &mut conn,
+        user.id,
+        current_hash.as_ref().map(|hash| hash.as_slice()),
+    )
+    .await?;
+
+    Ok(HtmlOrJsonOnce(accept, SessionsTemplate { sessions }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/sessions/{session_id}",
+    tag = "Users",
+    description = "Revoke one of your sessions. Revoking the current session logs you out.",
+    responses(
+        (status = OK, description = "Ok",
+            headers(
+                ("Set-Cookie" = String)
+            ),
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("session_id" = i32, Path, description = "Session ID to revoke")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_session(
+    DatabaseConnection(mut conn, jar, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Path(session_id): Path<i32>,
+) -> Result<(Jar, Option<TypedHeader<HxRefresh>>), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let revoked_hash = diesel::delete(sessions::table)
+        .filter(sessions::id.eq(session_id))
+        .filter(sessions::user_id.eq(user.id))
+        .returning(sessions::token_hash)
+        .get_result::<Vec<u8>>(&mut conn)
+        .await
+        .wrap_err("Failed to revoke session")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    let is_current = jar
+        .get(COOKIE_NAME)
+        .is_some_and(|sessionid| hash_token(sessionid.value()).as_slice() == revoked_hash);
+
+    if is_current {
+        Ok((remove_cookie(jar), Some(TypedHeader(HxRefresh(true)))))
+    } else {
+        Ok((jar, None))
+    }
+}
@@ -0,0 +1,217 @@
+//! Login throttling
+//!
+//! Failed password and second factor checks are counted per account
+//! and per client address in Postgres, so every instance sees the
+//! same counts. After a few free attempts each further failure
+//! doubles how long the next attempt has to wait, up to a lockout.
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::StatusCode,
+};
+use chrono::{DateTime, Duration, Utc};
+use clap::Args;
+use color_eyre::eyre::{Context, OptionExt, eyre};
+use diesel::{
+    ExpressionMethods, QueryDsl,
+    dsl::{IntervalDsl, now},
+    upsert::excluded,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Deserialize;
+
+use crate::{
+    error::{self, WithStatusCode},
+    schema::login_attempts,
+    state::AppState,
+};
+
+/// Failures older than this are forgotten
+const FORGET_AFTER_HOURS: i32 = 24;
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct ThrottleConfig {
+    /// How many failed logins are allowed before attempts are slowed down
+    #[clap(long, env = "LOGIN_FREE_ATTEMPTS")]
+    #[serde(default = "default_free_attempts")]
+    pub login_free_attempts: u32,
+    /// Longest time in minutes a throttled account or address has to wait
+    #[clap(long, env = "LOGIN_LOCKOUT_MINUTES")]
+    #[serde(default = "default_lockout_minutes")]
+    pub login_lockout_minutes: u32,
+    /// Take the client address from the last `X-Forwarded-For` entry.
+    /// Only enable this behind a reverse proxy that sets it.
+    #[clap(long, env = "TRUST_FORWARDED_FOR")]
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+#[inline]
+const fn default_free_attempts() -> u32 {
+    5
+}
+
+#[inline]
+const fn default_lockout_minutes() -> u32 {
+    15
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            login_free_attempts: default_free_attempts(),
+            login_lockout_minutes: default_lockout_minutes(),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// How long to wait after the `failures`th failed attempt
+    fn backoff(&self, failures: i32) -> Duration {
+        let over = failures - self.login_free_attempts as i32;
+        if over <= 0 {
+            return Duration::zero();
+        }
+
+        Duration::seconds(1 << (over - 1).min(20))
+            .min(Duration::minutes(self.login_lockout_minutes.into()))
+    }
+}
+
+/// Address of the client that made the request
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = error::Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if state.throttle.trust_forwarded_for
+            && let Some(forwarded) = parts
+                .headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+        {
+            // The proxy appends the address it saw, anything before it
+            // came from the client and can't be trusted
+            return forwarded
+                .rsplit(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .parse()
+                .map(Self)
+                .wrap_err("Malformed X-Forwarded-For header")
+                .with_status_code(StatusCode::BAD_REQUEST);
+        }
+
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| Self(addr.ip()))
+            .ok_or_eyre("Failed to get client address")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// A login attempt for `account` from `ip`. Check it before looking
+/// at the credentials and record how it went afterwards.
+pub struct LoginAttempt<'a> {
+    config: &'a ThrottleConfig,
+    account_key: String,
+    ip_key: String,
+}
+
+impl<'a> LoginAttempt<'a> {
+    pub fn new(config: &'a ThrottleConfig, ip: IpAddr, account: &str) -> Self {
+        Self {
+            config,
+            account_key: format!("account:{}", account),
+            ip_key: format!("ip:{}", ip),
+        }
+    }
+
+    /// A password reset requested for `account` from `ip`, counted apart
+    /// from logins so requesting links can't lock anyone out
+    pub fn password_reset(config: &'a ThrottleConfig, ip: IpAddr, account: &str) -> Self {
+        Self {
+            config,
+            account_key: format!("reset:account:{}", account),
+            ip_key: format!("reset:ip:{}", ip),
+        }
+    }
+
+    /// Rejects the attempt with `429 Too Many Requests` while the
+    /// account or address has to wait
+    pub async fn check(&self, conn: &mut AsyncPgConnection) -> error::Result<()> {
+        let attempts = login_attempts::table
+            .filter(login_attempts::key.eq_any([&self.account_key, &self.ip_key]))
+            .filter(login_attempts::last_failure_at.gt(now - FORGET_AFTER_HOURS.hours()))
+            .select((login_attempts::failures, login_attempts::last_failure_at))
+            .load::<(i32, DateTime<Utc>)>(conn)
+            .await
+            .wrap_err("Failed to get login attempts")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let retry_at = attempts
+            .into_iter()
+            .map(|(failures, last_failure_at)| last_failure_at + self.config.backoff(failures))
+            .max();
+
+        match retry_at.map(|retry_at| retry_at - Utc::now()) {
+            Some(wait) if wait > Duration::zero() => Err(eyre!(
+                "Too many failed logins, try again in {} seconds",
+                wait.num_seconds() + 1
+            ))
+            .with_retry_after(wait.to_std().unwrap_or_default()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failure against both the account and the address
+    pub async fn failed(&self, conn: &mut AsyncPgConnection) -> error::Result<()> {
+        diesel::delete(login_attempts::table)
+            .filter(login_attempts::key.eq_any([&self.account_key, &self.ip_key]))
+            .filter(login_attempts::last_failure_at.le(now - FORGET_AFTER_HOURS.hours()))
+            .execute(conn)
+            .await
+            .wrap_err("Failed to forget old login attempts")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        diesel::insert_into(login_attempts::table)
+            .values(Vec::from([&self.account_key, &self.ip_key].map(|key| {
+                (login_attempts::key.eq(key), login_attempts::failures.eq(1))
+            })))
+            .on_conflict(login_attempts::key)
+            .do_update()
+            .set((
+                login_attempts::failures
+                    .eq(login_attempts::failures + excluded(login_attempts::failures)),
+                login_attempts::last_failure_at.eq(now),
+            ))
+            .execute(conn)
+            .await
+            .wrap_err("Failed to record login attempt")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(())
+    }
+
+    /// Clears the failures of the account. Those of the address stay,
+    /// so logging in to one account doesn't help guessing another.
+    pub async fn succeeded(&self, conn: &mut AsyncPgConnection) -> error::Result<()> {
+        diesel::delete(login_attempts::table)
+            .filter(login_attempts::key.eq(&self.account_key))
+            .execute(conn)
+            .await
+            .wrap_err("Failed to clear login attempts")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(())
+    }
+}
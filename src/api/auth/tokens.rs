@@ -0,0 +1,518 @@
+//! Personal access tokens
+//!
+//! Named, revocable tokens for scripts and integrations, sent as
+//! `Authorization: Bearer rge_...`. Like sessions, only a hash of
+//! the token is stored and the secret is shown once on creation.
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{Context, eyre};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, HasQuery, OptionalExtension, QueryDsl,
+    dsl::now,
+    prelude::{AsChangeset, Insertable},
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        User,
+        cache::CredentialCache,
+        events::{self, AuthEventKind, ClientInfo},
+        pool::DatabaseConnection,
+        scope::{self, RequireScope, Scope},
+        session::{generate_token, hash_token},
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{api_tokens, users},
+};
+
+/// Marks a bearer token as a personal access token rather than a JWT
+pub const PREFIX: &str = "rge_";
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableApiToken<'a> {
+    user_id: i32,
+    name: &'a str,
+    token_hash: &'a [u8],
+    scopes: Vec<Option<Scope>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct ChangesetApiToken {
+    name: Option<String>,
+    scopes: Option<Vec<Option<Scope>>>,
+}
+
+#[derive(HasQuery, Debug)]
+#[diesel(table_name = crate::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct DatabaseApiToken {
+    id: i32,
+    name: String,
+    scopes: Vec<Option<Scope>>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct ApiToken {
+    id: i32,
+    name: String,
+    scopes: Vec<Scope>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<DatabaseApiToken> for ApiToken {
+    fn from(token: DatabaseApiToken) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            scopes: token.scopes.into_iter().flatten().collect(),
+            created_at: token.created_at,
+            last_used_at: token.last_used_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct ApiTokens {
+    tokens: Vec<ApiToken>,
+    /// Secret of the token that was just created. This is the only
+    /// time it is ever shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_secret: Option<String>,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct NewApiToken {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<Scope>,
+    /// Leave empty for a token that never expires
+    expires_in_days: Option<u32>,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct UpdateApiToken {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<Scope>>,
+}
+
+impl Placeholder for ApiToken {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            name: "Inventory sync".to_owned(),
+            scopes: vec![Scope::GamesRead, Scope::GamesWrite],
+            created_at: Utc::now(),
+            last_used_at: None,
+            expires_at: Some(Utc::now() + Duration::days(90)),
+        }
+    }
+}
+
+impl Placeholder for ApiTokens {
+    fn placeholder() -> Self {
+        Self {
+            tokens: vec![ApiToken::placeholder()],
+            created_secret: Some(format!(
+                "{}q0Xc2m3Jc9Jw1r0kq7wz8d5m0Jb6mC8d4Xn1Zr2yT3s",
+                PREFIX
+            )),
+        }
+    }
+}
+
+impl Placeholder for NewApiToken {
+    fn placeholder() -> Self {
+        Self {
+            name: "Inventory sync".to_owned(),
+            scopes: vec![Scope::GamesRead, Scope::GamesWrite],
+            expires_in_days: Some(90),
+        }
+    }
+}
+
+impl Placeholder for UpdateApiToken {
+    fn placeholder() -> Self {
+        Self {
+            name: Some("Inventory sync".to_owned()),
+            scopes: Some(vec![Scope::GamesRead]),
+        }
+    }
+}
+
+/// Resolves a personal access token to its user, scopes and expiry,
+/// recording when it was last used
+pub async fn authenticate(
+    conn: &mut AsyncPgConnection,
+    token: &str,
+) -> error::Result<Option<(User, Vec<Scope>, Option<DateTime<Utc>>)>> {
+    let Some((user_id, scopes, expires_at)) = diesel::update(api_tokens::table)
+        .filter(api_tokens::token_hash.eq(hash_token(token).as_slice()))
+        .filter(
+            api_tokens::expires_at
+                .is_null()
+                .or(api_tokens::expires_at.gt(now)),
+        )
+        .set(api_tokens::last_used_at.eq(now))
+        .returning((
+            api_tokens::user_id,
+            api_tokens::scopes,
+            api_tokens::expires_at,
+        ))
+        .get_result::<(i32, Vec<Option<Scope>>, Option<DateTime<Utc>>)>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to look up access token")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(None);
+    };
+
+    // Scheduling the deletion revokes the tokens, this is in case one
+    // gets created while it is
+    let Some(user) = User::query()
+        .filter(users::id.eq(user_id))
+        .filter(users::delete_after.is_null())
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some((
+        user,
+        scopes.into_iter().flatten().collect(),
+        expires_at,
+    )))
+}
+
+/// Lists the access tokens of `user_id`, newest first
+pub async fn load_tokens(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> error::Result<Vec<ApiToken>> {
+    Ok(DatabaseApiToken::query()
+        .filter(api_tokens::user_id.eq(user_id))
+        .order(api_tokens::created_at.desc())
+        .load(conn)
+        .await
+        .wrap_err("Failed to get access tokens")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(ApiToken::from)
+        .collect())
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "tokens.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct TokensTemplate {
+    tokens: ApiTokens,
+}
+
+impl Placeholder for TokensTemplate {
+    fn placeholder() -> Self {
+        Self {
+            tokens: ApiTokens::placeholder(),
+        }
+    }
+}
+
+openapi_template!(TokensTemplate, tokens);
+
+#[utoipa::path(
+    get,
+    path = "/auth/tokens",
+    tag = "Users",
+    description = "List your personal access tokens",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TokensTemplate) = "text/html", example = TokensTemplate::render_placeholder),
+                (ApiTokens, example = ApiTokens::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_tokens(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<TokensTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TokensTemplate {
+            tokens: ApiTokens {
+                tokens: load_tokens(&mut conn, user.id).await?,
+                created_secret: None,
+            },
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/tokens",
+    tag = "Users",
+    description = "Create a personal access token. The secret is only returned in this response.",
+    request_body(content(
+        (NewApiToken, example = NewApiToken::placeholder),
+        (NewApiToken = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TokensTemplate) = "text/html", example = TokensTemplate::render_placeholder),
+                (ApiTokens, example = ApiTokens::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn add_token(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    client: ClientInfo,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(new_token): JsonOrForm<NewApiToken>,
+) -> Result<HtmlOrJsonOnce<TokensTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    if new_token.scopes.is_empty() {
+        return Err(eyre!("A token needs at least one scope"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    let secret = format!("{}{}", PREFIX, generate_token());
+
+    diesel::insert_into(api_tokens::table)
+        .values(InsertableApiToken {
+            user_id: user.id,
+            name: &new_token.name,
+            token_hash: &hash_token(&secret),
+            scopes: new_token.scopes.into_iter().map(Some).collect(),
+            expires_at: new_token
+                .expires_in_days
+                .map(|days| Utc::now() + Duration::days(days.into())),
+        })
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to create access token")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+    events::record(&mut conn, &client, AuthEventKind::TokenCreated, user.id).await?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TokensTemplate {
+            tokens: ApiTokens {
+                tokens: load_tokens(&mut conn, user.id).await?,
+                created_secret: Some(secret),
+            },
+        },
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/auth/tokens/{token_id}",
+    tag = "Users",
+    description = "Rename a personal access token or change its scopes",
+    request_body(content(
+        (UpdateApiToken, example = UpdateApiToken::placeholder),
+        (UpdateApiToken = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TokensTemplate) = "text/html", example = TokensTemplate::render_placeholder),
+                (ApiTokens, example = ApiTokens::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("token_id" = i32, Path, description = "Access token ID to update")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, credential_cache))]
+pub async fn update_token(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(credential_cache): State<CredentialCache>,
+    Path(token_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(changes): JsonOrForm<UpdateApiToken>,
+) -> Result<HtmlOrJsonOnce<TokensTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    if changes.scopes.as_ref().is_some_and(Vec::is_empty) {
+        return Err(eyre!("A token needs at least one scope"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    diesel::update(api_tokens::table)
+        .filter(api_tokens::id.eq(token_id))
+        .filter(api_tokens::user_id.eq(user.id))
+        .set(ChangesetApiToken {
+            name: changes.name,
+            scopes: changes
+                .scopes
+                .map(|scopes| scopes.into_iter().map(Some).collect()),
+        })
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update access token")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+    credential_cache.invalidate_user(user.id);
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TokensTemplate {
+            tokens: ApiTokens {
+                tokens: load_tokens(&mut conn, user.id).await?,
+                created_secret: None,
+            },
+        },
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/tokens/{token_id}",
+    tag = "Users",
+    description = "Revoke a personal access token",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TokensTemplate) = "text/html", example = TokensTemplate::render_placeholder),
+                (ApiTokens, example = ApiTokens::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("token_id" = i32, Path, description = "Access token ID to revoke")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, credential_cache))]
+pub async fn delete_token(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(credential_cache): State<CredentialCache>,
+    Path(token_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<TokensTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    diesel::delete(api_tokens::table)
+        .filter(api_tokens::id.eq(token_id))
+        .filter(api_tokens::user_id.eq(user.id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to revoke access token")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+    credential_cache.invalidate_user(user.id);
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TokensTemplate {
+            tokens: ApiTokens {
+                tokens: load_tokens(&mut conn, user.id).await?,
+                created_secret: None,
+            },
+        },
+    ))
+}
@@ -0,0 +1,679 @@
+//! TOTP second factor (RFC 6238)
+//!
+//! Enrolling stores an unconfirmed secret. It only starts being
+//! asked for at login once a first code from the authenticator app
+//! was confirmed, which also hands out the recovery codes. Recovery
+//! codes are random like session tokens, so they're stored as plain
+//! blake3 hashes too.
+use axum::{extract::State, http::StatusCode};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, OptionExt, eyre};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, HasQuery, OptionalExtension, QueryDsl,
+    dsl::{exists, now},
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rand::Rng;
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, TOTP};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        DatabaseUser, User,
+        cache::CredentialCache,
+        events::{self, AuthEventKind, ClientInfo},
+        password::PasswordHasher,
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+        session::{self, SessionConfig, hash_token},
+        throttle::{LoginAttempt, ThrottleConfig},
+    },
+    cookies::Jar,
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    htmx::HxRefresh,
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{recovery_codes, totp_credentials, users},
+};
+
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn totp(secret: Vec<u8>, username: String) -> TOTP {
+    // Skew is handled by `matching_step` so the step that matched is known
+    TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP,
+        secret,
+        Some(env!("CARGO_PKG_NAME").to_owned()),
+        username,
+    )
+}
+
+/// Finds the time step `code` belongs to, allowing for one step of
+/// clock drift either way
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let current = Utc::now().timestamp() / STEP as i64;
+    (current - 1..=current + 1).find(|step| totp.check(code, *step as u64 * STEP))
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::rng();
+    (0..4)
+        .map(|_| {
+            (0..4)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())]
+                        as char
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Strips the separators people are likely to type along with a code
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Whether `user_id` has to pass a second factor to log in
+pub async fn is_enabled(conn: &mut AsyncPgConnection, user_id: i32) -> error::Result<bool> {
+    diesel::select(exists(
+        totp_credentials::table
+            .filter(totp_credentials::user_id.eq(user_id))
+            .filter(totp_credentials::confirmed_at.is_not_null()),
+    ))
+    .get_result(conn)
+    .await
+    .wrap_err("Failed to check for a second factor")
+    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Checks a TOTP or recovery code of `user_id`. Both are only
+/// accepted once.
+pub async fn verify(conn: &mut AsyncPgConnection, user_id: i32, code: &str) -> error::Result<bool> {
+    let code = normalize_code(code);
+
+    if code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+        let Some(secret) = totp_credentials::table
+            .filter(totp_credentials::user_id.eq(user_id))
+            .filter(totp_credentials::confirmed_at.is_not_null())
+            .select(totp_credentials::secret)
+            .get_result::<Vec<u8>>(conn)
+            .await
+            .optional()
+            .wrap_err("Failed to get second factor")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        else {
+            return Ok(false);
+        };
+
+        let Some(step) = matching_step(&totp(secret, String::new()), &code) else {
+            return Ok(false);
+        };
+
+        let updated = diesel::update(totp_credentials::table)
+            .filter(totp_credentials::user_id.eq(user_id))
+            .filter(
+                totp_credentials::last_used_step
+                    .is_null()
+                    .or(totp_credentials::last_used_step.lt(step)),
+            )
+            .set(totp_credentials::last_used_step.eq(step))
+            .execute(conn)
+            .await
+            .wrap_err("Failed to record used code")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(updated == 1)
+    } else {
+        let used = diesel::update(recovery_codes::table)
+            .filter(recovery_codes::user_id.eq(user_id))
+            .filter(recovery_codes::code_hash.eq(hash_token(&code).as_slice()))
+            .filter(recovery_codes::used_at.is_null())
+            .set(recovery_codes::used_at.eq(now))
+            .execute(conn)
+            .await
+            .wrap_err("Failed to use recovery code")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(used == 1)
+    }
+}
+
+#[derive(ToSchema, Serialize, Debug, Default)]
+pub struct TotpStatus {
+    enabled: bool,
+    /// `otpauth://` URI for authenticator apps, only set while enrolling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provisioning_uri: Option<String>,
+    /// Base32 secret for typing into an authenticator app, only set while enrolling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    /// Only returned once, right after the second factor is confirmed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+    /// How many recovery codes haven't been used yet
+    recovery_codes_left: i64,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct DisableTotp {
+    password: String,
+    /// A current TOTP or recovery code
+    code: String,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct SecondFactor {
+    /// A TOTP or recovery code
+    code: String,
+    /// Challenge returned by `/auth/login`. Browsers can leave this
+    /// out, it is also kept in the session cookie.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    challenge: Option<String>,
+}
+
+impl Placeholder for TotpStatus {
+    fn placeholder() -> Self {
+        Self {
+            enabled: false,
+            provisioning_uri: Some(
+                "otpauth://totp/retro-game-exchange:johndoe?secret=JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP&issuer=retro-game-exchange"
+                    .to_owned(),
+            ),
+            secret: Some("JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP".to_owned()),
+            recovery_codes: None,
+            recovery_codes_left: 0,
+        }
+    }
+}
+
+impl Placeholder for TotpCode {
+    fn placeholder() -> Self {
+        Self {
+            code: "123456".to_owned(),
+        }
+    }
+}
+
+impl Placeholder for DisableTotp {
+    fn placeholder() -> Self {
+        Self {
+            password: "verySecurePassword1234".to_owned(),
+            code: "123456".to_owned(),
+        }
+    }
+}
+
+impl Placeholder for SecondFactor {
+    fn placeholder() -> Self {
+        Self {
+            code: "123456".to_owned(),
+            challenge: None,
+        }
+    }
+}
+
+async fn load_status(conn: &mut AsyncPgConnection, user_id: i32) -> error::Result<TotpStatus> {
+    let enabled = is_enabled(conn, user_id).await?;
+
+    let recovery_codes_left = recovery_codes::table
+        .filter(recovery_codes::user_id.eq(user_id))
+        .filter(recovery_codes::used_at.is_null())
+        .count()
+        .get_result(conn)
+        .await
+        .wrap_err("Failed to count recovery codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(TotpStatus {
+        enabled,
+        recovery_codes_left,
+        ..Default::default()
+    })
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "totp.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct TotpTemplate {
+    totp: TotpStatus,
+}
+
+impl Placeholder for TotpTemplate {
+    fn placeholder() -> Self {
+        Self {
+            totp: TotpStatus::placeholder(),
+        }
+    }
+}
+
+openapi_template!(TotpTemplate, totp);
+
+#[utoipa::path(
+    get,
+    path = "/auth/totp",
+    tag = "Users",
+    description = "Check whether two-factor authentication is enabled",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TotpTemplate) = "text/html", example = TotpTemplate::render_placeholder),
+                (TotpStatus, example = TotpStatus::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_totp(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<TotpTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TotpTemplate {
+            totp: load_status(&mut conn, user.id).await?,
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/totp",
+    tag = "Users",
+    description = "Start enrolling an authenticator app. \
+        Two-factor authentication is only enabled once a code is confirmed with `/auth/totp/confirm`.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TotpTemplate) = "text/html", example = TotpTemplate::render_placeholder),
+                (TotpStatus, example = TotpStatus::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn enroll_totp(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<TotpTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    if is_enabled(&mut conn, user.id).await? {
+        return Err(eyre!("Two-factor authentication is already enabled"))
+            .with_status_code(StatusCode::CONFLICT);
+    }
+
+    let secret = rand::random::<[u8; 20]>().to_vec();
+
+    diesel::insert_into(totp_credentials::table)
+        .values((
+            totp_credentials::user_id.eq(user.id),
+            totp_credentials::secret.eq(secret.as_slice()),
+        ))
+        .on_conflict(totp_credentials::user_id)
+        .do_update()
+        .set((
+            totp_credentials::secret.eq(secret.as_slice()),
+            totp_credentials::last_used_step.eq(None::<i64>),
+        ))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to store second factor")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let totp = totp(secret, user.username);
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TotpTemplate {
+            totp: TotpStatus {
+                provisioning_uri: Some(totp.get_url()),
+                secret: Some(totp.get_secret_base32()),
+                ..Default::default()
+            },
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/totp/confirm",
+    tag = "Users",
+    description = "Confirm enrollment with a first code, enabling two-factor authentication. \
+        The recovery codes are only returned in this response.",
+    request_body(content(
+        (TotpCode, example = TotpCode::placeholder),
+        (TotpCode = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TotpTemplate) = "text/html", example = TotpTemplate::render_placeholder),
+                (TotpStatus, example = TotpStatus::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, credential_cache, code))]
+pub async fn confirm_totp(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(credential_cache): State<CredentialCache>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(code): JsonOrForm<TotpCode>,
+) -> Result<HtmlOrJsonOnce<TotpTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let (secret, confirmed_at) = totp_credentials::table
+        .filter(totp_credentials::user_id.eq(user.id))
+        .select((totp_credentials::secret, totp_credentials::confirmed_at))
+        .get_result::<(Vec<u8>, Option<DateTime<Utc>>)>(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get second factor")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_eyre("Start enrolling an authenticator app first")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    if confirmed_at.is_some() {
+        return Err(eyre!("Two-factor authentication is already enabled"))
+            .with_status_code(StatusCode::CONFLICT);
+    }
+
+    let step = matching_step(&totp(secret, user.username), &normalize_code(&code.code))
+        .ok_or_eyre("Invalid code, check the time on your device")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    diesel::update(totp_credentials::table)
+        .filter(totp_credentials::user_id.eq(user.id))
+        .set((
+            totp_credentials::confirmed_at.eq(now),
+            totp_credentials::last_used_step.eq(step),
+        ))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to enable second factor")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Basic auth stops working once the second factor is on
+    credential_cache.invalidate_user(user.id);
+
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<_>>();
+
+    diesel::delete(recovery_codes::table)
+        .filter(recovery_codes::user_id.eq(user.id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to remove old recovery codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::insert_into(recovery_codes::table)
+        .values(
+            codes
+                .iter()
+                .map(|code| {
+                    (
+                        recovery_codes::user_id.eq(user.id),
+                        recovery_codes::code_hash.eq(hash_token(&normalize_code(code)).to_vec()),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to store recovery codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TotpTemplate {
+            totp: TotpStatus {
+                enabled: true,
+                recovery_codes_left: codes.len() as i64,
+                recovery_codes: Some(codes),
+                ..Default::default()
+            },
+        },
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/totp",
+    tag = "Users",
+    description = "Disable two-factor authentication. Needs your password and a current code.",
+    request_body(content(
+        (DisableTotp, example = DisableTotp::placeholder),
+        (DisableTotp = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TotpTemplate) = "text/html", example = TotpTemplate::render_placeholder),
+                (TotpStatus, example = TotpStatus::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, hasher, credentials))]
+pub async fn disable_totp(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    State(hasher): State<PasswordHasher>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(credentials): JsonOrForm<DisableTotp>,
+) -> Result<HtmlOrJsonOnce<TotpTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let db_user = DatabaseUser::query()
+        .filter(users::id.eq(user.id))
+        .get_result(&mut conn)
+        .await
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !db_user
+        .verify_password(&mut conn, &hasher, &credentials.password)
+        .await?
+        || !verify(&mut conn, user.id, &credentials.code).await?
+    {
+        return Err(eyre!("Invalid password or code")).with_status_code(StatusCode::UNAUTHORIZED);
+    }
+
+    diesel::delete(totp_credentials::table)
+        .filter(totp_credentials::user_id.eq(user.id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to remove second factor")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    diesel::delete(recovery_codes::table)
+        .filter(recovery_codes::user_id.eq(user.id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to remove recovery codes")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        TotpTemplate {
+            totp: load_status(&mut conn, user.id).await?,
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/login/totp",
+    tag = "Users",
+    description = "Finish logging in to an account with two-factor authentication",
+    request_body(content(
+        (SecondFactor, example = SecondFactor::placeholder),
+        (SecondFactor = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            headers(
+                ("Set-Cookie" = String)
+            ),
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+)]
+#[instrument(skip(conn, second_factor))]
+pub async fn login_second_factor(
+    DatabaseConnection(mut conn, jar, _): DatabaseConnection,
+    State(session_config): State<SessionConfig>,
+    State(throttle_config): State<ThrottleConfig>,
+    client: ClientInfo,
+    JsonOrForm(second_factor): JsonOrForm<SecondFactor>,
+) -> Result<(Jar, TypedHeader<HxRefresh>), error::Error> {
+    let challenge = second_factor
+        .challenge
+        .or_else(|| {
+            jar.get(session::COOKIE_NAME)
+                .map(|sessionid| sessionid.value().to_owned())
+        })
+        .ok_or_eyre("There is no login waiting for a second factor")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    let user_id = session::pending_user(&mut conn, &challenge)
+        .await?
+        .ok_or_eyre("Your login expired, please log in again")
+        .with_status_code(StatusCode::UNAUTHORIZED)?;
+
+    let user = User::query()
+        .filter(users::id.eq(user_id))
+        .get_result(&mut conn)
+        .await
+        .wrap_err("Failed to get user from database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let attempt = LoginAttempt::new(&throttle_config, client.ip, &user.username);
+    attempt.check(&mut conn).await?;
+
+    if !verify(&mut conn, user_id, &second_factor.code).await? {
+        attempt.failed(&mut conn).await?;
+        events::record_failed_login(&mut conn, &client, Some(user_id), &user.username).await?;
+        return Err(eyre!("Invalid code")).with_status_code(StatusCode::UNAUTHORIZED);
+    }
+    attempt.succeeded(&mut conn).await?;
+    events::record(&mut conn, &client, AuthEventKind::LoginSucceeded, user_id).await?;
+
+    session::revoke(&mut conn, &challenge).await?;
+    let token = session::create(
+        &mut conn,
+        &session_config,
+        user_id,
+        client.user_agent.as_deref(),
+    )
+    .await?;
+
+    Ok((
+        session::set_cookie(jar, &session_config, token),
+        TypedHeader(HxRefresh(true)),
+    ))
+}
@@ -1,12 +1,20 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    str::FromStr,
+    sync::Arc,
+};
+
 use axum::{
     Json,
-    extract::{Path, Query},
+    extract::{Path, Query, State},
     http::StatusCode,
 };
-use axum_extra::TypedHeader;
+use axum_extra::{TypedHeader, extract::Query as FilterQuery};
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{Context, eyre};
 use diesel::{ExpressionMethods, HasQuery, QueryDsl, prelude::*};
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use diesel_derive_enum::DbEnum;
 use sailfish::{TemplateOnce, TemplateSimple};
 use serde::{Deserialize, Serialize};
@@ -15,36 +23,60 @@ use utoipa::ToSchema;
 
 use crate::{
     Placeholder,
-    api::auth::{User, pool::DatabaseConnection},
+    api::{
+        auth::{
+            User,
+            pool::DatabaseConnection,
+            role::Role,
+            scope::{self, RequireScope},
+        },
+        platforms::PlatformRef,
+        titles::{NewTitle, Title},
+    },
     error::{self, Error, WithStatusCode},
     html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce, HtmlOrJsonSimple},
     json_or_form::JsonOrForm,
     openapi_template,
-    schema::{games, sql_types, users},
+    schema::{games, platforms, sql_types, titles, users},
+    storage::Storage,
 };
 
-#[derive(Insertable, AsChangeset, ToSchema, Deserialize, Serialize, Debug)]
-#[diesel(table_name = crate::schema::games)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+pub mod autocomplete;
+pub mod filter;
+pub mod pagination;
+pub mod photos;
+pub mod search;
+pub mod sort;
+
+use filter::GameFilter;
+use pagination::{Cursor, MAX_PAGE_SIZE, NextLink, PAGE_SIZE};
+use photos::Photo;
+use sort::{GameSort, SortOrder};
+
+/// A copy of a game to list. Its title is the one with `title_id`, or
+/// else the one with the name, publisher, year and platform, which is
+/// added to the catalog if nobody listed it before.
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
 pub struct InsertableGame {
-    name: String,
-    #[serde(skip)]
-    owned_by: i32,
-    #[diesel(treat_none_as_null = true)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
     publisher: Option<String>,
-    #[diesel(treat_none_as_null = true)]
     #[schema(minimum = 0, maximum = 65535)]
     year: Option<i16>,
-    #[diesel(treat_none_as_null = true)]
-    platform: Option<String>,
-    #[diesel(treat_none_as_null = true)]
+    /// By ID, or by name or alias
+    platform: Option<PlatformRef>,
     condition: Option<Condition>,
 }
 
-#[derive(AsChangeset, ToSchema, Deserialize, Serialize, Debug)]
-#[diesel(table_name = crate::schema::games)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+/// Changes to a copy. Changing the name, publisher, year or platform
+/// makes it a copy of the title with the changed facts, the title it
+/// was a copy of stays the same for everyone else's copies.
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
 pub struct ChangesetGame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title_id: Option<i32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     #[serde(
@@ -60,12 +92,13 @@ pub struct ChangesetGame {
         with = "::serde_with::rust::double_option"
     )]
     year: Option<Option<i16>>,
+    /// By ID, or by name or alias
     #[serde(
         default,
         skip_serializing_if = "Option::is_none",
         with = "::serde_with::rust::double_option"
     )]
-    platform: Option<Option<String>>,
+    platform: Option<Option<PlatformRef>>,
     #[serde(
         default,
         skip_serializing_if = "Option::is_none",
@@ -74,23 +107,73 @@ pub struct ChangesetGame {
     condition: Option<Option<Condition>>,
 }
 
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::games)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertableCopy {
+    title_id: i32,
+    #[diesel(skip_update)]
+    owned_by: i32,
+    #[diesel(treat_none_as_null = true)]
+    condition: Option<Condition>,
+}
+
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::games)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct ChangesetCopy {
+    title_id: Option<i32>,
+    condition: Option<Option<Condition>>,
+}
+
+/// A copy of a game someone lists. The facts of its title are also
+/// repeated next to it, as they were before there were titles, until
+/// clients have moved over to `title`.
 #[derive(HasQuery, ToSchema, Deserialize, Serialize, Debug, Default)]
 #[diesel(table_name = crate::schema::games)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
-#[diesel(base_query = games::table.inner_join(users::table))]
+#[diesel(base_query = games::table
+    .inner_join(users::table)
+    .inner_join(titles::table.left_join(platforms::table)))]
 pub struct GameModel {
     id: i32,
+    /// What this is a copy of
+    #[diesel(embed)]
+    title: Title,
+    /// Use `title.name`
+    #[schema(deprecated)]
+    #[diesel(select_expression = titles::name)]
+    #[diesel(select_expression_type = titles::name)]
     name: String,
+    /// Use `title.publisher`
+    #[schema(deprecated)]
+    #[diesel(select_expression = titles::publisher)]
+    #[diesel(select_expression_type = titles::publisher)]
     publisher: Option<String>,
-    #[schema(minimum = 0, maximum = 65535)]
+    /// Use `title.year`
+    #[schema(deprecated, minimum = 0, maximum = 65535)]
+    #[diesel(select_expression = titles::year)]
+    #[diesel(select_expression_type = titles::year)]
     year: Option<i16>,
+    /// Use `title.platform_id`
+    #[schema(deprecated)]
+    #[diesel(select_expression = titles::platform_id)]
+    #[diesel(select_expression_type = titles::platform_id)]
+    platform_id: Option<i32>,
+    /// Use `title.platform`
+    #[schema(deprecated)]
+    #[diesel(select_expression = platforms::name.nullable())]
+    #[diesel(select_expression_type = diesel::dsl::Nullable<platforms::name>)]
     platform: Option<String>,
     condition: Option<Condition>,
+    /// Hidden by a moderator, only the owner and moderators see it
+    hidden: bool,
+    updated_at: DateTime<Utc>,
     #[diesel(embed)]
     user: User,
 }
 
-#[derive(DbEnum, ToSchema, Deserialize, Serialize, Debug, PartialEq)]
+#[derive(DbEnum, ToSchema, Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 #[db_enum(existing_type_path = "sql_types::Condition")]
 pub enum Condition {
     Mint,
@@ -99,14 +182,147 @@ pub enum Condition {
     Poor,
 }
 
+impl Condition {
+    pub const ALL: [Condition; 4] = [
+        Condition::Mint,
+        Condition::Good,
+        Condition::Fair,
+        Condition::Poor,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Condition::Mint => "Mint",
+            Condition::Good => "Good",
+            Condition::Fair => "Fair",
+            Condition::Poor => "Poor",
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Condition::ALL
+            .into_iter()
+            .find(|condition| condition.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl InsertableGame {
+    /// The copy to list for `owned_by`, adding its title to the catalog
+    /// if needed
+    async fn into_copy(
+        self,
+        conn: &mut AsyncPgConnection,
+        owned_by: i32,
+    ) -> error::Result<InsertableCopy> {
+        let title_id = match (self.title_id, self.name) {
+            (Some(title_id), _) => title_id,
+            (None, Some(name)) => {
+                let platform_id = match &self.platform {
+                    Some(platform) => platform.resolve(conn).await?,
+                    None => None,
+                };
+                NewTitle {
+                    name,
+                    publisher: self.publisher,
+                    year: self.year,
+                    platform_id,
+                }
+                .find_or_add(conn)
+                .await?
+            }
+            (None, None) => {
+                return Err(eyre!("A game needs a title ID or a name"))
+                    .with_status_code(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        Ok(InsertableCopy {
+            title_id,
+            owned_by,
+            condition: self.condition,
+        })
+    }
+}
+
+impl ChangesetGame {
+    /// The changes to game `game_id`, which becomes a copy of another
+    /// title if any of its facts change
+    async fn into_changeset(
+        self,
+        conn: &mut AsyncPgConnection,
+        game_id: i32,
+    ) -> error::Result<ChangesetCopy> {
+        let title_id = match self.title_id {
+            Some(title_id) => Some(title_id),
+            None if self.name.is_none()
+                && self.publisher.is_none()
+                && self.year.is_none()
+                && self.platform.is_none() =>
+            {
+                None
+            }
+            None => {
+                let (name, publisher, year, platform_id) = games::table
+                    .inner_join(titles::table)
+                    .filter(games::id.eq(game_id))
+                    .select((
+                        titles::name,
+                        titles::publisher,
+                        titles::year,
+                        titles::platform_id,
+                    ))
+                    .get_result::<(String, Option<String>, Option<i16>, Option<i32>)>(conn)
+                    .await
+                    .optional()
+                    .wrap_err("Failed to get game")
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+                    .ok_or_else(|| eyre!("That game doesn't exist"))
+                    .with_status_code(StatusCode::NOT_FOUND)?;
+
+                let platform_id = match &self.platform {
+                    Some(Some(platform)) => platform.resolve(conn).await?,
+                    Some(None) => None,
+                    None => platform_id,
+                };
+                Some(
+                    NewTitle {
+                        name: self.name.unwrap_or(name),
+                        publisher: self.publisher.unwrap_or(publisher),
+                        year: self.year.unwrap_or(year),
+                        platform_id,
+                    }
+                    .find_or_add(conn)
+                    .await?,
+                )
+            }
+        };
+
+        Ok(ChangesetCopy {
+            title_id,
+            condition: self.condition,
+        })
+    }
+}
+
 impl Placeholder for InsertableGame {
     fn placeholder() -> Self {
         Self {
-            name: "Starfield".to_owned(),
-            owned_by: 0,
+            title_id: None,
+            name: Some("Starfield".to_owned()),
             publisher: Some("Bethesda".to_owned()),
             year: Some(2023),
-            platform: Some("PC".to_owned()),
+            platform: Some(PlatformRef::Name("PC".to_owned())),
             condition: Some(Condition::Mint),
         }
     }
@@ -115,10 +331,11 @@ impl Placeholder for InsertableGame {
 impl Placeholder for ChangesetGame {
     fn placeholder() -> Self {
         Self {
+            title_id: None,
             name: Some("Starfield".to_owned()),
             publisher: Some(Some("Bethesda".to_owned())),
             year: Some(Some(2023)),
-            platform: Some(Some("PC".to_owned())),
+            platform: Some(Some(PlatformRef::Name("PC".to_owned()))),
             condition: Some(Some(Condition::Mint)),
         }
     }
@@ -128,22 +345,173 @@ impl Placeholder for GameModel {
     fn placeholder() -> Self {
         Self {
             id: 1,
+            title: Title::placeholder(),
             name: "Starfield".to_owned(),
             publisher: Some("Bethesda".to_owned()),
             year: Some(2023),
+            platform_id: Some(42),
             platform: Some("PC".to_owned()),
             condition: Some(Condition::Mint),
+            hidden: false,
+            updated_at: Utc::now(),
             user: User::placeholder(),
         }
     }
 }
 
+/// Newest listings first, or the best matches first when searching,
+/// unless sorted otherwise
+#[derive(ToSchema, Serialize, Debug)]
+pub struct GamePage {
+    games: Vec<GameModel>,
+    /// Photos of the games on the page that have any by game ID, the
+    /// cover first
+    photos: BTreeMap<i32, Vec<Photo>>,
+    /// Pass this as `cursor` to get the next page, `null` on the last one
+    next_cursor: Option<String>,
+}
+
+impl Placeholder for GamePage {
+    fn placeholder() -> Self {
+        Self {
+            games: vec![GameModel::placeholder()],
+            photos: BTreeMap::from([(1, vec![Photo::placeholder()])]),
+            next_cursor: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct GamesQuery {
+    limit: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<GameSort>,
+    order: Option<SortOrder>,
+}
+
+impl GamesQuery {
+    /// What to sort by and which way, by relevance when searching and
+    /// else newest first unless asked otherwise
+    fn sort(&self, filter: &GameFilter) -> (GameSort, SortOrder) {
+        let sort = self.sort.unwrap_or(if filter.search().is_some() {
+            GameSort::Relevance
+        } else {
+            GameSort::Newest
+        });
+        (sort, self.order.unwrap_or(sort.default_order()))
+    }
+
+    /// Link to the page after `cursor`, with the same parameters
+    fn next_page(&self, filter: &GameFilter, cursor: &str) -> String {
+        let mut url = format!("/games?cursor={}", cursor);
+        if let Some(limit) = self.limit {
+            let _ = write!(url, "&limit={}", limit);
+        }
+        if let Some(sort) = self.sort {
+            let _ = write!(url, "&sort={}", sort.as_str());
+        }
+        if let Some(order) = self.order {
+            let _ = write!(url, "&order={}", order.as_str());
+        }
+        filter.write_query(&mut url);
+        url
+    }
+}
+
+/// Loads the page of games `query` asks for that match `filter` and
+/// the connection's user may see
+async fn load_page(
+    conn: &mut AsyncPgConnection,
+    query: &GamesQuery,
+    filter: &GameFilter,
+    user_id: Option<i32>,
+) -> error::Result<GamePage> {
+    let limit = query.limit.unwrap_or(PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let q = filter.search();
+    let (sort, order) = query.sort(filter);
+    let cursor = query.cursor.as_deref().map(Cursor::decode).transpose()?;
+
+    let games = games::table
+        .inner_join(users::table)
+        .inner_join(titles::table.left_join(platforms::table))
+        .select((GameModel::as_select(), search::rank(q)))
+        .into_boxed();
+    let mut games = sort::apply(games, sort, order, q, cursor.as_ref())?
+        // One extra to know whether there is another page
+        .limit(limit + 1);
+
+    if let Some(predicate) = filter.predicate(user_id) {
+        games = games.filter(predicate);
+    }
+
+    let mut games = games
+        .load::<(GameModel, f32)>(conn)
+        .await
+        .wrap_err("Failed to get games list")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let next_cursor = if games.len() as i64 > limit {
+        games.truncate(limit as usize);
+        games
+            .last()
+            .map(|(game, rank)| {
+                Cursor {
+                    id: game.id,
+                    key: sort.key(game, *rank),
+                }
+                .encode()
+            })
+            .transpose()?
+    } else {
+        None
+    };
+
+    let games = games.into_iter().map(|(game, _)| game).collect::<Vec<_>>();
+    let ids = games.iter().map(|game| game.id).collect::<Vec<_>>();
+
+    Ok(GamePage {
+        photos: photos::load(conn, &ids).await?,
+        games,
+        next_cursor,
+    })
+}
+
 #[derive(TemplateOnce)]
 #[template(path = "games/all_games.stpl")]
 #[template(rm_whitespace = true, rm_newline = true)]
 pub struct AllGamesTemplate {
-    games: Vec<GameModel>,
+    page: GamePage,
     user_id: i32,
+    moderator: bool,
+    /// Later pages are appended to the first, without the list around
+    /// them, the filter bar or the form to add a game
+    first_page: bool,
+    next_page: Option<String>,
+    filter: GameFilter,
+    sort: GameSort,
+    /// Only if asked for, else the sort goes its default way
+    order: Option<SortOrder>,
+}
+
+impl AllGamesTemplate {
+    fn new(page: GamePage, query: &GamesQuery, filter: GameFilter, user: Option<User>) -> Self {
+        let (user_id, moderator) = user
+            .map(|u| (u.id, u.role.can_moderate()))
+            .unwrap_or_default();
+        Self {
+            next_page: page
+                .next_cursor
+                .as_deref()
+                .map(|cursor| query.next_page(&filter, cursor)),
+            page,
+            user_id,
+            moderator,
+            first_page: query.cursor.is_none(),
+            sort: query.sort(&filter).0,
+            order: query.order,
+            filter,
+        }
+    }
 }
 
 #[derive(TemplateSimple)]
@@ -153,13 +521,23 @@ pub struct GameTemplate {
     game: GameModel,
     editing: bool,
     user_id: i32,
+    moderator: bool,
+    /// Search terms to mark in the name and publisher
+    highlight: Vec<String>,
+    photos: Vec<Photo>,
 }
 
 impl Placeholder for AllGamesTemplate {
     fn placeholder() -> Self {
         Self {
-            games: vec![GameModel::placeholder()],
+            page: GamePage::placeholder(),
             user_id: 0,
+            moderator: false,
+            first_page: true,
+            next_page: None,
+            filter: GameFilter::default(),
+            sort: GameSort::Newest,
+            order: None,
         }
     }
 }
@@ -170,23 +548,29 @@ impl Placeholder for GameTemplate {
             game: GameModel::placeholder(),
             editing: false,
             user_id: 0,
+            moderator: false,
+            highlight: Vec::new(),
+            photos: vec![Photo::placeholder()],
         }
     }
 }
 
 openapi_template!(GameTemplate, game);
-openapi_template!(AllGamesTemplate, games);
+openapi_template!(AllGamesTemplate, page);
 
 #[utoipa::path(
     get,
     path = "/games",
     tag = "Games",
-    description = "Gets all the games in the exchange list.",
+    description = "Gets a page of the games in the exchange list that match all the filters given, newest first. With `q` only the games whose name or publisher match it, also with typos, best match first. `sort` and `order` sort them otherwise. Follow `next_cursor` or the `Link` header for the next page, it keeps the filters. As HTML, pages after the first are only the games, ending in an element that loads the next page when scrolled into view.",
     responses(
         (status = OK, description = "Ok",
+            headers(
+                ("Link" = String, description = "URL of the next page with `rel=\"next\"`, left out on the last page")
+            ),
             content(
                 (inline(AllGamesTemplate) = "text/html", example = AllGamesTemplate::render_placeholder),
-                ([GameModel], example = json!([GameModel::placeholder()]))
+                (GamePage, example = GamePage::placeholder)
             )
         ),
         (status = "4XX", description = "You did something wrong",
@@ -200,29 +584,50 @@ openapi_template!(AllGamesTemplate, games);
             )
         ),
     ),
+    params(
+        ("limit" = Option<i64>, Query, description = "Games per page, 24 by default and at most 100"),
+        ("cursor" = Option<String>, Query, description = "Where to continue, the `next_cursor` of the previous page"),
+        ("q" = Option<String>, Query, description = "Words to search the name and publisher for. Quote phrases, use `or` for either word and `-` to leave a word out"),
+        ("sort" = Option<GameSort>, Query, description = "What to sort by, `relevance` when searching and `newest` otherwise. Games with the same value are sorted by ID, those without one come last"),
+        ("order" = Option<SortOrder>, Query, description = "Which way to sort. By default the best match, newest, most recently updated and best condition come first, names, years and platforms from lowest to highest"),
+        ("platform" = Option<String>, Query, description = "Only games for this platform, by name or alias, ignoring case"),
+        ("condition" = Option<Vec<Condition>>, Query, style = Form, explode, description = "Only games in one of these conditions, repeat it for more than one"),
+        ("year_min" = Option<i16>, Query, description = "Only games released in or after this year"),
+        ("year_max" = Option<i16>, Query, description = "Only games released in or before this year"),
+        ("publisher" = Option<String>, Query, description = "Only games whose publisher contains this, ignoring case"),
+        ("owner" = Option<String>, Query, description = "Only games listed by this username"),
+        ("exclude_mine" = Option<bool>, Query, description = "Leave out the games you listed yourself")
+    ),
     security(
         ("basic_auth" = []),
         ("bearer_jwt" = []),
         ("cookie_jwt" = []),
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope))]
 pub async fn get_all_games(
     DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Query(query): Query<GamesQuery>,
+    FilterQuery(filter): FilterQuery<GameFilter>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
-) -> Result<HtmlOrJsonOnce<AllGamesTemplate>, error::Error> {
-    let games = GameModel::query()
-        .load(&mut conn)
-        .await
-        .wrap_err("Failed to get updated games list")
-        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<
+    (
+        Option<TypedHeader<NextLink>>,
+        HtmlOrJsonOnce<AllGamesTemplate>,
+    ),
+    error::Error,
+> {
+    let page = load_page(&mut conn, &query, &filter, user.as_ref().map(|u| u.id)).await?;
+    let template = AllGamesTemplate::new(page, &query, filter, user);
 
-    Ok(HtmlOrJsonOnce(
-        accept,
-        AllGamesTemplate {
-            games,
-            user_id: user.map(|u| u.id).unwrap_or_default(),
-        },
+    Ok((
+        template
+            .next_page
+            .as_deref()
+            .and_then(NextLink::new)
+            .map(TypedHeader),
+        HtmlOrJsonOnce(accept, template),
     ))
 }
 
@@ -264,9 +669,10 @@ pub struct GetGameQuery {
         ("edit" = Option<bool>, Query, description = "If Accept is text/html, makes all the form fields editable if authorized")
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope))]
 pub async fn get_game(
     DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
     Query(edit): Query<GetGameQuery>,
     Path(game_id): Path<i32>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
@@ -278,13 +684,20 @@ pub async fn get_game(
         .wrap_err("Failed to get updated games list")
         .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let user_id = user.map(|u| u.id);
+    let (user_id, moderator) = user
+        .map(|u| (u.id, u.role.can_moderate()))
+        .unwrap_or_default();
     Ok(HtmlOrJsonSimple(
         accept,
         GameTemplate {
-            editing: edit.edit.unwrap_or_default() && user_id == Some(game.user.id),
-            user_id: user_id.unwrap_or_default(),
+            editing: edit.edit.unwrap_or_default()
+                && user_id != 0
+                && (user_id == game.user.id || moderator),
+            user_id,
+            moderator,
             game,
+            highlight: Vec::new(),
+            photos: photos::load_for(&mut conn, game_id).await?,
         },
     ))
 }
@@ -293,7 +706,7 @@ pub async fn get_game(
     post,
     path = "/games",
     tag = "Games",
-    description = "Add a new game to the exchange list.",
+    description = "Add a new game to the exchange list, as a copy of the title with `title_id` or of the one with the given name, publisher, year and platform, which is added to the catalog if it isn't there yet. Returns the first page of the list, which starts with it.",
     request_body(content(
         (InsertableGame, example = InsertableGame::placeholder),
         (InsertableGame = "application/x-www-form-urlencoded")
@@ -302,7 +715,7 @@ pub async fn get_game(
         (status = OK, description = "Ok",
             content(
                 (inline(AllGamesTemplate) = "text/html", example = AllGamesTemplate::render_placeholder),
-                ([GameModel], example = json!([GameModel::placeholder()]))
+                (GamePage, example = GamePage::placeholder)
             )
         ),
         (status = "4XX", description = "You did something wrong",
@@ -322,34 +735,31 @@ pub async fn get_game(
         ("cookie_jwt" = []),
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope))]
 pub async fn add_game(
     DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
-    JsonOrForm(mut new_game): JsonOrForm<InsertableGame>,
+    JsonOrForm(new_game): JsonOrForm<InsertableGame>,
 ) -> Result<HtmlOrJsonOnce<AllGamesTemplate>, error::Error> {
     if let Some(user) = user {
-        new_game.owned_by = user.id;
+        let new_copy = new_game.into_copy(&mut conn, user.id).await?;
 
         diesel::insert_into(games::table)
-            .values(new_game)
+            .values(new_copy)
             .execute(&mut conn)
             .await
             .wrap_err("Failed to insert game into database")
             .with_status_code(StatusCode::BAD_REQUEST)?;
 
-        let games = GameModel::query()
-            .load(&mut conn)
-            .await
-            .wrap_err("Failed to get updated games list")
-            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+        // The new game is the newest, so it's on the first page
+        let query = GamesQuery::default();
+        let filter = GameFilter::default();
+        let page = load_page(&mut conn, &query, &filter, Some(user.id)).await?;
 
         Ok(HtmlOrJsonOnce(
             accept,
-            AllGamesTemplate {
-                games,
-                user_id: user.id,
-            },
+            AllGamesTemplate::new(page, &query, filter, Some(user)),
         ))
     } else {
         Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED)
@@ -360,7 +770,7 @@ pub async fn add_game(
     put,
     path = "/games/{game_id}",
     tag = "Games",
-    description = "Replace all properties of a game (full update).",
+    description = "Replace all properties of a game (full update). Other facts make it a copy of another title, leaving the one it was a copy of as it is.",
     request_body(content(
         (InsertableGame, example = InsertableGame::placeholder),
         (InsertableGame = "application/x-www-form-urlencoded")
@@ -390,19 +800,24 @@ pub async fn add_game(
         ("cookie_jwt" = []),
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope))]
 pub async fn update_game(
     DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
     Path(game_id): Path<i32>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
-    JsonOrForm(mut new_game): JsonOrForm<InsertableGame>,
+    JsonOrForm(new_game): JsonOrForm<InsertableGame>,
 ) -> Result<HtmlOrJsonSimple<GameTemplate>, error::Error> {
-    let user_id = user.map(|u| u.id).unwrap_or_default();
-    new_game.owned_by = user_id;
+    // `owned_by` is skipped on update, so a moderator editing someone
+    // else's game doesn't take it over
+    let (user_id, moderator) = user
+        .map(|u| (u.id, u.role.can_moderate()))
+        .unwrap_or_default();
+    let new_copy = new_game.into_copy(&mut conn, user_id).await?;
 
     diesel::update(games::table)
         .filter(games::id.eq(game_id))
-        .set(new_game)
+        .set(new_copy)
         .execute(&mut conn)
         .await
         .wrap_err("Failed to update game in database")
@@ -421,6 +836,9 @@ pub async fn update_game(
             game: updated_game,
             editing: false,
             user_id,
+            moderator,
+            highlight: Vec::new(),
+            photos: photos::load_for(&mut conn, game_id).await?,
         },
     ))
 }
@@ -429,7 +847,7 @@ pub async fn update_game(
     patch,
     path = "/games/{game_id}",
     tag = "Games",
-    description = "Update certain properties of a game (partial update).",
+    description = "Update certain properties of a game (partial update). Changing the name, publisher, year or platform makes it a copy of the title with those facts, leaving the one it was a copy of as it is.",
     request_body(content(
         (ChangesetGame, example = ChangesetGame::placeholder),
     )),
@@ -458,16 +876,22 @@ pub async fn update_game(
         ("cookie_jwt" = []),
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope))]
 pub async fn patch_game(
     DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
     Path(game_id): Path<i32>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
     Json(changeset_game): Json<ChangesetGame>,
 ) -> Result<HtmlOrJsonSimple<GameTemplate>, error::Error> {
+    let (user_id, moderator) = user
+        .map(|u| (u.id, u.role.can_moderate()))
+        .unwrap_or_default();
+    let changeset_copy = changeset_game.into_changeset(&mut conn, game_id).await?;
+
     diesel::update(games::table)
         .filter(games::id.eq(game_id))
-        .set(changeset_game)
+        .set(changeset_copy)
         .execute(&mut conn)
         .await
         .wrap_err("Failed to update game in database")
@@ -485,7 +909,10 @@ pub async fn patch_game(
         GameTemplate {
             game: updated_game,
             editing: false,
-            user_id: user.map(|u| u.id).unwrap_or_default(),
+            user_id,
+            moderator,
+            highlight: Vec::new(),
+            photos: photos::load_for(&mut conn, game_id).await?,
         },
     ))
 }
@@ -520,18 +947,113 @@ pub async fn patch_game(
         ("cookie_jwt" = []),
     )
 )]
-#[instrument(skip(conn))]
+#[instrument(skip(conn, _scope, storage))]
 pub async fn delete_game(
     DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    State(storage): State<Arc<dyn Storage>>,
     Path(game_id): Path<i32>,
     TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
 ) -> Result<(), error::Error> {
-    diesel::delete(games::table)
+    // The rows go with the game, but the files have to be removed
+    let keys = photos::storage_keys(&mut conn, game_id).await?;
+
+    let deleted = diesel::delete(games::table)
         .filter(games::id.eq(game_id))
         .execute(&mut conn)
         .await
         .wrap_err("Failed to delete game in database")
         .with_status_code(StatusCode::BAD_REQUEST)?;
 
+    if deleted > 0 {
+        photos::remove_files(&*storage, keys).await;
+    }
+
     Ok(())
 }
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct HideGame {
+    hidden: bool,
+}
+
+impl Placeholder for HideGame {
+    fn placeholder() -> Self {
+        Self { hidden: true }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/games/{game_id}/hidden",
+    tag = "Games",
+    description = "Hide a game from the exchange list, or list it again. Only its owner and moderators can still see a hidden game. Needs the `moderator` role.",
+    request_body(content(
+        (HideGame, example = HideGame::placeholder),
+        (HideGame = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(GameTemplate) = "text/html", example = GameTemplate::render_placeholder),
+                (GameModel, example = GameModel::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("game_id" = i32, Path, description = "Game ID to hide or list")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn hide_game(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path(game_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(hide): JsonOrForm<HideGame>,
+) -> Result<HtmlOrJsonSimple<GameTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Moderator)?;
+
+    diesel::update(games::table)
+        .filter(games::id.eq(game_id))
+        .set(games::hidden.eq(hide.hidden))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update game in database")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    let updated_game = GameModel::query()
+        .filter(games::id.eq(game_id))
+        .get_result(&mut conn)
+        .await
+        .wrap_err("Failed to get updated game in database")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        GameTemplate {
+            game: updated_game,
+            editing: false,
+            user_id: user.id,
+            moderator: true,
+            highlight: Vec::new(),
+            photos: photos::load_for(&mut conn, game_id).await?,
+        },
+    ))
+}
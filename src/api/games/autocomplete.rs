@@ -0,0 +1,198 @@
+//! Suggestions for the text fields of a game
+//!
+//! Names and publishers are typed in freely, and a title differing in
+//! any of them is another title. Suggesting those of the titles in the
+//! catalog while typing, the ones with the most copies first, steers new
+//! listings towards the titles everyone else's copies share. Platforms
+//! come from their own catalog, which also finds them by their aliases.
+use axum::{extract::Query, http::StatusCode};
+use axum_extra::TypedHeader;
+use color_eyre::eyre::Context;
+use diesel::{
+    QueryableByName,
+    sql_types::{BigInt, Text},
+};
+use diesel_async::RunQueryDsl;
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::{
+        auth::{
+            pool::DatabaseConnection,
+            scope::{self, RequireScope},
+        },
+        games::filter::escape_like,
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    openapi_template,
+};
+
+/// How many suggestions are returned at most
+const SUGGESTIONS: i64 = 10;
+
+#[derive(ToSchema, Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AutocompleteField {
+    Name,
+    Platform,
+    Publisher,
+}
+
+impl AutocompleteField {
+    /// Also the column of `titles` the suggestions come from, platforms
+    /// aside
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AutocompleteField::Name => "name",
+            AutocompleteField::Platform => "platform",
+            AutocompleteField::Publisher => "publisher",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AutocompleteQuery {
+    field: AutocompleteField,
+    #[serde(default)]
+    q: String,
+}
+
+/// Values in use that start with or resemble what was typed, best
+/// first
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Suggestions {
+    field: AutocompleteField,
+    suggestions: Vec<String>,
+}
+
+impl Placeholder for Suggestions {
+    fn placeholder() -> Self {
+        Self {
+            field: AutocompleteField::Platform,
+            suggestions: vec!["PC".to_owned(), "PlayStation 5".to_owned()],
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct Suggestion {
+    #[diesel(sql_type = Text)]
+    value: String,
+}
+
+/// A `<datalist>` with the id `{field}-suggestions`, to replace the one
+/// the input points at with `list`
+#[derive(TemplateOnce)]
+#[template(path = "games/autocomplete.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct AutocompleteTemplate {
+    suggestions: Suggestions,
+}
+
+impl Placeholder for AutocompleteTemplate {
+    fn placeholder() -> Self {
+        Self {
+            suggestions: Suggestions::placeholder(),
+        }
+    }
+}
+
+openapi_template!(AutocompleteTemplate, suggestions);
+
+#[utoipa::path(
+    get,
+    path = "/autocomplete",
+    tag = "Games",
+    description = "Suggests names and publishers of the titles in the catalog. Values starting with `q` come first, then those resembling it, each the one with the most copies you can see first. Without `q` the values with the most copies. Platforms are suggested from their catalog, also when one of their aliases starts with `q`.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(AutocompleteTemplate) = "text/html", example = AutocompleteTemplate::render_placeholder),
+                (Suggestions, example = Suggestions::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("field" = AutocompleteField, Query, description = "Which field to suggest values for"),
+        ("q" = Option<String>, Query, description = "What was typed so far")
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_autocomplete(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Query(query): Query<AutocompleteQuery>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<AutocompleteTemplate>, error::Error> {
+    let q = query.q.trim();
+    let sql = match query.field {
+        AutocompleteField::Platform => "SELECT platforms.name AS value FROM platforms \
+             LEFT JOIN titles ON titles.platform_id = platforms.id \
+             LEFT JOIN games ON games.title_id = titles.id \
+             CROSS JOIN LATERAL (SELECT platforms.name ILIKE $1 OR EXISTS ( \
+                 SELECT 1 FROM unnest(platforms.aliases) AS alias WHERE alias ILIKE $1 \
+             ) AS prefix) AS matched \
+             WHERE matched.prefix OR $2 <% platforms.name \
+             GROUP BY platforms.id, matched.prefix \
+             ORDER BY matched.prefix DESC, word_similarity($2, platforms.name) DESC, \
+             count(games.id) DESC, platforms.name \
+             LIMIT $3"
+            .to_owned(),
+        AutocompleteField::Name | AutocompleteField::Publisher => {
+            // The column comes from the enum, never from the request
+            let column = query.field.as_str();
+            format!(
+                "SELECT titles.{column} AS value FROM titles \
+                 LEFT JOIN games ON games.title_id = titles.id \
+                 WHERE titles.{column} ILIKE $1 OR $2 <% titles.{column} \
+                 GROUP BY titles.{column} \
+                 ORDER BY titles.{column} ILIKE $1 DESC, \
+                 word_similarity($2, titles.{column}) DESC, \
+                 count(games.id) DESC, titles.{column} \
+                 LIMIT $3"
+            )
+        }
+    };
+
+    let suggestions = diesel::sql_query(sql)
+        .bind::<Text, _>(format!("{}%", escape_like(q)))
+        .bind::<Text, _>(q)
+        .bind::<BigInt, _>(SUGGESTIONS)
+        .load::<Suggestion>(&mut conn)
+        .await
+        .wrap_err("Failed to get suggestions")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        AutocompleteTemplate {
+            suggestions: Suggestions {
+                field: query.field,
+                suggestions: suggestions
+                    .into_iter()
+                    .map(|suggestion| suggestion.value)
+                    .collect(),
+            },
+        },
+    ))
+}
@@ -0,0 +1,146 @@
+//! Filtering the game list
+//!
+//! Every filter that is set narrows the list further. They are turned
+//! into one boxed SQL condition on games joined with their owners and
+//! titles, and those with their platforms, so the list, its pages and
+//! anything else listing games can share them.
+use diesel::{
+    BoolExpressionMethods, BoxableExpression, ExpressionMethods, NullableExpressionMethods,
+    PgTextExpressionMethods, QueryDsl,
+    helper_types::{InnerJoinQuerySource, LeftJoinQuerySource},
+    pg::Pg,
+    sql_types::{Bool, Nullable},
+};
+use serde::Deserialize;
+
+use crate::{
+    api::{
+        games::{Condition, search},
+        platforms::named,
+    },
+    schema::{games, platforms, titles, users},
+};
+
+pub type GameSource = InnerJoinQuerySource<
+    InnerJoinQuerySource<games::table, users::table>,
+    LeftJoinQuerySource<titles::table, platforms::table>,
+>;
+pub type GamePredicate = Box<dyn BoxableExpression<GameSource, Pg, SqlType = Nullable<Bool>>>;
+
+/// Read with `axum_extra::extract::Query`, which takes a repeated
+/// `condition` as a list and empty form fields as unset
+#[derive(Deserialize, Debug, Default)]
+pub struct GameFilter {
+    /// Words to search the name and publisher for, see `search`
+    pub q: Option<String>,
+    /// Name or alias of the platform, ignoring case
+    pub platform: Option<String>,
+    /// Any of these conditions
+    #[serde(default)]
+    pub condition: Vec<Condition>,
+    pub year_min: Option<i16>,
+    pub year_max: Option<i16>,
+    /// Part of the publisher, ignoring case
+    pub publisher: Option<String>,
+    /// Username of the owner
+    pub owner: Option<String>,
+    /// Leave out your own listings
+    #[serde(default)]
+    pub exclude_mine: bool,
+}
+
+/// Escapes the wildcards of a `LIKE` pattern
+pub fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+impl GameFilter {
+    /// All filters that are set, combined into one condition. `user_id`
+    /// is whoever is asking, to leave out their own listings.
+    pub fn predicate(&self, user_id: Option<i32>) -> Option<GamePredicate> {
+        let mut predicates: Vec<GamePredicate> = Vec::new();
+
+        if let Some(q) = self.search() {
+            predicates.push(search::predicate(q));
+        }
+        if let Some(platform) = &self.platform {
+            predicates.push(Box::new(
+                titles::platform_id.eq_any(
+                    platforms::table
+                        .filter(named(platform))
+                        .select(platforms::id.nullable()),
+                ),
+            ));
+        }
+        if !self.condition.is_empty() {
+            predicates.push(Box::new(games::condition.eq_any(self.condition.clone())));
+        }
+        if let Some(year_min) = self.year_min {
+            predicates.push(Box::new(titles::year.ge(year_min)));
+        }
+        if let Some(year_max) = self.year_max {
+            predicates.push(Box::new(titles::year.le(year_max)));
+        }
+        if let Some(publisher) = &self.publisher {
+            predicates.push(Box::new(
+                titles::publisher.ilike(format!("%{}%", escape_like(publisher))),
+            ));
+        }
+        if let Some(owner) = &self.owner {
+            predicates.push(Box::new(users::username.eq(owner.clone()).nullable()));
+        }
+        if self.exclude_mine
+            && let Some(user_id) = user_id
+        {
+            predicates.push(Box::new(games::owned_by.ne(user_id).nullable()));
+        }
+
+        predicates
+            .into_iter()
+            .reduce(|all, predicate| Box::new(all.and(predicate)))
+    }
+
+    /// The search, unless there's nothing to search for
+    pub fn search(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+
+    /// Appends the filters that are set to the query string of `url`
+    pub fn write_query(&self, url: &mut String) {
+        let mut pairs = Vec::new();
+        if let Some(q) = self.search() {
+            pairs.push(("q", q.to_owned()));
+        }
+        if let Some(platform) = &self.platform {
+            pairs.push(("platform", platform.clone()));
+        }
+        for condition in &self.condition {
+            pairs.push(("condition", condition.as_str().to_owned()));
+        }
+        if let Some(year_min) = self.year_min {
+            pairs.push(("year_min", year_min.to_string()));
+        }
+        if let Some(year_max) = self.year_max {
+            pairs.push(("year_max", year_max.to_string()));
+        }
+        if let Some(publisher) = &self.publisher {
+            pairs.push(("publisher", publisher.clone()));
+        }
+        if let Some(owner) = &self.owner {
+            pairs.push(("owner", owner.clone()));
+        }
+        if self.exclude_mine {
+            pairs.push(("exclude_mine", "true".to_owned()));
+        }
+
+        if let Ok(query) = serde_urlencoded::to_string(&pairs)
+            && !query.is_empty()
+        {
+            url.push('&');
+            url.push_str(&query);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Cursor pagination of the game list
+//!
+//! Pages are cut by keyset instead of offset, so listings that are
+//! added or removed while someone scrolls don't shift the next page.
+//! The cursor is the key of the last game on a page, encoded so that
+//! clients treat it as opaque and what goes into it can change. When
+//! sorted by something else, the key starts with that, see `sort`.
+use axum::http::{HeaderName, HeaderValue, StatusCode, header};
+use axum_extra::headers::Header;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use color_eyre::eyre::{Context, OptionExt};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{self, WithStatusCode};
+
+pub const PAGE_SIZE: i64 = 24;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// Where a page ended
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Cursor {
+    pub id: i32,
+    /// The value the last game was sorted by, unless that's the id or
+    /// it had none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> error::Result<String> {
+        serde_urlencoded::to_string(self)
+            .map(|cursor| URL_SAFE_NO_PAD.encode(cursor))
+            .wrap_err("Failed to encode cursor")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn decode(cursor: &str) -> error::Result<Self> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|cursor| serde_urlencoded::from_bytes(&cursor).ok())
+            .ok_or_eyre("Invalid cursor, use the `next_cursor` of the previous page")
+            .with_status_code(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// `Link` header pointing at the next page, see RFC 8288
+pub struct NextLink(HeaderValue);
+
+impl NextLink {
+    pub fn new(url: &str) -> Option<Self> {
+        HeaderValue::from_str(&format!("<{}>; rel=\"next\"", url))
+            .ok()
+            .map(Self)
+    }
+}
+
+impl Header for NextLink {
+    fn name() -> &'static HeaderName {
+        &header::LINK
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, axum_extra::headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        values
+            .find(|value| {
+                value
+                    .to_str()
+                    .is_ok_and(|value| value.ends_with("rel=\"next\""))
+            })
+            .cloned()
+            .map(NextLink)
+            .ok_or_else(axum_extra::headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([self.0.clone()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+
+    use super::*;
+
+    fn round_trip(cursor: &Cursor) -> Cursor {
+        Cursor::decode(&cursor.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn relevance_keys_round_trip_exactly() {
+        for rank in [0.0, 0.1, 0.25, 1.0 / 3.0, 1.5e-8, f32::MIN_POSITIVE, 2.0] {
+            let cursor = round_trip(&Cursor {
+                id: 7,
+                key: Some(rank.to_string()),
+            });
+
+            assert_eq!(cursor.id, 7);
+            let key = cursor.key.unwrap().parse::<f32>().unwrap();
+            assert_eq!(key.to_bits(), rank.to_bits());
+        }
+    }
+
+    #[test]
+    fn updated_keys_round_trip() {
+        let updated_at = Utc.timestamp_opt(1_792_000_000, 123_456_000).unwrap();
+        let cursor = round_trip(&Cursor {
+            id: 7,
+            key: Some(updated_at.to_rfc3339_opts(SecondsFormat::Micros, true)),
+        });
+
+        assert_eq!(
+            cursor.key.unwrap().parse::<DateTime<Utc>>().unwrap(),
+            updated_at
+        );
+    }
+
+    #[test]
+    fn null_keys_round_trip() {
+        let cursor = round_trip(&Cursor { id: 7, key: None });
+
+        assert_eq!(cursor.id, 7);
+        assert_eq!(cursor.key, None);
+    }
+
+    #[test]
+    fn keys_with_separators_round_trip() {
+        let key = "Zelda: A Link & to=the Past, 100%".to_owned();
+        let cursor = round_trip(&Cursor {
+            id: 7,
+            key: Some(key.clone()),
+        });
+
+        assert_eq!(cursor.key, Some(key));
+    }
+
+    #[test]
+    fn invalid_cursors_are_rejected() {
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("key=1")).is_err());
+    }
+}
@@ -0,0 +1,707 @@
+//! Photos of listings
+//!
+//! Every game can have a few photos in an order its owner picks, the
+//! first one being the cover. Uploads have to be JPEG, PNG or WebP and
+//! are decoded and encoded again, which turns them upright and drops
+//! EXIF data like the GPS position along with any other metadata, and
+//! a thumbnail is made for the gallery. The files go to `Storage`.
+use std::{collections::BTreeMap, io::Cursor, sync::Arc};
+
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+};
+use axum_extra::TypedHeader;
+use clap::Args;
+use color_eyre::eyre::{self, Context, eyre};
+use diesel::{
+    ExpressionMethods, HasQuery, OptionalExtension, QueryDsl,
+    prelude::*,
+    result::{DatabaseErrorKind, Error as DieselError},
+};
+use diesel_async::{
+    AsyncConnection, AsyncPgConnection, RunQueryDsl, scoped_futures::ScopedFutureExt,
+};
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, codecs::jpeg::JpegEncoder,
+};
+use sailfish::TemplateSimple;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonSimple},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{game_photos, games},
+    storage::Storage,
+};
+
+/// Formats photos can be uploaded in, with the content type they have
+/// to be sent as and the extension of their files
+const FORMATS: [(ImageFormat, &str, &str); 3] = [
+    (ImageFormat::Jpeg, "image/jpeg", "jpg"),
+    (ImageFormat::Png, "image/png", "png"),
+    (ImageFormat::WebP, "image/webp", "webp"),
+];
+
+/// Larger photos are refused before they're decoded
+const MAX_DIMENSION: u32 = 12_000;
+
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct PhotoConfig {
+    /// Largest photo that can be uploaded, in bytes
+    #[clap(long, env = "PHOTO_MAX_BYTES")]
+    #[serde(default = "default_max_bytes")]
+    pub photo_max_bytes: usize,
+    /// How many photos a game can have
+    #[clap(long, env = "PHOTOS_PER_GAME")]
+    #[serde(default = "default_photos_per_game")]
+    pub photos_per_game: u32,
+    /// Width and height thumbnails fit into, in pixels
+    #[clap(long, env = "PHOTO_THUMBNAIL_SIZE")]
+    #[serde(default = "default_thumbnail_size")]
+    pub photo_thumbnail_size: u32,
+}
+
+#[inline]
+const fn default_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+#[inline]
+const fn default_photos_per_game() -> u32 {
+    8
+}
+
+#[inline]
+const fn default_thumbnail_size() -> u32 {
+    320
+}
+
+impl Default for PhotoConfig {
+    fn default() -> Self {
+        Self {
+            photo_max_bytes: default_max_bytes(),
+            photos_per_game: default_photos_per_game(),
+            photo_thumbnail_size: default_thumbnail_size(),
+        }
+    }
+}
+
+impl PhotoConfig {
+    /// Largest request body for an upload, every photo a game can have
+    /// at once plus room for the rest of the form
+    pub fn body_limit(&self) -> usize {
+        self.photo_max_bytes * self.photos_per_game as usize + 64 * 1024
+    }
+}
+
+#[derive(HasQuery, ToSchema, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::game_photos)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Photo {
+    id: i32,
+    game_id: i32,
+    /// 0 is the cover
+    position: i32,
+    content_type: String,
+    width: i32,
+    height: i32,
+    url: String,
+    thumbnail_url: String,
+}
+
+impl Placeholder for Photo {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            game_id: 1,
+            position: 0,
+            content_type: "image/jpeg".to_owned(),
+            width: 1600,
+            height: 1200,
+            url: "/uploads/1/4f2a9c0e5b7d4e1f8a3c6b9d2e5f7a1c.jpg".to_owned(),
+            thumbnail_url: "/uploads/1/4f2a9c0e5b7d4e1f8a3c6b9d2e5f7a1c-thumbnail.jpg".to_owned(),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::game_photos)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct NewPhoto {
+    game_id: i32,
+    position: i32,
+    content_type: String,
+    width: i32,
+    height: i32,
+    storage_key: String,
+    thumbnail_key: String,
+    url: String,
+    thumbnail_url: String,
+}
+
+/// Photos of every game in `game_ids` that has any, cover first
+pub async fn load(
+    conn: &mut AsyncPgConnection,
+    game_ids: &[i32],
+) -> error::Result<BTreeMap<i32, Vec<Photo>>> {
+    let photos = Photo::query()
+        .filter(game_photos::game_id.eq_any(game_ids))
+        .order((game_photos::game_id, game_photos::position))
+        .load(conn)
+        .await
+        .wrap_err("Failed to get photos")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut by_game = BTreeMap::<i32, Vec<Photo>>::new();
+    for photo in photos {
+        by_game.entry(photo.game_id).or_default().push(photo);
+    }
+    Ok(by_game)
+}
+
+/// Photos of `game_id`, cover first
+pub async fn load_for(conn: &mut AsyncPgConnection, game_id: i32) -> error::Result<Vec<Photo>> {
+    Ok(load(conn, &[game_id])
+        .await?
+        .remove(&game_id)
+        .unwrap_or_default())
+}
+
+/// Stored files of the photos of `game_id`
+pub async fn storage_keys(
+    conn: &mut AsyncPgConnection,
+    game_id: i32,
+) -> error::Result<Vec<(String, String)>> {
+    game_photos::table
+        .filter(game_photos::game_id.eq(game_id))
+        .select((game_photos::storage_key, game_photos::thumbnail_key))
+        .load(conn)
+        .await
+        .wrap_err("Failed to get photos")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Removes the files of photos that are gone from the database. Files
+/// that can't be removed are only logged, as the photos are gone anyway.
+pub async fn remove_files(storage: &dyn Storage, keys: Vec<(String, String)>) {
+    for (storage_key, thumbnail_key) in keys {
+        for key in [storage_key, thumbnail_key] {
+            if let Err(e) = storage.delete(&key).await {
+                tracing::error!("Failed to remove photo: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Numbers the photos of a game from 0 in the order of `ids`, in one
+/// transaction so positions are only unique again once it's done
+async fn renumber(conn: &mut AsyncPgConnection, ids: &[i32]) -> error::Result<()> {
+    conn.transaction::<_, error::Error, _>(|conn| {
+        async move {
+            for (position, id) in ids.iter().enumerate() {
+                diesel::update(game_photos::table)
+                    .filter(game_photos::id.eq(id))
+                    .filter(game_photos::position.ne(position as i32))
+                    .set(game_photos::position.eq(position as i32))
+                    .execute(conn)
+                    .await
+                    .wrap_err("Failed to reorder photos")
+                    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Who owns game `game_id`, which has to exist
+async fn owner_of(conn: &mut AsyncPgConnection, game_id: i32) -> error::Result<i32> {
+    games::table
+        .filter(games::id.eq(game_id))
+        .select(games::owned_by)
+        .get_result::<i32>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get game")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(|| eyre!("That game doesn't exist"))
+        .with_status_code(StatusCode::NOT_FOUND)
+}
+
+struct ProcessedPhoto {
+    photo: Vec<u8>,
+    thumbnail: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Decodes `bytes` as `format`, turns it upright and encodes it again
+/// without any of its metadata, along with a thumbnail that fits into
+/// `thumbnail_size` pixels
+fn process(bytes: &[u8], format: ImageFormat, thumbnail_size: u32) -> eyre::Result<ProcessedPhoto> {
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let thumbnail = image.thumbnail(thumbnail_size, thumbnail_size);
+    Ok(ProcessedPhoto {
+        photo: encode(&image, format)?,
+        thumbnail: encode(&thumbnail, format)?,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> eyre::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))?,
+        // The WebP encoder only takes 8 bit RGB(A)
+        ImageFormat::WebP => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_to(&mut Cursor::new(&mut bytes), format)?,
+        _ => image.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+    Ok(bytes)
+}
+
+/// The gallery of a game
+#[derive(TemplateSimple)]
+#[template(path = "games/photos.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct PhotosTemplate {
+    game_id: i32,
+    photos: Vec<Photo>,
+    /// Show the controls to add, reorder and delete photos
+    editing: bool,
+}
+
+impl Placeholder for PhotosTemplate {
+    fn placeholder() -> Self {
+        Self {
+            game_id: 1,
+            photos: vec![Photo::placeholder()],
+            editing: false,
+        }
+    }
+}
+
+openapi_template!(PhotosTemplate, photos);
+
+#[utoipa::path(
+    get,
+    path = "/games/{game_id}/photos",
+    tag = "Games",
+    description = "Gets the photos of a game, the cover first.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PhotosTemplate) = "text/html", example = PhotosTemplate::render_placeholder),
+                ([Photo], example = json!([Photo::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("game_id" = i32, Path, description = "Game ID to get the photos of")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_photos(
+    DatabaseConnection(mut conn, _, _): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Path(game_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<PhotosTemplate>, error::Error> {
+    Ok(HtmlOrJsonSimple(
+        accept,
+        PhotosTemplate {
+            game_id,
+            photos: load_for(&mut conn, game_id).await?,
+            editing: false,
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/games/{game_id}/photos",
+    tag = "Games",
+    description = "Add photos to one of your games, after the ones it has. Send each as a `photos` part of a multipart form, as JPEG, PNG or WebP. They are stored without their metadata, like the GPS position the camera put in.",
+    request_body(content(
+        (String = "multipart/form-data")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PhotosTemplate) = "text/html", example = PhotosTemplate::render_placeholder),
+                ([Photo], example = json!([Photo::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("game_id" = i32, Path, description = "Game ID to add photos to")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, storage, multipart))]
+pub async fn upload_photos(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<PhotoConfig>,
+    Path(game_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    mut multipart: Multipart,
+) -> Result<HtmlOrJsonSimple<PhotosTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    if owner_of(&mut conn, game_id).await? != user.id {
+        return Err(eyre!("Only the owner of a game can add photos to it"))
+            .with_status_code(StatusCode::FORBIDDEN);
+    }
+
+    let existing = game_photos::table
+        .filter(game_photos::game_id.eq(game_id))
+        .count()
+        .get_result::<i64>(&mut conn)
+        .await
+        .wrap_err("Failed to count photos")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)? as usize;
+
+    // Every part is read and processed before anything is stored, so a
+    // bad one leaves the game as it was
+    let mut uploads = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .wrap_err("Invalid multipart form")
+        .with_status_code(StatusCode::BAD_REQUEST)?
+    {
+        if field.name() != Some("photos") {
+            continue;
+        }
+        if existing + uploads.len() >= config.photos_per_game as usize {
+            return Err(eyre!(
+                "A game can have at most {} photos",
+                config.photos_per_game
+            ))
+            .with_status_code(StatusCode::BAD_REQUEST);
+        }
+
+        let (format, content_type, extension) = FORMATS
+            .into_iter()
+            .find(|(_, content_type, _)| field.content_type() == Some(*content_type))
+            .ok_or_else(|| eyre!("Photos have to be JPEG, PNG or WebP"))
+            .with_status_code(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+        let bytes = field
+            .bytes()
+            .await
+            .wrap_err("Failed to read photo")
+            .with_status_code(StatusCode::BAD_REQUEST)?;
+        if bytes.len() > config.photo_max_bytes {
+            return Err(eyre!(
+                "Photos can be at most {} MiB",
+                config.photo_max_bytes / 1024 / 1024
+            ))
+            .with_status_code(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        // The content type is only what the client claims
+        if image::guess_format(&bytes).ok() != Some(format) {
+            return Err(eyre!(
+                "The photo isn't the {} it claims to be",
+                content_type
+            ))
+            .with_status_code(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+
+        let thumbnail_size = config.photo_thumbnail_size;
+        let processed =
+            tokio::task::spawn_blocking(move || process(&bytes, format, thumbnail_size))
+                .await
+                .wrap_err("Failed to process photo")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+                .wrap_err("The photo couldn't be read")
+                .with_status_code(StatusCode::BAD_REQUEST)?;
+        uploads.push((processed, content_type, extension));
+    }
+
+    if uploads.is_empty() {
+        return Err(eyre!("Send the photos as `photos` parts of the form"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    let mut new_photos = Vec::with_capacity(uploads.len());
+    let mut keys = Vec::with_capacity(uploads.len());
+    for (position, (processed, content_type, extension)) in uploads.into_iter().enumerate() {
+        let name = format!("{}/{:032x}", game_id, rand::random::<u128>());
+        let storage_key = format!("{}.{}", name, extension);
+        let thumbnail_key = format!("{}-thumbnail.{}", name, extension);
+        let stored = async {
+            storage.put(&storage_key, processed.photo).await?;
+            storage.put(&thumbnail_key, processed.thumbnail).await
+        }
+        .await;
+        keys.push((storage_key.clone(), thumbnail_key.clone()));
+        if let Err(e) = stored {
+            remove_files(&*storage, keys).await;
+            return Err(e).with_status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        new_photos.push(NewPhoto {
+            game_id,
+            position: (existing + position) as i32,
+            content_type: content_type.to_owned(),
+            width: processed.width as i32,
+            height: processed.height as i32,
+            url: storage.url(&storage_key),
+            thumbnail_url: storage.url(&thumbnail_key),
+            storage_key,
+            thumbnail_key,
+        });
+    }
+
+    // One statement, so all photos are added or none. Another upload to
+    // the same game taking the same positions fails on
+    // `game_photos_position_key`
+    let inserted = diesel::insert_into(game_photos::table)
+        .values(new_photos)
+        .execute(&mut conn)
+        .await;
+    let inserted = match inserted {
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(eyre!(
+            "Photos are being added to this game already, try again"
+        ))
+        .with_status_code(StatusCode::CONFLICT),
+        inserted => inserted
+            .wrap_err("Failed to save photos")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    if inserted.is_err() {
+        remove_files(&*storage, keys).await;
+    }
+    inserted?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        PhotosTemplate {
+            game_id,
+            photos: load_for(&mut conn, game_id).await?,
+            editing: true,
+        },
+    ))
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct MovePhoto {
+    /// Where the photo goes, 0 makes it the cover
+    position: i32,
+}
+
+impl Placeholder for MovePhoto {
+    fn placeholder() -> Self {
+        Self { position: 0 }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/games/{game_id}/photos/{photo_id}",
+    tag = "Games",
+    description = "Move a photo of one of your games to another position, the others making room. Moving it to 0 makes it the cover.",
+    request_body(content(
+        (MovePhoto, example = MovePhoto::placeholder),
+        (MovePhoto = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PhotosTemplate) = "text/html", example = PhotosTemplate::render_placeholder),
+                ([Photo], example = json!([Photo::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("game_id" = i32, Path, description = "Game ID the photo belongs to"),
+        ("photo_id" = i32, Path, description = "Photo ID to move")
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn move_photo(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path((game_id, photo_id)): Path<(i32, i32)>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(new_position): JsonOrForm<MovePhoto>,
+) -> Result<HtmlOrJsonSimple<PhotosTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    if owner_of(&mut conn, game_id).await? != user.id {
+        return Err(eyre!("Only the owner of a game can reorder its photos"))
+            .with_status_code(StatusCode::FORBIDDEN);
+    }
+
+    let mut ids = load_for(&mut conn, game_id)
+        .await?
+        .into_iter()
+        .map(|photo| photo.id)
+        .collect::<Vec<_>>();
+    let index = ids
+        .iter()
+        .position(|id| *id == photo_id)
+        .ok_or_else(|| eyre!("That photo doesn't exist"))
+        .with_status_code(StatusCode::NOT_FOUND)?;
+    ids.remove(index);
+    ids.insert(
+        (new_position.position.max(0) as usize).min(ids.len()),
+        photo_id,
+    );
+
+    renumber(&mut conn, &ids).await?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        PhotosTemplate {
+            game_id,
+            photos: load_for(&mut conn, game_id).await?,
+            editing: true,
+        },
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/games/{game_id}/photos/{photo_id}",
+    tag = "Games",
+    description = "Delete a photo of one of your games. Moderators can delete any photo.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PhotosTemplate) = "text/html", example = PhotosTemplate::render_placeholder),
+                ([Photo], example = json!([Photo::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(
+        ("game_id" = i32, Path, description = "Game ID the photo belongs to"),
+        ("photo_id" = i32, Path, description = "Photo ID to delete")
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope, storage))]
+pub async fn delete_photo(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    State(storage): State<Arc<dyn Storage>>,
+    Path((game_id, photo_id)): Path<(i32, i32)>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<PhotosTemplate>, error::Error> {
+    if user.is_none() {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    }
+
+    let keys = diesel::delete(game_photos::table)
+        .filter(game_photos::id.eq(photo_id))
+        .filter(game_photos::game_id.eq(game_id))
+        .returning((game_photos::storage_key, game_photos::thumbnail_key))
+        .get_results::<(String, String)>(&mut conn)
+        .await
+        .wrap_err("Failed to delete photo")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+    if keys.is_empty() {
+        return Err(eyre!("That photo doesn't exist or isn't yours"))
+            .with_status_code(StatusCode::NOT_FOUND);
+    }
+    remove_files(&*storage, keys).await;
+
+    // Close the gap, which moderators may do as well
+    let photos = load_for(&mut conn, game_id).await?;
+    let ids = photos.iter().map(|photo| photo.id).collect::<Vec<_>>();
+    renumber(&mut conn, &ids).await?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        PhotosTemplate {
+            game_id,
+            photos: load_for(&mut conn, game_id).await?,
+            editing: true,
+        },
+    ))
+}
@@ -0,0 +1,181 @@
+//! Searching the game list
+//!
+//! `q` is matched against the name and the publisher of the title in
+//! two ways. Whole words go through the `search` column of `titles`, a
+//! stemmed `tsvector` with a GIN index, so "legends" finds "Legend".
+//! Trigram word similarity from `pg_trgm` catches typos and words that
+//! are only partly typed. Games found either way are ranked by both
+//! together, best first.
+//!
+//! `q` takes the syntax of `websearch_to_tsquery`: quoted phrases, `or`
+//! and `-` to leave a word out.
+use diesel::{
+    BoxableExpression,
+    dsl::sql,
+    pg::Pg,
+    sql_types::{Bool, Float, Nullable, Text},
+};
+
+use crate::api::games::filter::{GamePredicate, GameSource};
+
+pub type GameRank = Box<dyn BoxableExpression<GameSource, Pg, SqlType = Float>>;
+
+/// Games whose name or publisher has the words of `q`, or something
+/// close to them
+pub fn predicate(q: &str) -> GamePredicate {
+    Box::new(
+        sql::<Nullable<Bool>>("(titles.search @@ websearch_to_tsquery('english', ")
+            .bind::<Text, _>(q.to_owned())
+            .sql(") OR ")
+            .bind::<Text, _>(q.to_owned())
+            .sql(" <% titles.name OR ")
+            .bind::<Text, _>(q.to_owned())
+            .sql(" <% titles.publisher)"),
+    )
+}
+
+/// How well a game matches `q`, higher is better. Without a search
+/// every game ranks the same.
+pub fn rank(q: Option<&str>) -> GameRank {
+    let Some(q) = q else {
+        return Box::new(sql::<Float>("0::real"));
+    };
+
+    Box::new(
+        sql::<Float>("(ts_rank(titles.search, websearch_to_tsquery('english', ")
+            .bind::<Text, _>(q.to_owned())
+            .sql(")) + word_similarity(")
+            .bind::<Text, _>(q.to_owned())
+            .sql(", titles.name) + coalesce(word_similarity(")
+            .bind::<Text, _>(q.to_owned())
+            .sql(", titles.publisher), 0) / 2)::real"),
+    )
+}
+
+/// The lowercased words of `q` to highlight, without the operators of
+/// the search syntax or the words it leaves out
+pub fn terms(q: &str) -> Vec<String> {
+    q.split_whitespace()
+        .filter(|word| !word.starts_with('-') && !word.eq_ignore_ascii_case("or"))
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Splits `text` into runs of word and non-word characters, marking the
+/// words that contain one of `terms` to be highlighted. Games found
+/// only through a typo have nothing marked.
+pub fn highlight<'a>(text: &'a str, terms: &[String]) -> Vec<(&'a str, bool)> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+
+    while let Some(first) = rest.chars().next() {
+        let word = first.is_alphanumeric();
+        let end = rest
+            .find(|c: char| c.is_alphanumeric() != word)
+            .unwrap_or(rest.len());
+        let (part, tail) = rest.split_at(end);
+
+        let marked = word && {
+            let part = part.to_lowercase();
+            terms.iter().any(|term| part.contains(term.as_str()))
+        };
+        parts.push((part, marked));
+        rest = tail;
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked<'a>(text: &'a str, q: &str) -> Vec<&'a str> {
+        highlight(text, &terms(q))
+            .into_iter()
+            .filter(|(_, marked)| *marked)
+            .map(|(part, _)| part)
+            .collect()
+    }
+
+    #[test]
+    fn terms_leave_out_excluded_words() {
+        assert_eq!(terms("zelda -link"), ["zelda"]);
+        assert_eq!(terms("-zelda"), Vec::<String>::new());
+        assert_eq!(terms("zelda - link"), ["zelda", "link"]);
+    }
+
+    #[test]
+    fn terms_leave_out_or() {
+        assert_eq!(terms("mario or luigi"), ["mario", "luigi"]);
+        assert_eq!(terms("mario OR luigi"), ["mario", "luigi"]);
+        assert_eq!(terms("mario orchestra"), ["mario", "orchestra"]);
+    }
+
+    #[test]
+    fn terms_drop_surrounding_punctuation() {
+        assert_eq!(
+            terms("\"breath of the wild\" zelda!"),
+            ["breath", "of", "the", "wild", "zelda"]
+        );
+        assert_eq!(terms("half-life"), ["half-life"]);
+        assert_eq!(terms("... !!"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn terms_fold_non_ascii_case() {
+        assert_eq!(terms("ŌKAMI Pokémon"), ["ōkami", "pokémon"]);
+        assert_eq!(terms("ÉLAN OR Straße"), ["élan", "straße"]);
+    }
+
+    #[test]
+    fn highlight_splits_words_from_the_rest() {
+        assert_eq!(
+            highlight("The Legend of Zelda: Breath", &terms("zelda")),
+            [
+                ("The", false),
+                (" ", false),
+                ("Legend", false),
+                (" ", false),
+                ("of", false),
+                (" ", false),
+                ("Zelda", true),
+                (": ", false),
+                ("Breath", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn highlight_keeps_the_text() {
+        let text = "  Half-Life 2: Episode One (2006)  ";
+        let parts = highlight(text, &terms("episode"));
+
+        assert_eq!(
+            parts.iter().map(|(part, _)| *part).collect::<String>(),
+            text
+        );
+        assert_eq!(highlight("", &terms("zelda")), Vec::<(&str, bool)>::new());
+    }
+
+    #[test]
+    fn highlight_marks_words_containing_a_term() {
+        assert_eq!(marked("Super Mario Bros.", "mar"), ["Mario"]);
+        assert_eq!(marked("Super Mario Bros.", "mario -bros"), ["Mario"]);
+        assert_eq!(
+            marked("Mario & Luigi", "mario or luigi"),
+            ["Mario", "Luigi"]
+        );
+        assert_eq!(marked("Super Mario Bros.", "zelda"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn highlight_folds_non_ascii_case() {
+        assert_eq!(marked("Ōkami HD", "ōkami"), ["Ōkami"]);
+        assert_eq!(marked("POKÉMON Red", "pokémon"), ["POKÉMON"]);
+    }
+}
@@ -0,0 +1,313 @@
+//! Sorting the game list
+//!
+//! Every sort ends in the id, so games with the same value always come
+//! in the same order and a page can start right after the last game of
+//! the one before. The cursor then holds the value that game was sorted
+//! by next to its id. Games without the value, like an unknown year,
+//! come last in both directions.
+//!
+//! Conditions are compared in grade order, which is the order of the
+//! `condition` type in the database, so descending goes from Mint to
+//! Poor rather than alphabetically.
+use std::str::FromStr;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::eyre::OptionExt;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, PgSortExpressionMethods, QueryDsl,
+    dsl::{AsSelect, InnerJoin, IntoBoxed, LeftJoin, Select},
+    pg::Pg,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::games::{
+        Condition, GameModel,
+        pagination::Cursor,
+        search::{self, GameRank},
+    },
+    error::{self, WithStatusCode},
+    schema::{games, platforms, titles, users},
+};
+
+/// The game list, each game with how well it matches the search
+pub type GameQuery = IntoBoxed<
+    'static,
+    Select<
+        InnerJoin<InnerJoin<games::table, users::table>, LeftJoin<titles::table, platforms::table>>,
+        (AsSelect<GameModel, Pg>, GameRank),
+    >,
+    Pg,
+>;
+
+#[derive(ToSchema, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GameSort {
+    /// How well a game matches `q`
+    Relevance,
+    /// When a game was listed
+    Newest,
+    /// When a game was last changed
+    Updated,
+    Name,
+    Year,
+    /// Name of the platform in the catalog
+    Platform,
+    /// Grade order, Mint being the highest
+    Condition,
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl GameSort {
+    pub const ALL: [GameSort; 7] = [
+        GameSort::Relevance,
+        GameSort::Newest,
+        GameSort::Updated,
+        GameSort::Name,
+        GameSort::Year,
+        GameSort::Platform,
+        GameSort::Condition,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GameSort::Relevance => "relevance",
+            GameSort::Newest => "newest",
+            GameSort::Updated => "updated",
+            GameSort::Name => "name",
+            GameSort::Year => "year",
+            GameSort::Platform => "platform",
+            GameSort::Condition => "condition",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GameSort::Relevance => "Best match",
+            GameSort::Newest => "Newest",
+            GameSort::Updated => "Recently updated",
+            GameSort::Name => "Name",
+            GameSort::Year => "Year",
+            GameSort::Platform => "Platform",
+            GameSort::Condition => "Condition",
+        }
+    }
+
+    /// Which way it sorts unless `order` is given: the best, newest or
+    /// most recently updated first, everything else from A to Z or
+    /// oldest to newest
+    pub fn default_order(self) -> SortOrder {
+        match self {
+            GameSort::Relevance | GameSort::Newest | GameSort::Updated | GameSort::Condition => {
+                SortOrder::Desc
+            }
+            GameSort::Name | GameSort::Year | GameSort::Platform => SortOrder::Asc,
+        }
+    }
+
+    /// The value `game` was sorted by, to continue after it. `rank` is
+    /// how well it matched the search.
+    pub fn key(self, game: &GameModel, rank: f32) -> Option<String> {
+        match self {
+            GameSort::Relevance => Some(rank.to_string()),
+            GameSort::Newest => None,
+            GameSort::Updated => Some(game.updated_at.to_rfc3339_opts(SecondsFormat::Micros, true)),
+            GameSort::Name => Some(game.title.name.clone()),
+            GameSort::Year => game.title.year.map(|year| year.to_string()),
+            GameSort::Platform => game.title.platform.clone(),
+            GameSort::Condition => game
+                .condition
+                .map(|condition| condition.as_str().to_owned()),
+        }
+    }
+}
+
+impl SortOrder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+
+    fn reversed(self) -> Self {
+        match self {
+            SortOrder::Asc => SortOrder::Desc,
+            SortOrder::Desc => SortOrder::Asc,
+        }
+    }
+}
+
+/// The value in `cursor`, `None` if the last game had none
+fn parse_key<T: FromStr>(cursor: &Cursor) -> error::Result<Option<T>> {
+    cursor
+        .key
+        .as_deref()
+        .map(|key| {
+            key.parse()
+                .ok()
+                .ok_or_eyre("Invalid cursor, use the `next_cursor` of the previous page")
+                .with_status_code(StatusCode::BAD_REQUEST)
+        })
+        .transpose()
+}
+
+/// Orders `$games` by `$column`, then the id, and if there is a cursor
+/// keeps only the games after it. The key of the cursor parses as `$key`.
+macro_rules! keyset {
+    ($games:expr, $order:expr, $cursor:expr, $column:expr, $key:ty) => {{
+        let games = match $order {
+            SortOrder::Asc => $games.order(($column.asc().nulls_last(), games::id.asc())),
+            SortOrder::Desc => $games.order(($column.desc().nulls_last(), games::id.desc())),
+        };
+        match $cursor {
+            None => games,
+            Some(cursor) => match ($order, parse_key::<$key>(cursor)?) {
+                (SortOrder::Asc, Some(key)) => games.filter(
+                    $column
+                        .gt(key.clone())
+                        .or($column.eq(key).and(games::id.gt(cursor.id)))
+                        .or($column.is_null()),
+                ),
+                (SortOrder::Desc, Some(key)) => games.filter(
+                    $column
+                        .lt(key.clone())
+                        .or($column.eq(key).and(games::id.lt(cursor.id)))
+                        .or($column.is_null()),
+                ),
+                (SortOrder::Asc, None) => {
+                    games.filter($column.is_null().and(games::id.gt(cursor.id)))
+                }
+                (SortOrder::Desc, None) => {
+                    games.filter($column.is_null().and(games::id.lt(cursor.id)))
+                }
+            },
+        }
+    }};
+}
+
+/// Orders `games` by `sort` and, given the cursor of the previous page,
+/// starts right after its last game. `q` is the search to rank by.
+pub fn apply(
+    games: GameQuery,
+    sort: GameSort,
+    order: SortOrder,
+    q: Option<&str>,
+    cursor: Option<&Cursor>,
+) -> error::Result<GameQuery> {
+    Ok(match sort {
+        GameSort::Relevance => keyset!(games, order, cursor, search::rank(q), f32),
+        GameSort::Newest => {
+            let games = match order {
+                SortOrder::Asc => games.order(games::id.asc()),
+                SortOrder::Desc => games.order(games::id.desc()),
+            };
+            match (cursor, order) {
+                (None, _) => games,
+                (Some(cursor), SortOrder::Asc) => games.filter(games::id.gt(cursor.id)),
+                (Some(cursor), SortOrder::Desc) => games.filter(games::id.lt(cursor.id)),
+            }
+        }
+        GameSort::Updated => keyset!(games, order, cursor, games::updated_at, DateTime<Utc>),
+        GameSort::Name => keyset!(games, order, cursor, titles::name, String),
+        GameSort::Year => keyset!(games, order, cursor, titles::year, i16),
+        GameSort::Platform => keyset!(games, order, cursor, platforms::name, String),
+        // Mint comes first in the database, but is the highest grade
+        GameSort::Condition => {
+            keyset!(games, order.reversed(), cursor, games::condition, Condition)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::{SelectableHelper, debug_query};
+
+    use super::*;
+    use crate::Placeholder;
+
+    fn sql(sort: GameSort, order: SortOrder, cursor: Option<&Cursor>) -> String {
+        let games = games::table
+            .inner_join(users::table)
+            .inner_join(titles::table.left_join(platforms::table))
+            .select((GameModel::as_select(), search::rank(None)))
+            .into_boxed();
+        debug_query::<Pg, _>(&apply(games, sort, order, None, cursor).unwrap()).to_string()
+    }
+
+    /// The cursor of a page that ended with a game in `condition`
+    fn cursor_after(condition: Option<Condition>) -> Cursor {
+        let mut game = GameModel::placeholder();
+        game.id = 7;
+        game.condition = condition;
+        Cursor::decode(
+            &Cursor {
+                id: game.id,
+                key: GameSort::Condition.key(&game, 0.0),
+            }
+            .encode()
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn condition_keys_parse_back() {
+        for condition in Condition::ALL {
+            let cursor = cursor_after(Some(condition));
+            assert_eq!(parse_key::<Condition>(&cursor).unwrap(), Some(condition));
+        }
+        assert_eq!(parse_key::<Condition>(&cursor_after(None)).unwrap(), None);
+    }
+
+    #[test]
+    fn condition_descending_starts_at_mint() {
+        // Mint is first in the database type
+        let sql = sql(GameSort::Condition, SortOrder::Desc, None);
+
+        assert!(sql.contains(r#"ORDER BY "games"."condition" ASC NULLS LAST, "games"."id" ASC"#));
+    }
+
+    #[test]
+    fn condition_descending_continues_down_the_grades() {
+        let cursor = cursor_after(Some(Condition::Good));
+        let sql = sql(GameSort::Condition, SortOrder::Desc, Some(&cursor));
+
+        // The next page holds the rest of Good, then Fair and Poor, then
+        // games without a condition
+        assert!(sql.contains(r#"ORDER BY "games"."condition" ASC NULLS LAST, "games"."id" ASC"#));
+        assert!(sql.contains(r#""games"."condition" > $"#));
+        assert!(sql.contains(r#""games"."id" > $"#));
+        assert!(sql.contains(r#""games"."condition" IS NULL"#));
+        assert!(sql.ends_with("-- binds: [Good, Good, 7]"));
+    }
+
+    #[test]
+    fn condition_descending_ends_among_games_without_one() {
+        let cursor = cursor_after(None);
+        let sql = sql(GameSort::Condition, SortOrder::Desc, Some(&cursor));
+
+        assert!(sql.contains(r#""games"."condition" IS NULL"#));
+        assert!(sql.contains(r#""games"."id" > $"#));
+        assert!(!sql.contains(r#""games"."condition" > $"#));
+        assert!(sql.ends_with("-- binds: [7]"));
+    }
+
+    #[test]
+    fn condition_ascending_goes_up_the_grades() {
+        let cursor = cursor_after(Some(Condition::Fair));
+        let sql = sql(GameSort::Condition, SortOrder::Asc, Some(&cursor));
+
+        assert!(sql.contains(r#"ORDER BY "games"."condition" DESC NULLS LAST, "games"."id" DESC"#));
+        assert!(sql.contains(r#""games"."condition" < $"#));
+        assert!(sql.ends_with("-- binds: [Fair, Fair, 7]"));
+    }
+}
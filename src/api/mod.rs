@@ -1,2 +1,6 @@
+pub mod admin;
 pub mod auth;
 pub mod games;
+pub mod platforms;
+pub mod titles;
+pub mod users;
@@ -0,0 +1,563 @@
+//! The catalog of platforms games are listed for
+//!
+//! Listings used to name their platform freely, so the same console
+//! turned up under a dozen spellings. Now titles point into this
+//! catalog, and listings give theirs by the ID of a platform or by its
+//! name or one of its aliases, all ignoring case. Everyone may read the
+//! catalog, only admins change it.
+use axum::{extract::Path, http::StatusCode};
+use axum_extra::TypedHeader;
+use color_eyre::eyre::{Context, eyre};
+use diesel::{
+    BoolExpressionMethods, BoxableExpression, ExpressionMethods, HasQuery, OptionalExtension,
+    PgArrayExpressionMethods, PgTextExpressionMethods, QueryDsl,
+    pg::Pg,
+    prelude::{AsChangeset, Insertable},
+    result::{DatabaseErrorKind, Error as DieselError},
+    sql_types::Bool,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sailfish::{TemplateOnce, TemplateSimple};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::{
+        auth::{
+            User,
+            pool::DatabaseConnection,
+            role::Role,
+            scope::{self, RequireScope},
+        },
+        games::filter::escape_like,
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce, HtmlOrJsonSimple},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::platforms,
+};
+
+#[derive(HasQuery, Debug)]
+#[diesel(table_name = crate::schema::platforms)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct DatabasePlatform {
+    id: i32,
+    name: String,
+    manufacturer: Option<String>,
+    generation: Option<i16>,
+    release_year: Option<i16>,
+    region: Option<String>,
+    aliases: Vec<Option<String>>,
+}
+
+#[derive(ToSchema, Serialize, Debug, Default)]
+pub struct Platform {
+    id: i32,
+    name: String,
+    manufacturer: Option<String>,
+    /// Console generation, computers have none
+    generation: Option<i16>,
+    #[schema(minimum = 0, maximum = 65535)]
+    release_year: Option<i16>,
+    /// Where it came out first
+    region: Option<String>,
+    /// Other spellings it's known by, lowercase
+    aliases: Vec<String>,
+}
+
+impl From<DatabasePlatform> for Platform {
+    fn from(platform: DatabasePlatform) -> Self {
+        Self {
+            id: platform.id,
+            name: platform.name,
+            manufacturer: platform.manufacturer,
+            generation: platform.generation,
+            release_year: platform.release_year,
+            region: platform.region,
+            aliases: platform.aliases.into_iter().flatten().collect(),
+        }
+    }
+}
+
+impl Platform {
+    /// The aliases the way they're typed into a form
+    pub fn alias_list(&self) -> String {
+        self.aliases.join(", ")
+    }
+}
+
+impl Placeholder for Platform {
+    fn placeholder() -> Self {
+        Self {
+            id: 17,
+            name: "PlayStation".to_owned(),
+            manufacturer: Some("Sony".to_owned()),
+            generation: Some(5),
+            release_year: Some(1994),
+            region: Some("JP".to_owned()),
+            aliases: vec!["ps1".to_owned(), "psx".to_owned()],
+        }
+    }
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct NewPlatform {
+    name: String,
+    manufacturer: Option<String>,
+    generation: Option<i16>,
+    #[schema(minimum = 0, maximum = 65535)]
+    release_year: Option<i16>,
+    region: Option<String>,
+    /// Other spellings it's known by, matched ignoring case. In a form,
+    /// separate them with commas.
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+impl Placeholder for NewPlatform {
+    fn placeholder() -> Self {
+        Self {
+            name: "PlayStation".to_owned(),
+            manufacturer: Some("Sony".to_owned()),
+            generation: Some(5),
+            release_year: Some(1994),
+            region: Some("JP".to_owned()),
+            aliases: vec!["ps1".to_owned(), "psx".to_owned()],
+        }
+    }
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::platforms)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct InsertablePlatform {
+    name: String,
+    #[diesel(treat_none_as_null = true)]
+    manufacturer: Option<String>,
+    #[diesel(treat_none_as_null = true)]
+    generation: Option<i16>,
+    #[diesel(treat_none_as_null = true)]
+    release_year: Option<i16>,
+    #[diesel(treat_none_as_null = true)]
+    region: Option<String>,
+    aliases: Vec<Option<String>>,
+}
+
+impl TryFrom<NewPlatform> for InsertablePlatform {
+    type Error = error::Error;
+
+    fn try_from(platform: NewPlatform) -> Result<Self, Self::Error> {
+        let name = platform.name.trim();
+        if name.is_empty() {
+            return Err(eyre!("A platform needs a name")).with_status_code(StatusCode::BAD_REQUEST);
+        }
+
+        let mut aliases = platform
+            .aliases
+            .iter()
+            .flat_map(|aliases| aliases.split(','))
+            .map(|alias| alias.trim().to_lowercase())
+            .filter(|alias| !alias.is_empty())
+            .collect::<Vec<_>>();
+        aliases.sort();
+        aliases.dedup();
+
+        Ok(Self {
+            name: name.to_owned(),
+            manufacturer: platform.manufacturer,
+            generation: platform.generation,
+            release_year: platform.release_year,
+            region: platform.region,
+            aliases: aliases.into_iter().map(Some).collect(),
+        })
+    }
+}
+
+/// A platform by its ID, or by its name or one of its aliases
+#[derive(ToSchema, Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PlatformRef {
+    Id(i32),
+    Name(String),
+}
+
+impl PlatformRef {
+    /// The ID of the platform, `None` for an empty name
+    pub async fn resolve(&self, conn: &mut AsyncPgConnection) -> error::Result<Option<i32>> {
+        let name = match self {
+            PlatformRef::Id(id) => return Ok(Some(*id)),
+            PlatformRef::Name(name) => name.trim(),
+        };
+        if name.is_empty() {
+            return Ok(None);
+        }
+
+        platforms::table
+            .filter(named(name))
+            .select(platforms::id)
+            .order(platforms::id)
+            .first::<i32>(conn)
+            .await
+            .optional()
+            .wrap_err("Failed to look up platform")
+            .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or_else(|| eyre!("There is no platform called `{}`", name))
+            .with_status_code(StatusCode::BAD_REQUEST)
+            .map(Some)
+    }
+}
+
+/// Platforms called `name` or known by it, ignoring case
+pub fn named(name: &str) -> Box<dyn BoxableExpression<platforms::table, Pg, SqlType = Bool>> {
+    Box::new(
+        platforms::name
+            .ilike(escape_like(name))
+            .or(platforms::aliases.contains(vec![Some(name.to_lowercase())])),
+    )
+}
+
+/// The whole catalog, editable for admins
+#[derive(TemplateOnce)]
+#[template(path = "platforms.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct PlatformsTemplate {
+    platforms: Vec<Platform>,
+    admin: bool,
+}
+
+impl Placeholder for PlatformsTemplate {
+    fn placeholder() -> Self {
+        Self {
+            platforms: vec![Platform::placeholder()],
+            admin: false,
+        }
+    }
+}
+
+openapi_template!(PlatformsTemplate, platforms);
+
+/// A row of the catalog
+#[derive(TemplateSimple)]
+#[template(path = "platform.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct PlatformTemplate {
+    platform: Platform,
+    admin: bool,
+}
+
+impl Placeholder for PlatformTemplate {
+    fn placeholder() -> Self {
+        Self {
+            platform: Platform::placeholder(),
+            admin: false,
+        }
+    }
+}
+
+openapi_template!(PlatformTemplate, platform);
+
+async fn platforms_template(
+    conn: &mut AsyncPgConnection,
+    user: Option<&User>,
+) -> error::Result<PlatformsTemplate> {
+    let platforms = DatabasePlatform::query()
+        .order(platforms::name)
+        .load(conn)
+        .await
+        .wrap_err("Failed to get platforms")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(PlatformsTemplate {
+        platforms: platforms.into_iter().map(Platform::from).collect(),
+        admin: user.is_some_and(|user| user.role == Role::Admin),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/platforms",
+    tag = "Platforms",
+    description = "List the platforms games can be listed for, by name.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PlatformsTemplate) = "text/html", example = PlatformsTemplate::render_placeholder),
+                ([Platform], example = json!([Platform::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_platforms(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<PlatformsTemplate>, error::Error> {
+    Ok(HtmlOrJsonOnce(
+        accept,
+        platforms_template(&mut conn, user.as_ref()).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/platforms/{platform_id}",
+    tag = "Platforms",
+    description = "Gets a specific platform.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PlatformTemplate) = "text/html", example = PlatformTemplate::render_placeholder),
+                (Platform, example = Platform::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("platform_id" = i32, Path, description = "Platform ID to retrieve")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_platform(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Path(platform_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<PlatformTemplate>, error::Error> {
+    let platform = DatabasePlatform::query()
+        .filter(platforms::id.eq(platform_id))
+        .get_result(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get platform")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(|| eyre!("That platform doesn't exist"))
+        .with_status_code(StatusCode::NOT_FOUND)?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        PlatformTemplate {
+            platform: platform.into(),
+            admin: user.is_some_and(|user| user.role == Role::Admin),
+        },
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/platforms",
+    tag = "Platforms",
+    description = "Add a platform to the catalog. Returns the whole catalog. Needs the `admin` role.",
+    request_body(content(
+        (NewPlatform, example = NewPlatform::placeholder),
+        (NewPlatform = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PlatformsTemplate) = "text/html", example = PlatformsTemplate::render_placeholder),
+                ([Platform], example = json!([Platform::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn add_platform(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(new_platform): JsonOrForm<NewPlatform>,
+) -> Result<HtmlOrJsonOnce<PlatformsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    diesel::insert_into(platforms::table)
+        .values(InsertablePlatform::try_from(new_platform)?)
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to add platform")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        platforms_template(&mut conn, Some(&user)).await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/platforms/{platform_id}",
+    tag = "Platforms",
+    description = "Replace all properties of a platform. Returns the whole catalog. Needs the `admin` role.",
+    request_body(content(
+        (NewPlatform, example = NewPlatform::placeholder),
+        (NewPlatform = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PlatformsTemplate) = "text/html", example = PlatformsTemplate::render_placeholder),
+                ([Platform], example = json!([Platform::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("platform_id" = i32, Path, description = "Platform ID to update")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn update_platform(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path(platform_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(new_platform): JsonOrForm<NewPlatform>,
+) -> Result<HtmlOrJsonOnce<PlatformsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let updated = diesel::update(platforms::table)
+        .filter(platforms::id.eq(platform_id))
+        .set(InsertablePlatform::try_from(new_platform)?)
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to update platform")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    if updated == 0 {
+        return Err(eyre!("That platform doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        platforms_template(&mut conn, Some(&user)).await?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/platforms/{platform_id}",
+    tag = "Platforms",
+    description = "Remove a platform from the catalog, unless titles in the catalog still use it. Returns the whole catalog. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(PlatformsTemplate) = "text/html", example = PlatformsTemplate::render_placeholder),
+                ([Platform], example = json!([Platform::placeholder()]))
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("platform_id" = i32, Path, description = "Platform ID to delete")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_platform(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path(platform_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<PlatformsTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let deleted = match diesel::delete(platforms::table)
+        .filter(platforms::id.eq(platform_id))
+        .execute(&mut conn)
+        .await
+    {
+        Err(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            return Err(eyre!("Titles in the catalog still use that platform"))
+                .with_status_code(StatusCode::CONFLICT);
+        }
+        result => result
+            .wrap_err("Failed to delete platform")
+            .with_status_code(StatusCode::BAD_REQUEST)?,
+    };
+
+    if deleted == 0 {
+        return Err(eyre!("That platform doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        platforms_template(&mut conn, Some(&user)).await?,
+    ))
+}
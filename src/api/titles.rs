@@ -0,0 +1,536 @@
+//! The catalog of games, apart from the copies listed of them
+//!
+//! A title is what a game is: its name, publisher, year and platform.
+//! Every listing is a copy of a title, owned by someone and in some
+//! condition, so everyone's copies of the same game share one page
+//! showing how many there are and in which condition. A title is added
+//! along with the first copy of it, later copies find it ignoring case.
+//! Only admins correct titles or remove those nobody lists anymore.
+use std::collections::BTreeSet;
+
+use axum::{extract::Path, http::StatusCode};
+use axum_extra::TypedHeader;
+use color_eyre::eyre::{Context, OptionExt, eyre};
+use diesel::{
+    ExpressionMethods, HasQuery, NullableExpressionMethods, OptionalExtension,
+    PgSortExpressionMethods, QueryDsl, QueryableByName,
+    prelude::AsChangeset,
+    result::{DatabaseErrorKind, Error as DieselError},
+    sql_types::{Integer, Nullable, SmallInt, Text},
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sailfish::TemplateOnce;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::{
+        auth::{
+            User,
+            pool::DatabaseConnection,
+            role::Role,
+            scope::{self, RequireScope},
+        },
+        games::Condition,
+        platforms::PlatformRef,
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonOnce},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{games, platforms, titles, users},
+};
+
+#[derive(HasQuery, ToSchema, Deserialize, Serialize, Clone, Debug, Default)]
+#[diesel(table_name = crate::schema::titles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(base_query = titles::table.left_join(platforms::table))]
+pub struct Title {
+    pub id: i32,
+    pub name: String,
+    pub publisher: Option<String>,
+    #[schema(minimum = 0, maximum = 65535)]
+    pub year: Option<i16>,
+    /// ID of the platform in the catalog
+    pub platform_id: Option<i32>,
+    /// Name of the platform in the catalog
+    #[diesel(select_expression = platforms::name.nullable())]
+    #[diesel(select_expression_type = diesel::dsl::Nullable<platforms::name>)]
+    pub platform: Option<String>,
+}
+
+impl Title {
+    /// Publisher, year and platform, those that are known
+    pub fn details(&self) -> String {
+        [
+            self.publisher.clone(),
+            self.year.map(|year| year.to_string()),
+            self.platform.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+impl Placeholder for Title {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            name: "Starfield".to_owned(),
+            publisher: Some("Bethesda".to_owned()),
+            year: Some(2023),
+            platform_id: Some(42),
+            platform: Some("PC".to_owned()),
+        }
+    }
+}
+
+/// The facts of a title, to find it by or add it with
+pub struct NewTitle {
+    pub name: String,
+    pub publisher: Option<String>,
+    pub year: Option<i16>,
+    pub platform_id: Option<i32>,
+}
+
+/// Corrected facts of a title, which every copy of it shows from then on
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct UpdateTitle {
+    name: String,
+    publisher: Option<String>,
+    #[schema(minimum = 0, maximum = 65535)]
+    year: Option<i16>,
+    /// By ID, or by name or alias
+    platform: Option<PlatformRef>,
+}
+
+impl Placeholder for UpdateTitle {
+    fn placeholder() -> Self {
+        Self {
+            name: "Starfield".to_owned(),
+            publisher: Some("Bethesda".to_owned()),
+            year: Some(2023),
+            platform: Some(PlatformRef::Name("PC".to_owned())),
+        }
+    }
+}
+
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::titles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct ChangesetTitle {
+    name: String,
+    #[diesel(treat_none_as_null = true)]
+    publisher: Option<String>,
+    #[diesel(treat_none_as_null = true)]
+    year: Option<i16>,
+    #[diesel(treat_none_as_null = true)]
+    platform_id: Option<i32>,
+}
+
+impl UpdateTitle {
+    async fn into_changeset(self, conn: &mut AsyncPgConnection) -> error::Result<ChangesetTitle> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(eyre!("A game needs a name")).with_status_code(StatusCode::BAD_REQUEST);
+        }
+        let platform_id = match &self.platform {
+            Some(platform) => platform.resolve(conn).await?,
+            None => None,
+        };
+
+        Ok(ChangesetTitle {
+            name: name.to_owned(),
+            publisher: self
+                .publisher
+                .map(|publisher| publisher.trim().to_owned())
+                .filter(|publisher| !publisher.is_empty()),
+            year: self.year,
+            platform_id,
+        })
+    }
+}
+
+#[derive(QueryableByName)]
+struct TitleId {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+}
+
+impl NewTitle {
+    /// The ID of the title with these facts, ignoring case, added if
+    /// there is none yet
+    pub async fn find_or_add(self, conn: &mut AsyncPgConnection) -> error::Result<i32> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(eyre!("A game needs a name")).with_status_code(StatusCode::BAD_REQUEST);
+        }
+        let publisher = self
+            .publisher
+            .as_deref()
+            .map(str::trim)
+            .filter(|publisher| !publisher.is_empty());
+
+        // The same as `titles_identity_idx`, which makes the insert do
+        // nothing if the title is there already
+        diesel::sql_query(
+            "WITH added AS ( \
+                 INSERT INTO titles (name, publisher, year, platform_id) \
+                 VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT DO NOTHING \
+                 RETURNING id \
+             ) \
+             SELECT id FROM added \
+             UNION ALL \
+             SELECT id FROM titles \
+             WHERE lower(name) = lower($1) \
+             AND lower(coalesce(publisher, '')) = lower(coalesce($2, '')) \
+             AND coalesce(year, -1) = coalesce($3, -1) \
+             AND coalesce(platform_id, 0) = coalesce($4, 0) \
+             LIMIT 1",
+        )
+        .bind::<Text, _>(name)
+        .bind::<Nullable<Text>, _>(publisher)
+        .bind::<Nullable<SmallInt>, _>(self.year)
+        .bind::<Nullable<Integer>, _>(self.platform_id)
+        .get_result::<TitleId>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to add title")
+        .with_status_code(StatusCode::BAD_REQUEST)?
+        // Only if someone else is adding it right now
+        .ok_or_eyre("The title is being added, try again")
+        .with_status_code(StatusCode::CONFLICT)
+        .map(|title| title.id)
+    }
+}
+
+/// A copy of a title someone lists
+#[derive(HasQuery, ToSchema, Serialize, Debug)]
+#[diesel(table_name = crate::schema::games)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(base_query = games::table.inner_join(users::table))]
+pub struct TitleCopy {
+    /// Game ID of the listing
+    id: i32,
+    condition: Option<Condition>,
+    /// Hidden by a moderator, only the owner and moderators see it
+    hidden: bool,
+    #[diesel(embed)]
+    user: User,
+}
+
+impl Placeholder for TitleCopy {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            condition: Some(Condition::Mint),
+            hidden: false,
+            user: User::placeholder(),
+        }
+    }
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct ConditionCount {
+    /// `null` for copies nobody graded
+    condition: Option<Condition>,
+    copies: usize,
+}
+
+/// Of the copies you can see
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Availability {
+    copies: usize,
+    /// How many people list a copy
+    owners: usize,
+    /// Copies in each condition, the best first, leaving out those no
+    /// copy is in
+    conditions: Vec<ConditionCount>,
+}
+
+impl Availability {
+    fn of(copies: &[TitleCopy]) -> Self {
+        let conditions = Condition::ALL
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .map(|condition| ConditionCount {
+                condition,
+                copies: copies
+                    .iter()
+                    .filter(|copy| copy.condition == condition)
+                    .count(),
+            })
+            .filter(|count| count.copies > 0)
+            .collect();
+
+        Self {
+            copies: copies.len(),
+            owners: copies
+                .iter()
+                .map(|copy| copy.user.id)
+                .collect::<BTreeSet<_>>()
+                .len(),
+            conditions,
+        }
+    }
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct TitlePage {
+    title: Title,
+    availability: Availability,
+    /// The best condition first
+    copies: Vec<TitleCopy>,
+}
+
+impl Placeholder for TitlePage {
+    fn placeholder() -> Self {
+        let copies = vec![TitleCopy::placeholder()];
+        Self {
+            title: Title::placeholder(),
+            availability: Availability::of(&copies),
+            copies,
+        }
+    }
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "title.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct TitleTemplate {
+    page: TitlePage,
+    /// Offer to correct the title
+    admin: bool,
+}
+
+impl Placeholder for TitleTemplate {
+    fn placeholder() -> Self {
+        Self {
+            page: TitlePage::placeholder(),
+            admin: false,
+        }
+    }
+}
+
+openapi_template!(TitleTemplate, page);
+
+/// Title `title_id` with the copies of it the connection's user may see
+async fn title_template(
+    conn: &mut AsyncPgConnection,
+    title_id: i32,
+    user: Option<&User>,
+) -> error::Result<TitleTemplate> {
+    let title = Title::query()
+        .filter(titles::id.eq(title_id))
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get title")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(|| eyre!("That title doesn't exist"))
+        .with_status_code(StatusCode::NOT_FOUND)?;
+
+    // Filtered by the row level security policies of games
+    let copies = TitleCopy::query()
+        .filter(games::title_id.eq(title_id))
+        .order((games::condition.asc().nulls_last(), games::id))
+        .load(conn)
+        .await
+        .wrap_err("Failed to get copies")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(TitleTemplate {
+        page: TitlePage {
+            title,
+            availability: Availability::of(&copies),
+            copies,
+        },
+        admin: user.is_some_and(|user| user.role == Role::Admin),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/titles/{title_id}",
+    tag = "Games",
+    description = "Gets a title of the catalog along with the copies of it you can see and how many there are in each condition.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TitleTemplate) = "text/html", example = TitleTemplate::render_placeholder),
+                (TitlePage, example = TitlePage::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("title_id" = i32, Path, description = "Title ID to retrieve")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_title(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Path(title_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonOnce<TitleTemplate>, error::Error> {
+    Ok(HtmlOrJsonOnce(
+        accept,
+        title_template(&mut conn, title_id, user.as_ref()).await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/titles/{title_id}",
+    tag = "Games",
+    description = "Correct the facts of a title, for every copy of it. Fails if another title has the same facts already. Needs the `admin` role.",
+    request_body(content(
+        (UpdateTitle, example = UpdateTitle::placeholder),
+        (UpdateTitle = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(TitleTemplate) = "text/html", example = TitleTemplate::render_placeholder),
+                (TitlePage, example = TitlePage::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("title_id" = i32, Path, description = "Title ID to update")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn update_title(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path(title_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(changes): JsonOrForm<UpdateTitle>,
+) -> Result<HtmlOrJsonOnce<TitleTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let changeset = changes.into_changeset(&mut conn).await?;
+    let updated = match diesel::update(titles::table)
+        .filter(titles::id.eq(title_id))
+        .set(changeset)
+        .execute(&mut conn)
+        .await
+    {
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return Err(eyre!(
+                "Another title has the same name, publisher, year and platform"
+            ))
+            .with_status_code(StatusCode::CONFLICT);
+        }
+        result => result
+            .wrap_err("Failed to update title")
+            .with_status_code(StatusCode::BAD_REQUEST)?,
+    };
+
+    if updated == 0 {
+        return Err(eyre!("That title doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(HtmlOrJsonOnce(
+        accept,
+        title_template(&mut conn, title_id, Some(&user)).await?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/titles/{title_id}",
+    tag = "Games",
+    description = "Remove a title from the catalog, unless copies of it are still listed. Needs the `admin` role.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (String = "text/html", example = ""),
+                ((), example = "")
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("title_id" = i32, Path, description = "Title ID to delete")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn delete_title(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesWrite>,
+    Path(title_id): Path<i32>,
+) -> Result<(), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+    user.require_role(Role::Admin)?;
+
+    let deleted = match diesel::delete(titles::table)
+        .filter(titles::id.eq(title_id))
+        .execute(&mut conn)
+        .await
+    {
+        Err(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            return Err(eyre!("Copies of that title are still listed"))
+                .with_status_code(StatusCode::CONFLICT);
+        }
+        result => result
+            .wrap_err("Failed to delete title")
+            .with_status_code(StatusCode::BAD_REQUEST)?,
+    };
+
+    if deleted == 0 {
+        return Err(eyre!("That title doesn't exist")).with_status_code(StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
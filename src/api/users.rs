@@ -0,0 +1,524 @@
+//! Profiles
+//!
+//! A profile shows the username, join date, bio and listing count of
+//! an account. The street address is only shown to those its owner
+//! shares it with: nobody, their trade partners or everyone. That is
+//! up to the row level security policies on `street_addresses`, so
+//! the handlers here just select it and get nothing when they may not.
+//!
+//! There are no trades to derive trade partners from yet, so users
+//! pick who they share their address with from the other's profile.
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use axum_extra::TypedHeader;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, eyre};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, HasQuery, OptionalExtension, QueryDsl, dsl::exists,
+    prelude::AsChangeset,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_derive_enum::DbEnum;
+use sailfish::TemplateSimple;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::auth::{
+        pool::DatabaseConnection,
+        scope::{self, RequireScope},
+    },
+    error::{self, Error, WithStatusCode},
+    html_or_json::{HtmlOrJsonHeader, HtmlOrJsonSimple},
+    json_or_form::JsonOrForm,
+    openapi_template,
+    schema::{games, sql_types, street_addresses, trade_partners, users},
+};
+
+pub mod export;
+
+const MAX_BIO_LENGTH: usize = 1000;
+
+/// Who may see the street address of an account
+#[derive(DbEnum, ToSchema, Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[db_enum(existing_type_path = "sql_types::AddressVisibility")]
+#[serde(rename_all = "snake_case")]
+pub enum AddressVisibility {
+    #[default]
+    Nobody,
+    /// Users the owner picked as trade partners
+    TradePartners,
+    /// Every logged in user
+    Everyone,
+}
+
+impl AddressVisibility {
+    pub const ALL: [AddressVisibility; 3] = [
+        AddressVisibility::Nobody,
+        AddressVisibility::TradePartners,
+        AddressVisibility::Everyone,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AddressVisibility::Nobody => "nobody",
+            AddressVisibility::TradePartners => "trade_partners",
+            AddressVisibility::Everyone => "everyone",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AddressVisibility::Nobody => "Nobody",
+            AddressVisibility::TradePartners => "Trade partners",
+            AddressVisibility::Everyone => "Everyone",
+        }
+    }
+}
+
+#[derive(HasQuery, Debug)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct DatabaseProfile {
+    id: i32,
+    username: String,
+    bio: Option<String>,
+    address_visibility: AddressVisibility,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Profile {
+    id: i32,
+    username: String,
+    bio: Option<String>,
+    joined_at: DateTime<Utc>,
+    /// How many of their listings you can see
+    listings: i64,
+    /// Only there if they share it with you
+    #[serde(skip_serializing_if = "Option::is_none")]
+    street_address: Option<String>,
+    address_visibility: AddressVisibility,
+    /// Whether you share your address with them
+    trade_partner: bool,
+}
+
+impl Placeholder for Profile {
+    fn placeholder() -> Self {
+        Self {
+            id: 1,
+            username: "johndoe".to_owned(),
+            bio: Some("Collecting everything Sega since 1991".to_owned()),
+            joined_at: Utc::now(),
+            listings: 12,
+            street_address: Some("1 Infinite Loop, Cupertino".to_owned()),
+            address_visibility: AddressVisibility::TradePartners,
+            trade_partner: false,
+        }
+    }
+}
+
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+pub struct UpdateProfile {
+    /// Empty to remove it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bio: Option<String>,
+    /// Empty to remove it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    street_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address_visibility: Option<AddressVisibility>,
+}
+
+impl Placeholder for UpdateProfile {
+    fn placeholder() -> Self {
+        Self {
+            bio: Some("Collecting everything Sega since 1991".to_owned()),
+            street_address: Some("1 Infinite Loop, Cupertino".to_owned()),
+            address_visibility: Some(AddressVisibility::TradePartners),
+        }
+    }
+}
+
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct ProfileChangeset {
+    bio: Option<Option<String>>,
+    address_visibility: Option<AddressVisibility>,
+}
+
+#[derive(TemplateSimple)]
+#[template(path = "profile.stpl")]
+#[template(rm_whitespace = true, rm_newline = true)]
+pub struct ProfileTemplate {
+    profile: Profile,
+    editing: bool,
+    user_id: i32,
+}
+
+impl Placeholder for ProfileTemplate {
+    fn placeholder() -> Self {
+        Self {
+            profile: Profile::placeholder(),
+            editing: false,
+            user_id: 0,
+        }
+    }
+}
+
+openapi_template!(ProfileTemplate, profile);
+
+/// Loads the profile of `user_id` as `viewer_id` gets to see it
+async fn profile(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    viewer_id: i32,
+) -> error::Result<Profile> {
+    let user = DatabaseProfile::query()
+        .filter(users::id.eq(user_id))
+        // Accounts pending deletion are gone for everyone but their owner
+        .filter(users::delete_after.is_null().or(users::id.eq(viewer_id)))
+        .get_result(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get profile")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or_else(|| eyre!("That user doesn't exist"))
+        .with_status_code(StatusCode::NOT_FOUND)?;
+
+    let listings = games::table
+        .filter(games::owned_by.eq(user_id))
+        .count()
+        .get_result::<i64>(conn)
+        .await
+        .wrap_err("Failed to count listings")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Filtered by the row level security policies
+    let street_address = street_addresses::table
+        .filter(street_addresses::user_id.eq(user_id))
+        .select(street_addresses::street_address)
+        .get_result::<String>(conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get street address")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let trade_partner = diesel::select(exists(
+        trade_partners::table
+            .filter(trade_partners::user_id.eq(viewer_id))
+            .filter(trade_partners::partner_id.eq(user_id)),
+    ))
+    .get_result::<bool>(conn)
+    .await
+    .wrap_err("Failed to get trade partners")
+    .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Profile {
+        id: user.id,
+        username: user.username,
+        bio: user.bio,
+        joined_at: user.created_at,
+        listings,
+        street_address,
+        address_visibility: user.address_visibility,
+        trade_partner,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetProfileQuery {
+    edit: Option<bool>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{user_id}",
+    tag = "Users",
+    description = "Gets the profile of a user. The street address is only included if they share it with you.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(ProfileTemplate) = "text/html", example = ProfileTemplate::render_placeholder),
+                (Profile, example = Profile::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    ),
+    params(
+        ("user_id" = i32, Path, description = "User ID to get the profile of"),
+        ("edit" = Option<bool>, Query, description = "If Accept is text/html and it's your own profile, makes the form fields editable")
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn get_profile(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::GamesRead>,
+    Query(edit): Query<GetProfileQuery>,
+    Path(user_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<ProfileTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        ProfileTemplate {
+            profile: profile(&mut conn, user_id, user.id).await?,
+            editing: edit.edit.unwrap_or_default() && user_id == user.id,
+            user_id: user.id,
+        },
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/me",
+    tag = "Users",
+    description = "Edit your own profile. Fields that are left out stay as they are.",
+    request_body(content(
+        (UpdateProfile, example = UpdateProfile::placeholder),
+        (UpdateProfile = "application/x-www-form-urlencoded")
+    )),
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(ProfileTemplate) = "text/html", example = ProfileTemplate::render_placeholder),
+                (Profile, example = Profile::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn update_profile(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+    JsonOrForm(update): JsonOrForm<UpdateProfile>,
+) -> Result<HtmlOrJsonSimple<ProfileTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let bio = update.bio.map(|bio| {
+        let bio = bio.trim();
+        (!bio.is_empty()).then(|| bio.to_owned())
+    });
+    if bio
+        .as_ref()
+        .flatten()
+        .is_some_and(|bio| bio.chars().count() > MAX_BIO_LENGTH)
+    {
+        return Err(eyre!("Keep your bio under {} characters", MAX_BIO_LENGTH))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    if bio.is_some() || update.address_visibility.is_some() {
+        diesel::update(users::table)
+            .filter(users::id.eq(user.id))
+            .set(ProfileChangeset {
+                bio,
+                address_visibility: update.address_visibility,
+            })
+            .execute(&mut conn)
+            .await
+            .wrap_err("Failed to update profile")
+            .with_status_code(StatusCode::BAD_REQUEST)?;
+    }
+
+    match update.street_address.as_deref().map(str::trim) {
+        None => {}
+        Some("") => {
+            diesel::delete(street_addresses::table)
+                .filter(street_addresses::user_id.eq(user.id))
+                .execute(&mut conn)
+                .await
+                .wrap_err("Failed to remove street address")
+                .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+        Some(street_address) => {
+            diesel::insert_into(street_addresses::table)
+                .values((
+                    street_addresses::user_id.eq(user.id),
+                    street_addresses::street_address.eq(street_address),
+                ))
+                .on_conflict(street_addresses::user_id)
+                .do_update()
+                .set(street_addresses::street_address.eq(street_address))
+                .execute(&mut conn)
+                .await
+                .wrap_err("Failed to update street address")
+                .with_status_code(StatusCode::BAD_REQUEST)?;
+        }
+    }
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        ProfileTemplate {
+            profile: profile(&mut conn, user.id, user.id).await?,
+            editing: false,
+            user_id: user.id,
+        },
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{user_id}/trade-partner",
+    tag = "Users",
+    description = "Pick a user as trade partner, which shares your street address with them if you chose `trade_partners`.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(ProfileTemplate) = "text/html", example = ProfileTemplate::render_placeholder),
+                (Profile, example = Profile::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("user_id" = i32, Path, description = "User ID to share your address with")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn add_trade_partner(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Path(user_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<ProfileTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    if user_id == user.id {
+        return Err(eyre!("You can't trade with yourself"))
+            .with_status_code(StatusCode::BAD_REQUEST);
+    }
+
+    diesel::insert_into(trade_partners::table)
+        .values((
+            trade_partners::user_id.eq(user.id),
+            trade_partners::partner_id.eq(user_id),
+        ))
+        .on_conflict_do_nothing()
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to add trade partner")
+        .with_status_code(StatusCode::BAD_REQUEST)?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        ProfileTemplate {
+            profile: profile(&mut conn, user_id, user.id).await?,
+            editing: false,
+            user_id: user.id,
+        },
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{user_id}/trade-partner",
+    tag = "Users",
+    description = "Stop sharing your street address with a trade partner.",
+    responses(
+        (status = OK, description = "Ok",
+            content(
+                (inline(ProfileTemplate) = "text/html", example = ProfileTemplate::render_placeholder),
+                (Profile, example = Profile::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    params(("user_id" = i32, Path, description = "User ID to stop sharing your address with")),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn remove_trade_partner(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+    Path(user_id): Path<i32>,
+    TypedHeader(accept): TypedHeader<HtmlOrJsonHeader>,
+) -> Result<HtmlOrJsonSimple<ProfileTemplate>, error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    diesel::delete(trade_partners::table)
+        .filter(trade_partners::user_id.eq(user.id))
+        .filter(trade_partners::partner_id.eq(user_id))
+        .execute(&mut conn)
+        .await
+        .wrap_err("Failed to remove trade partner")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(HtmlOrJsonSimple(
+        accept,
+        ProfileTemplate {
+            profile: profile(&mut conn, user_id, user.id).await?,
+            editing: false,
+            user_id: user.id,
+        },
+    ))
+}
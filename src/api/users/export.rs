@@ -0,0 +1,207 @@
+//! Exporting the personal data of an account
+//!
+//! The export is a single JSON document with everything stored about
+//! the account: its profile and street address, its listings, who it
+//! shares its address with, its invite codes, and its sessions, access
+//! tokens and linked identities. Password and token hashes are left
+//! out. The exchange doesn't record trades or messages yet, so there
+//! are none to export.
+use axum::{
+    Json,
+    http::{HeaderName, StatusCode, header},
+};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, eyre};
+use diesel::{ExpressionMethods, HasQuery, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::{
+    Placeholder,
+    api::{
+        auth::{
+            User,
+            invites::{self, Invite},
+            oidc::Identity,
+            pool::DatabaseConnection,
+            role::Role,
+            scope::{self, RequireScope},
+            session::{self, Session},
+            tokens::{self, ApiToken},
+        },
+        games::GameModel,
+        users::AddressVisibility,
+    },
+    error::{self, Error, WithStatusCode},
+    schema::{games, street_addresses, trade_partners, user_identities, users},
+};
+
+#[derive(HasQuery, ToSchema, Serialize, Debug)]
+#[diesel(table_name = crate::schema::users)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ExportedAccount {
+    id: i32,
+    username: String,
+    email: Option<String>,
+    email_verified_at: Option<DateTime<Utc>>,
+    role: Role,
+    bio: Option<String>,
+    address_visibility: AddressVisibility,
+    created_at: DateTime<Utc>,
+    /// When the account is going to be deleted, if it is
+    delete_after: Option<DateTime<Utc>>,
+}
+
+#[derive(HasQuery, ToSchema, Serialize, Debug)]
+#[diesel(table_name = crate::schema::trade_partners)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ExportedTradePartner {
+    /// User ID you share your address with
+    partner_id: i32,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(ToSchema, Serialize, Debug)]
+pub struct Export {
+    exported_at: DateTime<Utc>,
+    account: ExportedAccount,
+    street_address: Option<String>,
+    listings: Vec<GameModel>,
+    trade_partners: Vec<ExportedTradePartner>,
+    sessions: Vec<Session>,
+    access_tokens: Vec<ApiToken>,
+    identities: Vec<Identity>,
+    invites: Vec<Invite>,
+}
+
+impl Placeholder for Export {
+    fn placeholder() -> Self {
+        let now = Utc::now();
+        let user = User::placeholder();
+        Self {
+            exported_at: now,
+            account: ExportedAccount {
+                id: user.id,
+                username: user.username,
+                email: Some("johndoe@example.com".to_owned()),
+                email_verified_at: Some(now),
+                role: user.role,
+                bio: Some("Collecting everything Sega since 1991".to_owned()),
+                address_visibility: AddressVisibility::TradePartners,
+                created_at: now,
+                delete_after: None,
+            },
+            street_address: Some("1 Infinite Loop, Cupertino".to_owned()),
+            listings: vec![GameModel::placeholder()],
+            trade_partners: vec![ExportedTradePartner {
+                partner_id: 2,
+                created_at: now,
+            }],
+            sessions: vec![Session::placeholder()],
+            access_tokens: Vec::new(),
+            identities: vec![Identity::placeholder()],
+            invites: vec![Invite::placeholder()],
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me/export",
+    tag = "Users",
+    description = "Download everything stored about your account as JSON",
+    responses(
+        (status = OK, description = "Ok",
+            headers(
+                ("Content-Disposition" = String)
+            ),
+            content(
+                (Export, example = Export::placeholder)
+            )
+        ),
+        (status = "4XX", description = "You did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+        (status = "5XX", description = "We did something wrong",
+            content(
+                (Error, example = Error::placeholder),
+            )
+        ),
+    ),
+    security(
+        ("basic_auth" = []),
+        ("bearer_jwt" = []),
+        ("cookie_jwt" = []),
+    )
+)]
+#[instrument(skip(conn, _scope))]
+pub async fn export(
+    DatabaseConnection(mut conn, _, user): DatabaseConnection,
+    _scope: RequireScope<scope::Account>,
+) -> Result<([(HeaderName, &'static str); 1], Json<Export>), error::Error> {
+    let Some(user) = user else {
+        return Err(eyre!("You aren't logged in")).with_status_code(StatusCode::UNAUTHORIZED);
+    };
+
+    let account = ExportedAccount::query()
+        .filter(users::id.eq(user.id))
+        .get_result(&mut conn)
+        .await
+        .wrap_err("Failed to get account")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let street_address = street_addresses::table
+        .filter(street_addresses::user_id.eq(user.id))
+        .select(street_addresses::street_address)
+        .get_result::<String>(&mut conn)
+        .await
+        .optional()
+        .wrap_err("Failed to get street address")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let listings = GameModel::query()
+        .filter(games::owned_by.eq(user.id))
+        .order(games::id)
+        .load(&mut conn)
+        .await
+        .wrap_err("Failed to get listings")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let trade_partners = ExportedTradePartner::query()
+        .filter(trade_partners::user_id.eq(user.id))
+        .order(trade_partners::created_at)
+        .load(&mut conn)
+        .await
+        .wrap_err("Failed to get trade partners")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let identities = Identity::query()
+        .filter(user_identities::user_id.eq(user.id))
+        .order(user_identities::created_at)
+        .load(&mut conn)
+        .await
+        .wrap_err("Failed to get identities")
+        .with_status_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"retro-game-exchange-export.json\"",
+        )],
+        Json(Export {
+            exported_at: Utc::now(),
+            account,
+            street_address,
+            listings,
+            trade_partners,
+            sessions: session::load(&mut conn, user.id, None).await?,
+            access_tokens: tokens::load_tokens(&mut conn, user.id).await?,
+            identities,
+            invites: invites::load(&mut conn, Some(user.id)).await?,
+        }),
+    ))
+}
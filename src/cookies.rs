@@ -0,0 +1,278 @@
+//! Cookie attributes and encryption
+//!
+//! Every cookie is built by `Cookies::build`, so they all get the
+//! `Secure`, `SameSite` and `Domain` attributes from the config.
+//! Cookies that carry credentials go through a `Jar`, which encrypts
+//! and authenticates them, so a client can neither read nor forge them.
+//!
+//! The first configured key encrypts new cookies and the rest are only
+//! used to decrypt, so a key can be rotated out by prepending its
+//! replacement and dropping it once the sessions it encrypted have
+//! expired.
+use std::{convert::Infallible, fmt::Debug, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{HeaderMap, request::Parts},
+    response::{IntoResponseParts, ResponseParts},
+};
+use axum_extra::extract::{
+    PrivateCookieJar,
+    cookie::{Cookie, Key, SameSite},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::{self, Context};
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct CookieKey(Key);
+
+impl Debug for CookieKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CookieKey").finish_non_exhaustive()
+    }
+}
+
+impl FromStr for CookieKey {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE64_STANDARD
+            .decode(s)
+            .wrap_err("Cookie keys must be base64")?;
+
+        Ok(Self(
+            Key::try_from(bytes.as_slice()).wrap_err("Cookie keys need at least 64 bytes")?,
+        ))
+    }
+}
+
+impl TryFrom<String> for CookieKey {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieSameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(value: CookieSameSite) -> Self {
+        match value {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
+}
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct CookieConfig {
+    /// Keys for encrypting cookies, as base64 of at least 64 random
+    /// bytes, e.g. from `openssl rand -base64 64`. The first one
+    /// encrypts new cookies, the others are only used to decrypt.
+    #[clap(long = "cookie-key", env = "COOKIE_KEYS", value_delimiter = ',')]
+    #[serde(default)]
+    pub cookie_keys: Vec<CookieKey>,
+    /// Also send cookies over plain HTTP. Browsers already do for
+    /// `localhost`, so this is only needed to serve other hosts
+    /// without TLS.
+    #[clap(long, env = "COOKIE_INSECURE")]
+    #[serde(default)]
+    pub cookie_insecure: bool,
+    /// Which cross-site requests cookies are sent with
+    #[clap(long, env = "COOKIE_SAME_SITE")]
+    #[serde(default)]
+    pub cookie_same_site: CookieSameSite,
+    /// Domain to scope cookies to, instead of only the host that set them
+    #[clap(long, env = "COOKIE_DOMAIN")]
+    #[serde(default)]
+    pub cookie_domain: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            cookie_keys: Vec::new(),
+            cookie_insecure: false,
+            cookie_same_site: CookieSameSite::default(),
+            cookie_domain: None,
+        }
+    }
+}
+
+struct Inner {
+    key: Key,
+    old_keys: Vec<Key>,
+    secure: bool,
+    same_site: SameSite,
+    domain: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Cookies(Arc<Inner>);
+
+impl Cookies {
+    pub fn new(config: &CookieConfig) -> Self {
+        let mut keys = config.cookie_keys.iter().map(|CookieKey(key)| key.clone());
+        let key = keys.next().unwrap_or_else(|| {
+            tracing::warn!(
+                "No cookie keys configured, using a random one. Sessions won't survive a restart"
+            );
+            Key::generate()
+        });
+
+        Self(Arc::new(Inner {
+            key,
+            old_keys: keys.collect(),
+            secure: !config.cookie_insecure,
+            same_site: config.cookie_same_site.into(),
+            domain: config.cookie_domain.clone(),
+        }))
+    }
+
+    /// Builds a cookie with the configured attributes. It is sent with
+    /// every request to the site and hidden from scripts.
+    pub fn build(&self, name: &'static str, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(name, value);
+        cookie.set_path("/");
+        cookie.set_http_only(true);
+        cookie.set_secure(self.0.secure);
+        cookie.set_same_site(self.0.same_site);
+        if let Some(domain) = &self.0.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+
+    /// Builds a cookie that removes `name` when passed to `remove`. The
+    /// path and domain have to match the ones it was set with.
+    pub fn removal(&self, name: &'static str) -> Cookie<'static> {
+        let mut cookie = Cookie::from(name);
+        cookie.set_path("/");
+        if let Some(domain) = &self.0.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// The encrypted cookies of a request. Cookies are decrypted with
+/// whichever configured key they were encrypted with, and added ones
+/// are encrypted with the current key.
+pub struct Jar {
+    jar: PrivateCookieJar,
+    /// Cookies that only decrypted with an older key
+    rotated: Vec<Cookie<'static>>,
+    cookies: Cookies,
+}
+
+/// Only the names, the values would log in anyone who replays them
+impl Debug for Jar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Jar")
+            .field(
+                "names",
+                &self
+                    .jar
+                    .iter()
+                    .chain(self.rotated.iter().cloned())
+                    .map(|cookie| cookie.name().to_owned())
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jar {
+    pub fn from_headers(headers: &HeaderMap, cookies: &Cookies) -> Self {
+        Self {
+            jar: PrivateCookieJar::from_headers(headers, cookies.0.key.clone()),
+            rotated: cookies
+                .0
+                .old_keys
+                .iter()
+                .flat_map(|key| {
+                    PrivateCookieJar::from_headers(headers, key.clone())
+                        .iter()
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            cookies: cookies.clone(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Cookie<'static>> {
+        self.jar.get(name).or_else(|| {
+            self.rotated
+                .iter()
+                .find(|cookie| cookie.name() == name)
+                .cloned()
+        })
+    }
+
+    pub fn add(mut self, cookie: Cookie<'static>) -> Self {
+        self.rotated
+            .retain(|rotated| rotated.name() != cookie.name());
+        self.jar = self.jar.add(cookie);
+        self
+    }
+
+    pub fn remove(mut self, mut cookie: Cookie<'static>) -> Self {
+        let before = self.rotated.len();
+        self.rotated
+            .retain(|rotated| rotated.name() != cookie.name());
+
+        self.jar = if self.rotated.len() < before {
+            // The current jar never saw it, so it wouldn't send a removal
+            cookie.make_removal();
+            self.jar.add(cookie)
+        } else {
+            self.jar.remove(cookie)
+        };
+        self
+    }
+
+    /// See `Cookies::build`
+    pub fn build(&self, name: &'static str, value: String) -> Cookie<'static> {
+        self.cookies.build(name, value)
+    }
+
+    /// See `Cookies::removal`
+    pub fn removal(&self, name: &'static str) -> Cookie<'static> {
+        self.cookies.removal(name)
+    }
+}
+
+impl<S> FromRequestParts<S> for Jar
+where
+    Cookies: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_headers(
+            &parts.headers,
+            &Cookies::from_ref(state),
+        ))
+    }
+}
+
+impl IntoResponseParts for Jar {
+    type Error = Infallible;
+
+    fn into_response_parts(self, res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        self.jar.into_response_parts(res)
+    }
+}
@@ -0,0 +1,96 @@
+//! Cross-site request forgery protection
+//!
+//! Double-submit tokens: every browser gets a random token in the
+//! `csrftoken` cookie, and the frontend copies it into the meta tag
+//! that `hx-headers` sends back as `X-CSRF-Token`. Another site can
+//! make the browser send the cookie, but can't read it to set the
+//! header.
+//!
+//! Unsafe requests that carry our cookies have to send the header.
+//! Requests with an `Authorization` header carry their credentials
+//! explicitly and are exempt, as are clients without cookies, which
+//! have nothing a forged request could ride on.
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use color_eyre::eyre::eyre;
+
+use crate::{
+    api::auth::session,
+    cookies::Cookies,
+    error::{self, WithStatusCode},
+};
+
+pub const COOKIE_NAME: &str = "csrftoken";
+static HEADER_NAME: HeaderName = HeaderName::from_static("x-csrf-token");
+
+fn cookie(cookies: &Cookies, token: String) -> Cookie<'static> {
+    let mut cookie = cookies.build(COOKIE_NAME, token);
+    // Read by the frontend, so not http only
+    cookie.set_http_only(false);
+    cookie.set_same_site(SameSite::Strict);
+    cookie
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Middleware that checks the token on unsafe requests and hands out
+/// a token to browsers that don't have one yet
+pub async fn protect(
+    State(cookies): State<Cookies>,
+    jar: CookieJar,
+    request: Request,
+    next: Next,
+) -> error::Result<Response> {
+    let token = jar.get(COOKIE_NAME).map(|cookie| cookie.value().to_owned());
+
+    let carries_cookies = token.is_some() || jar.get(session::COOKIE_NAME).is_some();
+    if !is_safe(request.method())
+        && carries_cookies
+        && !request.headers().contains_key(header::AUTHORIZATION)
+    {
+        let sent = request
+            .headers()
+            .get(&HEADER_NAME)
+            .and_then(|value| value.to_str().ok());
+
+        match (&token, sent) {
+            // `blake3::Hash`'s `PartialEq` is constant time
+            (Some(token), Some(sent))
+                if blake3::hash(token.as_bytes()) == blake3::hash(sent.as_bytes()) => {}
+            _ => {
+                return Err(eyre!(
+                    "Missing or invalid CSRF token, reload the page and try again"
+                ))
+                .with_status_code(StatusCode::FORBIDDEN);
+            }
+        }
+    }
+
+    let response = next.run(request).await;
+
+    Ok(match token {
+        Some(_) => response,
+        None => (
+            jar.add(cookie(
+                &cookies,
+                BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()),
+            )),
+            response,
+        )
+            .into_response(),
+    })
+}
@@ -5,9 +5,14 @@
 //! the error is then returned to the browser or
 //! whatever it is, it's then nicely formatted to
 //! a webpage
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
-use axum::{Json, body::Body, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    body::Body,
+    http::{HeaderValue, StatusCode, header},
+    response::IntoResponse,
+};
 use color_eyre::eyre::eyre;
 use sailfish::Template;
 use serde::{
@@ -67,6 +72,8 @@ pub struct Error {
     status_code: StatusCode,
     error: color_eyre::eyre::Report,
     actions: Actions,
+    /// Sent as `Retry-After` when set
+    retry_after: Option<Duration>,
 }
 
 impl Placeholder for Error {
@@ -76,6 +83,7 @@ impl Placeholder for Error {
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
             error: eyre!("Example error"),
             actions: Actions::default(),
+            retry_after: None,
         }
     }
 }
@@ -88,7 +96,15 @@ impl Debug for Error {
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        (self.status_code, Json(self)).into_response()
+        let retry_after = self.retry_after;
+        let mut response = (self.status_code, Json(self)).into_response();
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+        }
+        response
     }
 }
 
@@ -224,6 +240,19 @@ impl Serialize for SerializeSpantrace<'_> {
     }
 }
 
+/// Only for `transaction`, which fails by itself if it can't begin or
+/// commit. Queries inside it still wrap their own errors.
+impl From<diesel::result::Error> for Error {
+    fn from(value: diesel::result::Error) -> Self {
+        Self {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            error: color_eyre::eyre::Report::new(value).wrap_err("Database transaction failed"),
+            actions: Actions::default(),
+            retry_after: None,
+        }
+    }
+}
+
 impl From<Error> for Box<dyn std::error::Error + Sync + Send + 'static> {
     fn from(value: Error) -> Self {
         value.error.into()
@@ -256,6 +285,7 @@ impl ResponseForPanic for PanicHandler {
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
             error: eyre!("{}", error_string),
             actions: Actions::default(),
+            retry_after: None,
         }
         .into_response()
     }
@@ -264,6 +294,8 @@ impl ResponseForPanic for PanicHandler {
 pub trait WithStatusCode<T> {
     fn with_status_code(self, status_code: StatusCode) -> Result<T>;
     fn with_status_code_and_actions(self, status_code: StatusCode, actions: Actions) -> Result<T>;
+    /// Answers with `429 Too Many Requests`, telling the client when to try again
+    fn with_retry_after(self, retry_after: Duration) -> Result<T>;
 }
 
 impl<T> WithStatusCode<T> for std::result::Result<T, color_eyre::eyre::Report> {
@@ -272,6 +304,7 @@ impl<T> WithStatusCode<T> for std::result::Result<T, color_eyre::eyre::Report> {
             status_code,
             error,
             actions: Actions::default(),
+            retry_after: None,
         })
     }
 
@@ -280,6 +313,16 @@ impl<T> WithStatusCode<T> for std::result::Result<T, color_eyre::eyre::Report> {
             status_code,
             error,
             actions,
+            retry_after: None,
+        })
+    }
+
+    fn with_retry_after(self, retry_after: Duration) -> Result<T> {
+        self.map_err(|error| Error {
+            status_code: StatusCode::TOO_MANY_REQUESTS,
+            error,
+            actions: Actions::default(),
+            retry_after: Some(retry_after),
         })
     }
 }
@@ -28,3 +28,28 @@ impl Header for HxRefresh {
         }
     }
 }
+
+pub struct HxRedirect(pub HeaderValue);
+
+impl Header for HxRedirect {
+    fn name() -> &'static axum::http::HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("hx-redirect");
+        &NAME
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, axum_extra::headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i axum::http::HeaderValue>,
+    {
+        values
+            .last()
+            .cloned()
+            .map(HxRedirect)
+            .ok_or_else(axum_extra::headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<axum::http::HeaderValue>>(&self, values: &mut E) {
+        values.extend([self.0.clone()]);
+    }
+}
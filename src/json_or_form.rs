@@ -1,9 +1,9 @@
 use axum::{
-    Form, Json,
+    Json,
     extract::{FromRequest, FromRequestParts},
     http::{HeaderName, HeaderValue, Request, StatusCode},
 };
-use axum_extra::{TypedHeader, headers::Header};
+use axum_extra::{TypedHeader, extract::Form, headers::Header};
 use color_eyre::eyre::Context;
 use serde::de::DeserializeOwned;
 
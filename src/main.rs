@@ -1,5 +1,6 @@
 use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 
+use axum::extract::DefaultBodyLimit;
 use clap::Parser;
 use color_eyre::{
     config::Theme,
@@ -24,10 +25,15 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod api;
 mod cli_level_filter;
+mod cookies;
+mod csrf;
 mod error;
 mod html_or_json;
 mod htmx;
 mod json_or_form;
+mod mail;
+mod state;
+mod storage;
 
 pub mod schema;
 
@@ -116,7 +122,26 @@ macro_rules! openapi_template {
 
 pub(crate) use openapi_template;
 
-use crate::api::auth::pool::Pool;
+use crate::{
+    api::{
+        auth::{
+            cache::{CredentialCache, CredentialCacheConfig},
+            deletion::{self, DeletionConfig},
+            invites::InviteConfig,
+            jwt::{JwtConfig, JwtKeys},
+            oidc::{OidcProviderConfig, OidcProviders},
+            password::{PasswordConfig, PasswordHasher},
+            pool::Pool,
+            session::SessionConfig,
+            throttle::ThrottleConfig,
+        },
+        games::photos::PhotoConfig,
+    },
+    cookies::{CookieConfig, Cookies},
+    mail::{MailConfig, Mailer},
+    state::AppState,
+    storage::{self, LocalStorage, StorageConfig},
+};
 
 pub trait Placeholder {
     fn placeholder() -> Self;
@@ -140,6 +165,43 @@ struct Cli {
     #[clap(short, long, env = "DATABASE_URL")]
     #[serde(default)]
     db_url: String,
+    #[command(flatten)]
+    #[serde(default)]
+    password: PasswordConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    session: SessionConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    deletion: DeletionConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    jwt: JwtConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    cookies: CookieConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    throttle: ThrottleConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    credential_cache: CredentialCacheConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    invites: InviteConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    mail: MailConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    storage: StorageConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    photos: PhotoConfig,
+    /// Only read from the config file, as `[[oidc_providers]]` tables
+    #[clap(skip)]
+    #[serde(default)]
+    oidc_providers: Vec<OidcProviderConfig>,
 }
 
 impl Default for Cli {
@@ -148,6 +210,18 @@ impl Default for Cli {
             log_level: CliLevelFilter::default(),
             addr: default_listen_addr(),
             db_url: String::new(),
+            password: PasswordConfig::default(),
+            session: SessionConfig::default(),
+            deletion: DeletionConfig::default(),
+            jwt: JwtConfig::default(),
+            cookies: CookieConfig::default(),
+            throttle: ThrottleConfig::default(),
+            credential_cache: CredentialCacheConfig::default(),
+            invites: InviteConfig::default(),
+            mail: MailConfig::default(),
+            storage: StorageConfig::default(),
+            photos: PhotoConfig::default(),
+            oidc_providers: Vec::new(),
         }
     }
 }
@@ -188,6 +262,14 @@ async fn main() -> eyre::Result<()> {
         bail!("db_url is not set");
     }
 
+    let hasher = PasswordHasher::new(&config.password)?;
+    let jwt = JwtKeys::new(&config.jwt);
+    let cookies = Cookies::new(&config.cookies);
+    let credential_cache = CredentialCache::new(&config.credential_cache);
+    let mailer = Mailer::new(&config.mail)?;
+    let storage = LocalStorage::new(&config.storage)?;
+    let oidc = OidcProviders::discover(&config.oidc_providers, &config.mail.public_url).await?;
+
     let db_config =
         AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(config.db_url);
     let pool = bb8::Pool::builder()
@@ -204,6 +286,12 @@ async fn main() -> eyre::Result<()> {
     // so must use unwrap
     harness.run_pending_migrations(MIGRATIONS).unwrap();
 
+    tokio::spawn(deletion::purge_periodically(
+        pool.clone(),
+        credential_cache.clone(),
+        storage.clone(),
+    ));
+
     let (router, mut api) = OpenApiRouter::new()
         .routes(routes!(api::games::get_all_games, api::games::add_game))
         .routes(routes!(
@@ -212,14 +300,88 @@ async fn main() -> eyre::Result<()> {
             api::games::patch_game,
             api::games::delete_game
         ))
+        .routes(routes!(api::games::hide_game))
+        .routes(routes!(api::games::autocomplete::get_autocomplete))
+        .merge(
+            OpenApiRouter::new()
+                .routes(routes!(
+                    api::games::photos::get_photos,
+                    api::games::photos::upload_photos
+                ))
+                .layer(DefaultBodyLimit::max(config.photos.body_limit())),
+        )
+        .routes(routes!(
+            api::games::photos::move_photo,
+            api::games::photos::delete_photo
+        ))
+        .routes(routes!(
+            api::platforms::get_platforms,
+            api::platforms::add_platform
+        ))
+        .routes(routes!(
+            api::platforms::get_platform,
+            api::platforms::update_platform,
+            api::platforms::delete_platform
+        ))
+        .routes(routes!(
+            api::titles::get_title,
+            api::titles::update_title,
+            api::titles::delete_title
+        ))
+        .routes(routes!(api::users::get_profile))
+        .routes(routes!(api::users::update_profile))
+        .routes(routes!(api::users::export::export))
+        .routes(routes!(
+            api::users::add_trade_partner,
+            api::users::remove_trade_partner
+        ))
+        .routes(routes!(api::admin::get_users))
+        .routes(routes!(api::admin::update_role))
+        .routes(routes!(api::admin::delete_user))
+        .routes(routes!(api::admin::get_auth_events))
+        .routes(routes!(api::admin::get_invites))
+        .routes(routes!(api::admin::delete_invite))
         .routes(routes!(api::auth::signup))
         .routes(routes!(api::auth::logout))
+        .routes(routes!(api::auth::deletion::cancel_deletion))
+        .routes(routes!(api::auth::jwt::token))
         .routes(routes!(
             api::auth::login,
             api::auth::get_login,
             api::auth::patch_login,
             api::auth::delete_login
         ))
+        .routes(routes!(api::auth::totp::login_second_factor))
+        .routes(routes!(
+            api::auth::totp::get_totp,
+            api::auth::totp::enroll_totp,
+            api::auth::totp::disable_totp
+        ))
+        .routes(routes!(api::auth::totp::confirm_totp))
+        .routes(routes!(api::auth::email::update_email))
+        .routes(routes!(api::auth::email::verify_email))
+        .routes(routes!(api::auth::email::request_password_reset))
+        .routes(routes!(api::auth::email::reset_password))
+        .routes(routes!(api::auth::oidc::start))
+        .routes(routes!(api::auth::oidc::callback))
+        .routes(routes!(api::auth::oidc::get_identities))
+        .routes(routes!(api::auth::oidc::delete_identity))
+        .routes(routes!(api::auth::session::get_sessions))
+        .routes(routes!(api::auth::events::get_events))
+        .routes(routes!(api::auth::session::delete_session))
+        .routes(routes!(
+            api::auth::tokens::get_tokens,
+            api::auth::tokens::add_token
+        ))
+        .routes(routes!(
+            api::auth::tokens::update_token,
+            api::auth::tokens::delete_token
+        ))
+        .routes(routes!(
+            api::auth::invites::get_invites,
+            api::auth::invites::add_invite
+        ))
+        .routes(routes!(api::auth::invites::delete_invite))
         .split_for_parts();
     api.info = Info::builder()
         .title(env!("CARGO_PKG_NAME"))
@@ -242,6 +404,9 @@ async fn main() -> eyre::Result<()> {
                 Http::builder()
                     .scheme(HttpAuthScheme::Bearer)
                     .bearer_format("JWT")
+                    .description(Some(
+                        "An access token from `/auth/token` or a personal access token",
+                    ))
                     .build(),
             ),
         );
@@ -255,7 +420,26 @@ async fn main() -> eyre::Result<()> {
             ),
         );
     });
+    let state = AppState {
+        pool: Pool::new(pool),
+        hasher,
+        session: config.session,
+        deletion: config.deletion,
+        jwt,
+        cookies,
+        throttle: config.throttle,
+        invites: config.invites,
+        credential_cache,
+        mailer,
+        oidc,
+        storage,
+        photos: config.photos,
+    };
     let app = router
+        .nest_service(
+            storage::URL_PREFIX,
+            ServeDir::new(&config.storage.storage_dir),
+        )
         .fallback_service(
             ServeDir::new("frontend/dist")
                 .precompressed_gzip()
@@ -267,19 +451,26 @@ async fn main() -> eyre::Result<()> {
                 .layer(
                     TraceLayer::new_for_http()
                         .make_span_with(DefaultMakeSpan::new().level(Level::INFO)),
-                ),
+                )
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    csrf::protect,
+                )),
         )
         .merge(SwaggerUi::new("/swagger").url("/api/openapi.json", api))
-        .with_state(Pool::new(pool));
+        .with_state(state);
 
     let listener = TcpListener::bind(config.addr)
         .await
         .wrap_err_with(|| format!("Failed to open listener on {}", config.addr))?;
     tracing::info!("Listening on {}", config.addr);
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .wrap_err("Failed to serve make service")
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .wrap_err("Failed to serve make service")
 }
 
 async fn shutdown_signal() {
@@ -1,9 +1,95 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "address_visibility"))]
+    pub struct AddressVisibility;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "auth_event_kind"))]
+    pub struct AuthEventKind;
+
     #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "condition"))]
     pub struct Condition;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "email_token_purpose"))]
+    pub struct EmailTokenPurpose;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "token_scope"))]
+    pub struct TokenScope;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "user_role"))]
+    pub struct UserRole;
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::TokenScope;
+
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Bytea,
+        scopes -> Array<Nullable<TokenScope>>,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::AuthEventKind;
+
+    auth_events (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        username -> Nullable<Varchar>,
+        kind -> AuthEventKind,
+        ip -> Varchar,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::EmailTokenPurpose;
+
+    email_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        purpose -> EmailTokenPurpose,
+        token_hash -> Bytea,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    game_photos (id) {
+        id -> Int4,
+        game_id -> Int4,
+        position -> Int4,
+        content_type -> Varchar,
+        width -> Int4,
+        height -> Int4,
+        storage_key -> Varchar,
+        thumbnail_key -> Varchar,
+        url -> Varchar,
+        thumbnail_url -> Varchar,
+        created_at -> Timestamptz,
+    }
 }
 
 diesel::table! {
@@ -11,25 +97,180 @@ diesel::table! {
     use super::sql_types::Condition;
 
     games (id) {
+        id -> Int4,
+        condition -> Nullable<Condition>,
+        owned_by -> Int4,
+        hidden -> Bool,
+        updated_at -> Timestamptz,
+        title_id -> Int4,
+    }
+}
+
+diesel::table! {
+    invites (id) {
+        id -> Int4,
+        code -> Varchar,
+        created_by -> Int4,
+        used_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    login_attempts (key) {
+        key -> Varchar,
+        failures -> Int4,
+        last_failure_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    oidc_logins (state_hash) {
+        state_hash -> Bytea,
+        provider -> Varchar,
+        pkce_verifier -> Varchar,
+        nonce -> Varchar,
+        link_user_id -> Nullable<Int4>,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    platforms (id) {
+        id -> Int4,
+        name -> Varchar,
+        manufacturer -> Nullable<Varchar>,
+        generation -> Nullable<Int2>,
+        release_year -> Nullable<Int2>,
+        region -> Nullable<Varchar>,
+        aliases -> Array<Nullable<Varchar>>,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Bytea,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Bytea,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        second_factor_pending -> Bool,
+    }
+}
+
+diesel::table! {
+    street_addresses (user_id) {
+        user_id -> Int4,
+        street_address -> Varchar,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Tsvector;
+
+    titles (id) {
         id -> Int4,
         name -> Varchar,
         publisher -> Nullable<Varchar>,
         year -> Nullable<Int2>,
-        platform -> Nullable<Varchar>,
-        condition -> Nullable<Condition>,
-        owned_by -> Int4,
+        platform_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        search -> Tsvector,
+    }
+}
+
+diesel::table! {
+    totp_credentials (user_id) {
+        user_id -> Int4,
+        secret -> Bytea,
+        confirmed_at -> Nullable<Timestamptz>,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    trade_partners (user_id, partner_id) {
+        user_id -> Int4,
+        partner_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_used_at -> Timestamptz,
     }
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::UserRole;
+    use super::sql_types::AddressVisibility;
+
     users (id) {
         id -> Int4,
         username -> Varchar,
-        street_address -> Nullable<Varchar>,
-        password -> Bytea,
+        password -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamptz>,
+        role -> UserRole,
+        bio -> Nullable<Varchar>,
+        address_visibility -> AddressVisibility,
+        created_at -> Timestamptz,
+        delete_after -> Nullable<Timestamptz>,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(auth_events -> users (user_id));
+diesel::joinable!(email_tokens -> users (user_id));
+diesel::joinable!(game_photos -> games (game_id));
+diesel::joinable!(games -> titles (title_id));
 diesel::joinable!(games -> users (owned_by));
+diesel::joinable!(oidc_logins -> users (link_user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(street_addresses -> users (user_id));
+diesel::joinable!(titles -> platforms (platform_id));
+diesel::joinable!(totp_credentials -> users (user_id));
+diesel::joinable!(user_identities -> users (user_id));
 
-diesel::allow_tables_to_appear_in_same_query!(games, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    auth_events,
+    email_tokens,
+    game_photos,
+    games,
+    invites,
+    login_attempts,
+    oidc_logins,
+    platforms,
+    recovery_codes,
+    sessions,
+    street_addresses,
+    titles,
+    totp_credentials,
+    trade_partners,
+    user_identities,
+    users,
+);
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+
+use crate::{
+    api::{
+        auth::{
+            cache::CredentialCache, deletion::DeletionConfig, invites::InviteConfig, jwt::JwtKeys,
+            oidc::OidcProviders, password::PasswordHasher, pool::Pool, session::SessionConfig,
+            throttle::ThrottleConfig,
+        },
+        games::photos::PhotoConfig,
+    },
+    cookies::Cookies,
+    mail::Mailer,
+    storage::Storage,
+};
+
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub pool: Pool,
+    pub hasher: PasswordHasher,
+    pub session: SessionConfig,
+    pub deletion: DeletionConfig,
+    pub jwt: JwtKeys,
+    pub cookies: Cookies,
+    pub throttle: ThrottleConfig,
+    pub invites: InviteConfig,
+    pub credential_cache: CredentialCache,
+    pub mailer: Mailer,
+    pub oidc: OidcProviders,
+    pub storage: Arc<dyn Storage>,
+    pub photos: PhotoConfig,
+}
@@ -0,0 +1,114 @@
+//! Where uploaded files are kept
+//!
+//! Handlers only see the `Storage` trait, so files could as well go to
+//! an object store. `LocalStorage` keeps them in a directory that is
+//! served as is under `URL_PREFIX`. File names are random, which is all
+//! that keeps the files of hidden listings from being found, as they
+//! aren't covered by row level security.
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use clap::Args;
+use color_eyre::eyre::{self, Context, ensure};
+use serde::Deserialize;
+
+/// Where `LocalStorage` serves its files
+pub const URL_PREFIX: &str = "/uploads";
+
+#[derive(Args, Deserialize, Clone, Debug)]
+pub struct StorageConfig {
+    /// Directory uploaded files are kept in
+    #[clap(long, env = "STORAGE_DIR")]
+    #[serde(default = "default_storage_dir")]
+    pub storage_dir: PathBuf,
+}
+
+#[inline]
+fn default_storage_dir() -> PathBuf {
+    PathBuf::from("uploads")
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            storage_dir: default_storage_dir(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `bytes` under `key`, a relative path like `12/abcd.jpg`,
+    /// replacing whatever was there
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> eyre::Result<()>;
+
+    /// Removes the file under `key`, if there is one
+    async fn delete(&self, key: &str) -> eyre::Result<()>;
+
+    /// Where the file under `key` can be downloaded from
+    fn url(&self, key: &str) -> String;
+}
+
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(config: &StorageConfig) -> eyre::Result<Arc<dyn Storage>> {
+        std::fs::create_dir_all(&config.storage_dir).wrap_err_with(|| {
+            format!(
+                "Failed to create storage directory {}",
+                config.storage_dir.display()
+            )
+        })?;
+
+        Ok(Arc::new(Self {
+            dir: config.storage_dir.clone(),
+        }))
+    }
+
+    /// The file under `key`, which mustn't lead out of the directory
+    fn path(&self, key: &str) -> eyre::Result<PathBuf> {
+        ensure!(
+            Path::new(key)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_))),
+            "Invalid storage key {}",
+            key
+        );
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> eyre::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .wrap_err("Failed to create directory for file")?;
+        }
+        tokio::fs::write(&path, bytes)
+            .await
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+
+    async fn delete(&self, key: &str) -> eyre::Result<()> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| format!("Failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", URL_PREFIX, key)
+    }
+}